@@ -0,0 +1,113 @@
+use clap::{CommandFactory, Parser, error::ErrorKind};
+
+use cuda_gists::bench::Runner;
+use cuda_gists::collectives::{AllGather, Broadcast, Collective, Op};
+use cuda_gists::devices::DeviceSelector;
+use cuda_gists::report::{Environment, Format, Reporter};
+use cuda_gists::*;
+
+/// Broadcast, scatter, gather and all-gather across devices, comparing
+/// algorithms. The root is a pinned host buffer on the first device.
+#[derive(Debug, Parser)]
+#[command(name = "collectives")]
+struct Cli {
+    /// Bytes per rank.
+    #[arg(long, default_value = "256MiB")]
+    size: ByteSize,
+
+    /// Pipeline chunk counts tried for chain and tree broadcasts.
+    #[arg(long, value_delimiter = ',', default_value = "1,4,16")]
+    chunks: Vec<usize>,
+
+    /// Measured iterations per collective.
+    #[arg(long, default_value_t = 5)]
+    iters: usize,
+
+    /// Unmeasured iterations before the measured ones.
+    #[arg(long, default_value_t = 1)]
+    warmup: usize,
+
+    /// Devices by ordinal, GPU UUID (or prefix), PCI bus id or MIG id, or `all`; one rank each.
+    #[arg(long, default_value = "0,1,2,3")]
+    devices: DeviceSelector,
+
+    /// Result format: text, json, csv or markdown.
+    #[arg(long, default_value = "text")]
+    format: Format,
+}
+
+fn main() {
+    let cli = Cli::parse();
+
+    if cli.iters == 0 {
+        Cli::command()
+            .error(ErrorKind::InvalidValue, "--iters must be at least 1")
+            .exit();
+    }
+
+    let ordinals = cli
+        .devices
+        .select()
+        .unwrap_or_else(|e| Cli::command().error(ErrorKind::InvalidValue, e).exit());
+    if ordinals.is_empty() {
+        Cli::command()
+            .error(ErrorKind::InvalidValue, "no devices selected")
+            .exit();
+    }
+    if cli
+        .chunks
+        .iter()
+        .any(|&c| c == 0 || c > cli.size.as_usize())
+    {
+        Cli::command()
+            .error(
+                ErrorKind::InvalidValue,
+                "--chunks must be between 1 and --size",
+            )
+            .exit();
+    }
+
+    log!("Hello from collectives");
+
+    let ctxs = ordinals
+        .iter()
+        .map(|&d| Context::new(d))
+        .collect::<Vec<_>>();
+    let streams = ctxs.iter().map(|c| c.create_stream()).collect::<Vec<_>>();
+    let root = ctxs[0].create_stream();
+    let size = cli.size.as_usize();
+
+    let mut scenarios = vec![Collective::new(
+        Op::Broadcast(Broadcast::Flat),
+        &root,
+        &streams,
+        size,
+    )];
+    for algorithm in [Broadcast::Chain, Broadcast::Tree] {
+        for &chunks in &cli.chunks {
+            scenarios.push(
+                Collective::new(Op::Broadcast(algorithm), &root, &streams, size).chunks(chunks),
+            );
+        }
+    }
+    for op in [
+        Op::Scatter,
+        Op::Gather,
+        Op::AllGather(AllGather::Direct),
+        Op::AllGather(AllGather::Ring),
+    ] {
+        scenarios.push(Collective::new(op, &root, &streams, size));
+    }
+
+    let env = Environment::collect(&ordinals);
+    let mut reporter = Reporter::new(cli.format, env, Box::new(std::io::stdout()));
+    let runner = Runner {
+        warmup: cli.warmup,
+        iterations: cli.iters,
+    };
+    for scenario in &mut scenarios {
+        let result = runner.run(scenario);
+        reporter.report(&result);
+    }
+    reporter.finish();
+}
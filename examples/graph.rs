@@ -0,0 +1,118 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use clap::{CommandFactory, Parser, error::ErrorKind};
+
+use cuda_gists::bench::Runner;
+use cuda_gists::devices::DeviceSelector;
+use cuda_gists::graph::TransferGraph;
+use cuda_gists::report::{Environment, Format, Reporter};
+use cuda_gists::*;
+
+/// Runs a fan-out/fan-in transfer graph: pinned host memory is staged onto
+/// the first device, copied from there to every other device, and a host
+/// callback runs once all copies are done. Prints where the scheduler put
+/// events and the critical path of the last iteration.
+#[derive(Debug, Parser)]
+#[command(name = "graph")]
+struct Cli {
+    /// Bytes per copy.
+    #[arg(long, default_value = "256MiB")]
+    size: ByteSize,
+
+    /// Measured iterations.
+    #[arg(long, default_value_t = 5)]
+    iters: usize,
+
+    /// Unmeasured iterations before the measured ones.
+    #[arg(long, default_value_t = 1)]
+    warmup: usize,
+
+    /// Devices by ordinal, GPU UUID (or prefix), PCI bus id or MIG id, or `all`; the first one is staged onto.
+    #[arg(long, default_value = "0,1,2,3")]
+    devices: DeviceSelector,
+
+    /// Result format: text, json, csv or markdown.
+    #[arg(long, default_value = "text")]
+    format: Format,
+}
+
+fn main() {
+    let cli = Cli::parse();
+
+    if cli.iters == 0 {
+        Cli::command()
+            .error(ErrorKind::InvalidValue, "--iters must be at least 1")
+            .exit();
+    }
+
+    let ordinals = cli
+        .devices
+        .select()
+        .unwrap_or_else(|e| Cli::command().error(ErrorKind::InvalidValue, e).exit());
+    if ordinals.is_empty() {
+        Cli::command()
+            .error(ErrorKind::InvalidValue, "no devices selected")
+            .exit();
+    }
+
+    log!("Hello from graph");
+
+    let ctxs = ordinals
+        .iter()
+        .map(|&d| Context::new(d))
+        .collect::<Vec<_>>();
+    let streams = ctxs.iter().map(|c| c.create_stream()).collect::<Vec<_>>();
+    let size = cli.size.as_usize();
+
+    let host = streams[0].create_buffer_async(size, AddressSpace::Pinned);
+    let device = streams
+        .iter()
+        .map(|s| s.create_buffer_async(size, AddressSpace::Device))
+        .collect::<Vec<_>>();
+
+    let mut graph = TransferGraph::new("fan-out", &streams).timed();
+    let fill = graph.memset(&host, 0xab, &[]);
+    let stage = graph.copy(&device[0], &host, &[fill]);
+    let fan_out = device[1..]
+        .iter()
+        .map(|dst| graph.copy(dst, &device[0], &[stage]))
+        .collect::<Vec<_>>();
+    let done = Arc::new(AtomicUsize::new(0));
+    let counter = done.clone();
+    graph.host(
+        "done",
+        move || {
+            counter.fetch_add(1, Ordering::Relaxed);
+        },
+        &fan_out,
+    );
+
+    let schedule = graph.schedule();
+    log!(
+        "{} nodes on {} streams, {} recorded events",
+        graph.len(),
+        streams.len(),
+        schedule.recorded().len()
+    );
+
+    let env = Environment::collect(&ordinals);
+    let mut reporter = Reporter::new(cli.format, env, Box::new(std::io::stdout()));
+    let runner = Runner {
+        warmup: cli.warmup,
+        iterations: cli.iters,
+    };
+    let result = runner.run(&mut graph);
+    reporter.report(&result);
+    reporter.finish();
+
+    if let Some(path) = graph.critical_path() {
+        log!("{}", path.to_string().trim_end());
+    }
+    log!("Host callback ran {} times", done.load(Ordering::Relaxed));
+
+    streams[0].free_buffer_sync(&host);
+    for (stream, buf) in streams.iter().zip(&device) {
+        stream.free_buffer_sync(buf);
+    }
+}
@@ -1,433 +1,689 @@
-use std::sync::{Arc, atomic::{AtomicUsize, Ordering}};
-
-use itertools::izip;
-
+use clap::{CommandFactory, Parser, error::ErrorKind};
+
+use cuda_gists::accounting;
+use cuda_gists::affinity::{self, Affinity, CpuBinding};
+use cuda_gists::alloc::AllocPolicy;
+use cuda_gists::bench::{
+    BenchResult, Endpoint, Runner, Scenario, Transfer, glob_match, sweep_sizes,
+};
+use cuda_gists::devices::DeviceSelector;
+use cuda_gists::expr::TransferExpr;
+use cuda_gists::history::{self, HistoryRow, Thresholds, Verdict};
+use cuda_gists::host::numa_node_cpus;
+use cuda_gists::multiproc::{self, Synchronized};
+use cuda_gists::report::{Environment, Format, Reporter};
+use cuda_gists::topology::{Sysfs, Topology};
 use cuda_gists::*;
 
-const ITERS: usize = 3;
-const MB: usize = 1024 * 1024;
-const GB: usize = 1024 * 1024 * 1024;
-const SIZE: usize = 8 * GB;
-
-pub fn compute_bandwidth_gb_s(time: std::time::Duration, size: usize) -> f64 {
-    let gb = size as f64 / 1024.0 / 1024.0 / 1024.0;
-    gb / time.as_secs_f64()
+/// Host <-> device and device <-> device copy bandwidth.
+#[derive(Debug, Parser)]
+#[command(name = "h2d")]
+struct Cli {
+    /// Transfer sizes, e.g. `8GiB,512MB`.
+    #[arg(long, value_delimiter = ',', default_value = "8GiB")]
+    size: Vec<ByteSize>,
+
+    /// Sweep sizes geometrically instead of using --size: `MIN:MAX[:FACTOR]`,
+    /// e.g. `4KiB:8GiB` (factor defaults to 2).
+    #[arg(long, value_parser = parse_sweep)]
+    sweep: Option<Vec<ByteSize>>,
+
+    /// Measured iterations per scenario.
+    #[arg(long, default_value_t = 3)]
+    iters: usize,
+
+    /// Unmeasured iterations before the measured ones.
+    #[arg(long, default_value_t = 0)]
+    warmup: usize,
+
+    /// Devices to use, by ordinal, GPU UUID (or prefix), PCI bus id or MIG id,
+    /// or `all`; GPU<i> in scenario names is the i-th of these.
+    #[arg(long, default_value = "all")]
+    devices: DeviceSelector,
+
+    /// Scenarios to run, by name or glob (default: all).
+    #[arg(long, value_delimiter = ',')]
+    scenario: Vec<String>,
+
+    /// Run a transfer expression instead of the built-in scenarios, e.g.
+    /// `pinned@numa1 -> dev:1 x4streams` or `dev:0 => dev:1,dev:2 (chain)`;
+    /// may be repeated. Device indices refer to --devices.
+    #[arg(long, conflicts_with_all = ["scenario", "processes"])]
+    expr: Vec<TransferExpr>,
+
+    /// Write one byte per page of host buffers before copying.
+    #[arg(long)]
+    touch: bool,
+
+    /// Streams per device; multi-stream/thread scenarios issue one copy per stream.
+    #[arg(long, default_value_t = 1)]
+    streams: usize,
+
+    /// Result format: text, json, csv or markdown.
+    #[arg(long, default_value = "text")]
+    format: Format,
+
+    /// Write results here instead of stdout.
+    #[arg(long)]
+    output: Option<std::path::PathBuf>,
+
+    /// Append this run's samples to a history file.
+    #[arg(long)]
+    history: Option<std::path::PathBuf>,
+
+    /// Compare against a run from --history: a timestamp, `latest` or `previous`.
+    /// Exits with status 1 if any scenario regressed.
+    #[arg(long, requires = "history")]
+    baseline: Option<String>,
+
+    /// Don't benchmark; compare this stored run against --baseline instead.
+    #[arg(long, requires = "baseline")]
+    compare: Option<String>,
+
+    /// Allowed bandwidth drop before a scenario counts as regressed, e.g. `5%`.
+    #[arg(long, default_value = "5%", value_parser = parse_percent)]
+    threshold: f64,
+
+    /// Per-scenario threshold as `GLOB=PCT`; the first matching glob wins.
+    #[arg(long, value_parser = parse_scenario_threshold)]
+    threshold_for: Vec<(String, f64)>,
+
+    /// Bind copy-issuing threads to CPUs: `none`, `local` (each GPU's NUMA-local
+    /// CPUs) or a CPU list such as `0-7,16`.
+    #[arg(long, default_value = "none")]
+    cpu_bind: CpuBinding,
+
+    /// When pinned memory runs out, fall back to registered, then pageable
+    /// memory; copies are reported with the memory actually used.
+    #[arg(long)]
+    alloc_fallback: bool,
+
+    /// When memory runs out, shrink transfers to the largest size that fits,
+    /// down to this size.
+    #[arg(long)]
+    alloc_min: Option<ByteSize>,
+
+    /// Print allocation totals, high-water marks and unfreed buffers at exit.
+    #[arg(long)]
+    alloc_report: bool,
+
+    /// Run each scenario in one process per device, started together, and
+    /// report the processes' combined bandwidth.
+    #[arg(long)]
+    processes: bool,
+
+    /// List scenario names and exit.
+    #[arg(long)]
+    list: bool,
 }
 
-pub fn create_bufs(streams: &[Stream], touch: bool) -> (Buffer, Vec<Buffer>, Vec<Buffer>) {
-    let pageable_bufs = streams[0].create_buffer_async(SIZE, AddressSpace::Cpu);
-
-    let pinned_bufs = streams
-        .iter()
-        .map(|stream| stream.create_buffer_async(SIZE, AddressSpace::Pinned))
-        .collect::<Vec<_>>();
+fn parse_sweep(s: &str) -> Result<Vec<ByteSize>, String> {
+    let parts = s.split(':').collect::<Vec<_>>();
+    let (min, max, factor) = match parts[..] {
+        [min, max] => (min, max, "2"),
+        [min, max, factor] => (min, max, factor),
+        _ => return Err(format!("expected MIN:MAX[:FACTOR], got {:?}", s)),
+    };
+    let min: ByteSize = min.parse().map_err(|e| format!("{}", e))?;
+    let max: ByteSize = max.parse().map_err(|e| format!("{}", e))?;
+    let factor: f64 = factor
+        .parse()
+        .map_err(|_| format!("invalid factor {:?}", factor))?;
+    if min.bytes() == 0 || min > max || factor <= 1.0 {
+        return Err(format!(
+            "sweep needs 0 < MIN <= MAX and FACTOR > 1, got {:?}",
+            s
+        ));
+    }
+    Ok(sweep_sizes(min, max, factor))
+}
 
-    let gpu_bufs = streams
-        .iter()
-        .map(|stream| stream.create_buffer_async(SIZE, AddressSpace::Device))
-        .collect::<Vec<_>>();
+fn parse_percent(s: &str) -> Result<f64, String> {
+    s.trim_end_matches('%')
+        .parse::<f64>()
+        .map(|pct| pct / 100.0)
+        .map_err(|_| format!("invalid percentage {:?}", s))
+}
 
-    for stream in streams {
-        stream.synchronize();
-    }
+fn parse_scenario_threshold(s: &str) -> Result<(String, f64), String> {
+    let (glob, pct) = s
+        .rsplit_once('=')
+        .ok_or_else(|| format!("expected GLOB=PCT, got {:?}", s))?;
+    Ok((glob.to_string(), parse_percent(pct)?))
+}
 
-    // write random values to the pageable and pinned buffers
-    // write 1 byte to start of ech 4k page
-    if touch {
-        let page_size = 1024;
-        // Write to every 4k page in pinned_bufs and the single pageable_bufs
-        for buf in pinned_bufs.iter().chain(std::iter::once(&pageable_bufs)) {
-            let num_pages = buf.size / page_size;
-            for idx in 0..num_pages {
-                let page_start = idx * page_size;
-                let page_data = (buf.addr as *mut u8).wrapping_add(page_start);
-                unsafe { *page_data = idx as u8 };
+/// Prints a comparison and returns whether anything regressed.
+fn report_comparison(baseline: &[HistoryRow], current: &[HistoryRow], cli: &Cli) -> bool {
+    let thresholds = Thresholds {
+        default: cli.threshold,
+        scenarios: cli.threshold_for.clone(),
+    };
+    let mut regressed = false;
+    for c in history::compare(baseline, current, &thresholds) {
+        let line = format!(
+            "{:?}: {} ({}) {:.2} -> {:.2} GB/s ({:+.1}%, threshold {:.1}%, noise {:.1}%)",
+            c.verdict,
+            c.scenario,
+            ByteSize(c.total_bytes),
+            c.baseline_gb_s,
+            c.current_gb_s,
+            c.change * 100.0,
+            c.threshold * 100.0,
+            c.noise * 100.0
+        );
+        match c.verdict {
+            Verdict::Regressed => {
+                regressed = true;
+                log_error!("{}", line);
             }
+            Verdict::Missing | Verdict::New => log_warn!("{}", line),
+            Verdict::Unchanged | Verdict::Improved => log!("{}", line),
         }
     }
-
-    (pageable_bufs, pinned_bufs, gpu_bufs)
+    regressed
 }
 
-pub fn free_bufs(
-    streams: &[Stream],
-    pageable_bufs: Buffer,
-    pinned_bufs: Vec<Buffer>,
-    gpu_bufs: Vec<Buffer>,
-) {
-    for (stream, buf) in izip!(streams.iter(), pinned_bufs.iter()) {
-        stream.free_buffer_sync(&buf);
-    }
-    for (stream, buf) in izip!(streams.iter(), gpu_bufs.iter()) {
-        stream.free_buffer_sync(&buf);
-    }
-    streams[0].free_buffer_sync(&pageable_bufs);
-
-    for stream in streams {
-        stream.synchronize();
-    }
+/// Loads the history and resolves `selector`, exiting with a usage error if
+/// either fails.
+fn load_run(cli: &Cli, selector: &str) -> Vec<HistoryRow> {
+    let path = cli.history.as_ref().unwrap();
+    let rows = history::load(path).unwrap_or_else(|e| {
+        Cli::command()
+            .error(
+                ErrorKind::Io,
+                format!("can't read history {}: {}", path.display(), e),
+            )
+            .exit()
+    });
+    let Some(run) = history::select_run(&rows, selector) else {
+        Cli::command()
+            .error(
+                ErrorKind::InvalidValue,
+                format!("no run {:?} in {}", selector, path.display()),
+            )
+            .exit()
+    };
+    history::run_rows(&rows, run)
 }
 
-fn main() {
-    log!("Hello from h2d");
-
-    const NUM_DEVICES: usize = 4;
-    let ctxs = (0..NUM_DEVICES)
-        .map(|i| Context::new(i as i32))
-        .collect::<Vec<_>>();
+struct ScenarioDef {
+    name: &'static str,
+    description: &'static str,
+    min_devices: usize,
+}
 
-    let streams = ctxs
+const SCENARIOS: &[ScenarioDef] = &[
+    ScenarioDef {
+        name: "p2p",
+        description: "GPU0 -> GPU1 P2P",
+        min_devices: 2,
+    },
+    ScenarioDef {
+        name: "pageable-gpu0",
+        description: "Pageable -> GPU0",
+        min_devices: 1,
+    },
+    ScenarioDef {
+        name: "pinned-gpu0",
+        description: "Pinned0 -> GPU0",
+        min_devices: 1,
+    },
+    ScenarioDef {
+        name: "pinned0-gpu1",
+        description: "Pinned0 -> GPU1",
+        min_devices: 2,
+    },
+    ScenarioDef {
+        name: "pageable-pinned0",
+        description: "Pageable -> Pinned0",
+        min_devices: 1,
+    },
+    ScenarioDef {
+        name: "registered-gpu0",
+        description: "Registered0 -> GPU0",
+        min_devices: 1,
+    },
+    ScenarioDef {
+        name: "gpu0-pageable",
+        description: "GPU0 -> Pageable",
+        min_devices: 1,
+    },
+    ScenarioDef {
+        name: "gpu0-pinned",
+        description: "GPU0 -> Pinned0",
+        min_devices: 1,
+    },
+    ScenarioDef {
+        name: "gpu0-registered",
+        description: "GPU0 -> Registered0",
+        min_devices: 1,
+    },
+    ScenarioDef {
+        name: "bidir-gpu0",
+        description: "Pinned0 -> GPU0 + GPU0 -> Pinned0 (bidirectional)",
+        min_devices: 1,
+    },
+    ScenarioDef {
+        name: "bidir-all",
+        description: "PinnedN -> GPUN + GPUN -> PinnedN, all devices (bidirectional)",
+        min_devices: 1,
+    },
+    ScenarioDef {
+        name: "multi-stream",
+        description: "Pinned0 -> GPU0, Pinned1 -> GPU1, Pinned2 -> GPU2, ... (multi stream)",
+        min_devices: 1,
+    },
+    ScenarioDef {
+        name: "multi-thread",
+        description: "Pinned0 -> GPU0, Pinned1 -> GPU1, Pinned2 -> GPU2, ... (multi thread/stream)",
+        min_devices: 1,
+    },
+    ScenarioDef {
+        name: "fan-out",
+        description: "Pinned0 -> GPU0, GPU1, GPU2, ... (multi stream)",
+        min_devices: 1,
+    },
+    ScenarioDef {
+        name: "chain",
+        description: "Pinned -> GPU0 -> GPU1, GPU2, GPU3, ... (multi stream)",
+        min_devices: 2,
+    },
+];
+
+/// Streams per device: `--streams`, but at least two so bidirectional
+/// scenarios can run each direction on its own stream, and as many as any
+/// --expr needs.
+fn streams_per_device(cli: &Cli) -> usize {
+    cli.expr
         .iter()
-        .map(|ctx| ctx.create_stream())
-        .collect::<Vec<_>>();
+        .map(TransferExpr::streams_per_device)
+        .fold(cli.streams.max(2), usize::max)
+}
 
-    let events = ctxs
-        .iter()
-        .map(|ctx| ctx.create_event())
+/// Builds scenario `def` over `streams`, which holds `streams_per_device`
+/// consecutive streams for each of `n_devices` devices, of which the first
+/// `cli.streams` carry the multi-stream/thread copies.
+fn build(
+    def: &ScenarioDef,
+    cli: &Cli,
+    streams: &[Stream],
+    n_devices: usize,
+    size: usize,
+    host_options: HostOptions,
+) -> Transfer {
+    let name = def.description;
+    let per_device = streams_per_device(cli);
+    let gpu = |i: usize| i * per_device;
+    let active = (0..n_devices)
+        .flat_map(|i| (0..cli.streams).map(move |k| gpu(i) + k))
         .collect::<Vec<_>>();
-
-    for stream in &streams {
-        stream.synchronize();
-    }
-
-    log!("Benchmarking GPU0 -> GPU1 P2P");
-    for _ in 0..ITERS {
-        let (pageable_bufs, pinned_bufs, gpu_bufs) = create_bufs(&streams, false);
-
-        let t0 = std::time::Instant::now();
-        streams[0].memcpy_async(&gpu_bufs[1], &gpu_bufs[0]);
-        let t1 = std::time::Instant::now();
-        for stream in &streams {
-            stream.synchronize();
+    let bidir = |t: Transfer, i: usize| {
+        t.copy(Endpoint::device(gpu(i)), Endpoint::pinned(gpu(i)), gpu(i))
+            .copy(
+                Endpoint::pinned(gpu(i)).slot(1),
+                Endpoint::device(gpu(i)).slot(1),
+                gpu(i) + 1,
+            )
+    };
+    let t = Transfer::new(name, streams, size).host_options(host_options);
+    match def.name {
+        "p2p" => t.copy(Endpoint::device(gpu(1)), Endpoint::device(gpu(0)), gpu(0)),
+        "pageable-gpu0" => t.copy(Endpoint::device(gpu(0)), Endpoint::cpu(gpu(0)), gpu(0)),
+        "pinned-gpu0" => t.copy(Endpoint::device(gpu(0)), Endpoint::pinned(gpu(0)), gpu(0)),
+        "pinned0-gpu1" => t.copy(Endpoint::device(gpu(1)), Endpoint::pinned(gpu(0)), gpu(1)),
+        "pageable-pinned0" => t.copy(Endpoint::pinned(gpu(0)), Endpoint::cpu(gpu(0)), gpu(0)),
+        "registered-gpu0" => t.copy(
+            Endpoint::device(gpu(0)),
+            Endpoint::registered(gpu(0)),
+            gpu(0),
+        ),
+        "gpu0-pageable" => t.copy(Endpoint::cpu(gpu(0)), Endpoint::device(gpu(0)), gpu(0)),
+        "gpu0-pinned" => t.copy(Endpoint::pinned(gpu(0)), Endpoint::device(gpu(0)), gpu(0)),
+        "gpu0-registered" => t.copy(
+            Endpoint::registered(gpu(0)),
+            Endpoint::device(gpu(0)),
+            gpu(0),
+        ),
+        "bidir-gpu0" => bidir(t, 0).timed(),
+        "bidir-all" => (0..n_devices).fold(t, bidir).timed(),
+        "multi-stream" | "multi-thread" => {
+            let mut t = t;
+            for &s in &active {
+                t = t.copy(Endpoint::device(s), Endpoint::pinned(s), s);
+            }
+            if def.name == "multi-thread" {
+                t.threaded()
+            } else {
+                t
+            }
         }
-        let t2 = std::time::Instant::now();
-
-        let copy_time = t1.duration_since(t0);
-        let sync_time = t2.duration_since(t1);
-        let total_time = t2.duration_since(t0);
-        let bw = compute_bandwidth_gb_s(total_time, SIZE);
-        log!(
-            "--- Copy time: {:?}, Sync time: {:?}, Bandwidth: {:.2} GB/s",
-            copy_time,
-            sync_time,
-            bw
-        );
-
-        free_bufs(&streams, pageable_bufs, pinned_bufs, gpu_bufs);
+        "fan-out" => active.iter().fold(t, |t, &s| {
+            t.copy(Endpoint::device(s), Endpoint::pinned(gpu(0)), s)
+        }),
+        "chain" => (1..n_devices).fold(
+            t.copy(Endpoint::device(gpu(0)), Endpoint::pinned(gpu(0)), gpu(0)),
+            |t, i| {
+                t.copy_after(
+                    Endpoint::device(gpu(i)),
+                    Endpoint::device(gpu(0)),
+                    gpu(i),
+                    Some(0),
+                )
+            },
+        ),
+        _ => unreachable!(),
     }
+}
 
-    log!("Benchmarking Pageable -> GPU0");
-    for _ in 0..ITERS {
-        let (pageable_bufs, pinned_bufs, gpu_bufs) = create_bufs(&streams, false);
-
-        let t0 = std::time::Instant::now();
-        streams[0].memcpy_async(&gpu_bufs[0], &pageable_bufs);
-        let t1 = std::time::Instant::now();
-        for stream in &streams {
-            stream.synchronize();
+/// Re-runs this program once per device and aggregates the children's CSV.
+fn run_processes(n_devices: usize) -> Vec<BenchResult> {
+    let args = std::env::args_os().skip(1).collect::<Vec<_>>();
+    let results = multiproc::spawn(n_devices, |_| {
+        let mut command = std::process::Command::new(std::env::current_exe().unwrap());
+        command.args(&args);
+        command
+    })
+    .unwrap();
+    for result in &results {
+        if !result.status.success() {
+            std::process::exit(1);
         }
-        let t2 = std::time::Instant::now();
-
-        let copy_time = t1.duration_since(t0);
-        let sync_time = t2.duration_since(t1);
-        let total_time = t2.duration_since(t0);
-        let bw = compute_bandwidth_gb_s(total_time, SIZE);
-        log!(
-            "--- Copy time: {:?}, Sync time: {:?}, Bandwidth: {:.2} GB/s",
-            copy_time,
-            sync_time,
-            bw
-        );
-
-        free_bufs(&streams, pageable_bufs, pinned_bufs, gpu_bufs);
     }
+    multiproc::aggregate(&results)
+}
 
-    log!("Benchmarking Pageable -> GPU0 (touch=True)");
-    for _ in 0..ITERS {
-        let (pageable_bufs, pinned_bufs, gpu_bufs) = create_bufs(&streams, true);
-
-        let t0 = std::time::Instant::now();
-        streams[0].memcpy_async(&gpu_bufs[0], &pageable_bufs);
-        let t1 = std::time::Instant::now();
-        for stream in &streams {
-            stream.synchronize();
-        }
-        let t2 = std::time::Instant::now();
-
-        let copy_time = t1.duration_since(t0);
-        let sync_time = t2.duration_since(t1);
-        let total_time = t2.duration_since(t0);
-        let bw = compute_bandwidth_gb_s(total_time, SIZE);
-        log!(
-            "--- Copy time: {:?}, Sync time: {:?}, Bandwidth: {:.2} GB/s",
-            copy_time,
-            sync_time,
-            bw
-        );
-
-        free_bufs(&streams, pageable_bufs, pinned_bufs, gpu_bufs);
+fn main() {
+    let mut cli = Cli::parse();
+
+    // As a child of --processes: one device, results as CSV on stdout.
+    let child = multiproc::child();
+    if child.is_some() {
+        cli.format = Format::Csv;
+        cli.output = None;
+        cli.history = None;
+        cli.baseline = None;
+        cli.processes = false;
     }
 
-    log!("Benchmarking Pinned0 -> GPU0");
-    for _ in 0..ITERS {
-        let (pageable_bufs, pinned_bufs, gpu_bufs) = create_bufs(&streams, false);
-
-        let t0 = std::time::Instant::now();
-        streams[0].memcpy_async(&gpu_bufs[0], &pinned_bufs[0]);
-        let t1 = std::time::Instant::now();
-        for stream in &streams {
-            stream.synchronize();
+    if cli.list {
+        for def in SCENARIOS {
+            println!("{:<18} {}", def.name, def.description);
         }
-        let t2 = std::time::Instant::now();
-
-        let copy_time = t1.duration_since(t0);
-        let sync_time = t2.duration_since(t1);
-        let total_time = t2.duration_since(t0);
-        let bw = compute_bandwidth_gb_s(total_time, SIZE);
-        log!(
-            "--- Copy time: {:?}, Sync time: {:?}, Bandwidth: {:.2} GB/s",
-            copy_time,
-            sync_time,
-            bw
-        );
-
-        free_bufs(&streams, pageable_bufs, pinned_bufs, gpu_bufs);
+        return;
     }
 
-    log!("Benchmarking Pinned0 -> GPU0 (touch=True)");
-    for _ in 0..ITERS {
-        let (pageable_bufs, pinned_bufs, gpu_bufs) = create_bufs(&streams, true);
-
-        let t0 = std::time::Instant::now();
-        streams[0].memcpy_async(&gpu_bufs[0], &pinned_bufs[0]);
-        let t1 = std::time::Instant::now();
-        for stream in &streams {
-            stream.synchronize();
-        }
-        let t2 = std::time::Instant::now();
-
-        let copy_time = t1.duration_since(t0);
-        let sync_time = t2.duration_since(t1);
-        let total_time = t2.duration_since(t0);
-        let bw = compute_bandwidth_gb_s(total_time, SIZE);
-        log!(
-            "--- Copy time: {:?}, Sync time: {:?}, Bandwidth: {:.2} GB/s",
-            copy_time,
-            sync_time,
-            bw
-        );
-
-        free_bufs(&streams, pageable_bufs, pinned_bufs, gpu_bufs);
+    let baseline = cli.baseline.as_ref().map(|b| load_run(&cli, b));
+    if let (Some(compare), Some(baseline)) = (&cli.compare, &baseline) {
+        let current = load_run(&cli, compare);
+        std::process::exit(report_comparison(baseline, &current, &cli) as i32);
     }
 
-    log!("Benchmarking Pinned0 -> GPU1");
-    for _ in 0..ITERS {
-        let (pageable_bufs, pinned_bufs, gpu_bufs) = create_bufs(&streams, false);
-
-        let t0 = std::time::Instant::now();
-        streams[1].memcpy_async(&gpu_bufs[1], &pinned_bufs[0]);
-        let t1 = std::time::Instant::now();
-        for stream in &streams {
-            stream.synchronize();
+    // Expressions replace the built-in scenarios.
+    let selected = SCENARIOS
+        .iter()
+        .filter(|def| {
+            cli.expr.is_empty()
+                && (cli.scenario.is_empty() || cli.scenario.iter().any(|p| glob_match(p, def.name)))
+        })
+        .collect::<Vec<_>>();
+    for pattern in &cli.scenario {
+        if !SCENARIOS.iter().any(|def| glob_match(pattern, def.name)) {
+            Cli::command()
+                .error(
+                    ErrorKind::InvalidValue,
+                    format!("no scenario matches {:?} (see --list)", pattern),
+                )
+                .exit();
         }
-        let t2 = std::time::Instant::now();
-
-        let copy_time = t1.duration_since(t0);
-        let sync_time = t2.duration_since(t1);
-        let total_time = t2.duration_since(t0);
-        let bw = compute_bandwidth_gb_s(total_time, SIZE);
-        log!(
-            "--- Copy time: {:?}, Sync time: {:?}, Bandwidth: {:.2} GB/s",
-            copy_time,
-            sync_time,
-            bw
-        );
-
-        free_bufs(&streams, pageable_bufs, pinned_bufs, gpu_bufs);
+    }
+    if cli.streams == 0 {
+        Cli::command()
+            .error(ErrorKind::InvalidValue, "--streams must be at least 1")
+            .exit();
+    }
+    if cli.iters == 0 {
+        Cli::command()
+            .error(ErrorKind::InvalidValue, "--iters must be at least 1")
+            .exit();
     }
 
-    log!("Benchmarking Pageable -> Pinned0");
-    for _ in 0..ITERS {
-        let (pageable_bufs, pinned_bufs, gpu_bufs) = create_bufs(&streams, false);
-
-        let t0 = std::time::Instant::now();
-        streams[0].memcpy_async(&pinned_bufs[0], &pageable_bufs);
-        let t1 = std::time::Instant::now();
-        for stream in &streams {
-            stream.synchronize();
+    let mut ordinals = cli
+        .devices
+        .select()
+        .unwrap_or_else(|e| Cli::command().error(ErrorKind::InvalidValue, e).exit());
+    if let Some(child) = &child {
+        ordinals = vec![ordinals[child.rank]];
+    }
+    for expr in &cli.expr {
+        if ordinals.len() < expr.devices_needed() {
+            Cli::command()
+                .error(
+                    ErrorKind::InvalidValue,
+                    format!(
+                        "{} needs {} devices but --devices lists {}",
+                        expr,
+                        expr.devices_needed(),
+                        ordinals.len()
+                    ),
+                )
+                .exit();
         }
-        let t2 = std::time::Instant::now();
-
-        let copy_time = t1.duration_since(t0);
-        let sync_time = t2.duration_since(t1);
-        let total_time = t2.duration_since(t0);
-        let bw = compute_bandwidth_gb_s(total_time, SIZE);
-        log!(
-            "--- Copy time: {:?}, Sync time: {:?}, Bandwidth: {:.2} GB/s",
-            copy_time,
-            sync_time,
-            bw
-        );
-
-        free_bufs(&streams, pageable_bufs, pinned_bufs, gpu_bufs);
     }
-
-    log!("Benchmarking Pageable -> Pinned0 (touch=True)");
-    for _ in 0..ITERS {
-        let (pageable_bufs, pinned_bufs, gpu_bufs) = create_bufs(&streams, true);
-
-        let t0 = std::time::Instant::now();
-        streams[0].memcpy_async(&pinned_bufs[0], &pageable_bufs);
-        let t1 = std::time::Instant::now();
-        for stream in &streams {
-            stream.synchronize();
+    for def in &selected {
+        if ordinals.len() < def.min_devices {
+            Cli::command()
+                .error(
+                    ErrorKind::InvalidValue,
+                    format!(
+                        "scenario {} needs {} devices but --devices lists {}",
+                        def.name,
+                        def.min_devices,
+                        ordinals.len()
+                    ),
+                )
+                .exit();
         }
-        let t2 = std::time::Instant::now();
-
-        let copy_time = t1.duration_since(t0);
-        let sync_time = t2.duration_since(t1);
-        let total_time = t2.duration_since(t0);
-        let bw = compute_bandwidth_gb_s(total_time, SIZE);
-        log!(
-            "--- Copy time: {:?}, Sync time: {:?}, Bandwidth: {:.2} GB/s",
-            copy_time,
-            sync_time,
-            bw
-        );
+    }
 
-        free_bufs(&streams, pageable_bufs, pinned_bufs, gpu_bufs);
+    if cli.processes
+        && let Some(def) = selected.iter().find(|def| def.min_devices > 1)
+    {
+        Cli::command()
+            .error(
+                ErrorKind::InvalidValue,
+                format!("scenario {} can't run one device per process", def.name),
+            )
+            .exit();
     }
 
-    log!("Benchmarking Pinned0 -> GPU0, Pinned1 -> GPU1, Pinned2 -> GPU2, ... (multi stream)");
-    for _ in 0..ITERS {
-        let (pageable_bufs, pinned_bufs, gpu_bufs) = create_bufs(&streams, false);
+    log!("Hello from h2d");
 
-        let t0 = std::time::Instant::now();
-        for (pinned_buf, gpu_buf, stream) in izip!(&pinned_bufs, &gpu_bufs, &streams) {
-            stream.memcpy_async(gpu_buf, pinned_buf);
-        }
-        let t1 = std::time::Instant::now();
-        for stream in &streams {
-            stream.synchronize();
+    if cli.processes {
+        let mut env = Environment::collect(&ordinals);
+        // Each child binds itself to its own device's CPUs.
+        let topology = Topology::discover(&Sysfs::default(), &ordinals);
+        env.cpu_binding = Affinity::resolve(&cli.cpu_bind, &ordinals, &topology).describe();
+        let out: Box<dyn std::io::Write> = match &cli.output {
+            Some(path) => Box::new(std::fs::File::create(path).unwrap()),
+            None => Box::new(std::io::stdout()),
+        };
+        let mut reporter = Reporter::new(cli.format, env.clone(), out);
+        for result in run_processes(ordinals.len()) {
+            reporter.report(&result);
         }
-        let t2 = std::time::Instant::now();
-
-        let copy_time = t1.duration_since(t0);
-        let sync_time = t2.duration_since(t1);
-        log!("--- Copy time: {:?}, Sync time: {:?}", copy_time, sync_time);
-
-        free_bufs(&streams, pageable_bufs, pinned_bufs, gpu_bufs);
+        finish(&cli, &env, reporter, baseline.as_deref());
+        return;
     }
 
-    log!(
-        "Benchmarking Pinned0 -> GPU0, Pinned1 -> GPU1, Pinned2 -> GPU2, ... (multi thread/stream)"
+    let affinity = Affinity::resolve(
+        &cli.cpu_bind,
+        &ordinals,
+        &Topology::discover(&Sysfs::default(), &ordinals),
     );
-    for _ in 0..ITERS {
-        let streams = streams.clone();
-        let (pageable_bufs, pinned_bufs, gpu_bufs) = create_bufs(&streams, false);
-
-        // let t0 = std::time::Instant::now();
-        // for (pinned_buf, gpu_buf, stream) in izip!(&pinned_bufs, &gpu_bufs, &streams) {
-        //     stream.memcpy_async(gpu_buf, pinned_buf);
-        // }
-        // let t1 = std::time::Instant::now();
-        // for stream in &streams {
-        //     stream.synchronize();
-        // }
-        // let t2 = std::time::Instant::now();
-
-        // let copy_time = t1.duration_since(t0);
-        // let sync_time = t2.duration_since(t1);
-        // log!("--- Copy time: {:?}, Sync time: {:?}", copy_time, sync_time);
-
-        // free_bufs(&streams, pageable_bufs, pinned_bufs, gpu_bufs);
-
-        let ready_count = Arc::new(AtomicUsize::new(0));
-
-        // launch N threads
-        let n_threads = NUM_DEVICES;
-        let mut threads = Vec::new();
-        for i in 0..n_threads {
-            let streams = streams.clone();
-            let pinned_bufs = pinned_bufs.clone();
-            let gpu_bufs = gpu_bufs.clone();
-            let ready_count = ready_count.clone();
-            threads.push(std::thread::spawn(move || {
-                let stream = &streams[i];
-                let pinned_buf = &pinned_bufs[i];
-                let gpu_buf = &gpu_bufs[i];
-
-                ready_count.fetch_add(1, Ordering::SeqCst);
-                while ready_count.load(Ordering::SeqCst) < n_threads {
-                    std::thread::yield_now();
-                }
-
-                let t0 = std::time::Instant::now();
-                stream.memcpy_async(&gpu_buf, &pinned_buf);
-                let t1 = std::time::Instant::now();
-
-                stream.synchronize();
-                let t2 = std::time::Instant::now();
-
-                let copy_time = t1.duration_since(t0);
-                let sync_time = t2.duration_since(t1);
-                if i == 0 {
-                    log!("--- Copy time: {:?}, Sync time: {:?}", copy_time, sync_time);
-                }
-            }));
-        }
+    // Single-threaded scenarios issue everything from here.
+    affinity.bind_current_thread(ordinals[0]);
 
-        for thread in threads {
-            thread.join().unwrap();
-        }
+    let ctxs = ordinals
+        .iter()
+        .map(|&i| Context::new(i))
+        .collect::<Vec<_>>();
 
-        free_bufs(&streams, pageable_bufs, pinned_bufs, gpu_bufs);
+    let streams = ctxs
+        .iter()
+        .flat_map(|ctx| (0..streams_per_device(&cli)).map(|_| ctx.create_stream()))
+        .collect::<Vec<_>>();
+
+    for stream in &streams {
+        stream.synchronize();
     }
 
-    log!("Benchmarking Pinned0 -> GPU0, GPU1, GPU2, ... (multi stream)");
-    for _ in 0..ITERS {
-        let (pageable_bufs, pinned_bufs, gpu_bufs) = create_bufs(&streams, false);
+    let host_options = HostOptions {
+        prefault: if cli.touch {
+            Prefault::Touch
+        } else {
+            Prefault::None
+        },
+        ..Default::default()
+    };
+
+    let out: Box<dyn std::io::Write> = match &cli.output {
+        Some(path) => Box::new(std::fs::File::create(path).unwrap()),
+        None => Box::new(std::io::stdout()),
+    };
+    let mut env = Environment::collect(&ordinals);
+    env.cpu_binding = affinity.describe();
+    let mut reporter = Reporter::new(cli.format, env.clone(), out);
+
+    let mut alloc_policy = AllocPolicy::default();
+    if cli.alloc_fallback {
+        alloc_policy = alloc_policy.host_fallback();
+    }
+    if let Some(min) = cli.alloc_min {
+        alloc_policy = alloc_policy.shrink_to(min.as_usize());
+    }
 
-        let t0 = std::time::Instant::now();
-        let pinned0 = pinned_bufs[0].clone();
-        for (stream, gpu_buf) in izip!(&streams, &gpu_bufs) {
-            stream.memcpy_async(gpu_buf, &pinned0);
+    let runner = Runner {
+        warmup: cli.warmup,
+        iterations: cli.iters,
+    };
+    if !cli.expr.is_empty() {
+        let sizes = cli.sweep.as_ref().unwrap_or(&cli.size);
+        for &size in sizes {
+            for expr in &cli.expr {
+                run_expr(
+                    expr,
+                    &runner,
+                    &streams,
+                    &cli,
+                    size,
+                    &affinity,
+                    &mut reporter,
+                );
+            }
         }
-        let t1 = std::time::Instant::now();
-        for stream in &streams {
-            stream.synchronize();
+        finish(&cli, &env, reporter, baseline.as_deref());
+        return;
+    }
+    if let Some(sizes) = &cli.sweep {
+        let max = *sizes.last().unwrap();
+        for def in &selected {
+            let scenario = build(
+                def,
+                &cli,
+                &streams,
+                ctxs.len(),
+                max.as_usize(),
+                host_options,
+            )
+            .affinity(affinity.clone())
+            .alloc_policy(alloc_policy.clone())
+            .persistent(max.as_usize());
+            let mut scenario = Synchronized::new(scenario, child.as_ref().map(|c| &c.barrier));
+            let (results, _) = runner.sweep(&mut scenario, sizes);
+            scenario.inner.release();
+            for result in &results {
+                reporter.report(result);
+            }
         }
-        let t2 = std::time::Instant::now();
-
-        let copy_time = t1.duration_since(t0);
-        let sync_time = t2.duration_since(t1);
-        log!("--- Copy time: {:?}, Sync time: {:?}", copy_time, sync_time);
+    }
 
-        free_bufs(&streams, pageable_bufs, pinned_bufs, gpu_bufs);
+    let sizes = if cli.sweep.is_some() {
+        &[][..]
+    } else {
+        &cli.size[..]
+    };
+    for &size in sizes {
+        for def in &selected {
+            let scenario = build(
+                def,
+                &cli,
+                &streams,
+                ctxs.len(),
+                size.as_usize(),
+                host_options,
+            )
+            .affinity(affinity.clone())
+            .alloc_policy(alloc_policy.clone());
+            let mut scenario = Synchronized::new(scenario, child.as_ref().map(|c| &c.barrier));
+            let result = runner.run(&mut scenario as &mut dyn Scenario);
+            reporter.report(&result);
+        }
     }
+    finish(&cli, &env, reporter, baseline.as_deref());
+}
 
-    log!("Benchmarking Pinned -> GPU0 -> GPU1, GPU2, GPU3, ... (multi stream)");
-    for _ in 0..ITERS {
-        let (pageable_bufs, pinned_bufs, gpu_bufs) = create_bufs(&streams, false);
+/// Runs one --expr. With a `@numaN` placement the calling thread runs on that
+/// node while the scenario allocates and copies, so pinned memory lands there.
+fn run_expr(
+    expr: &TransferExpr,
+    runner: &Runner,
+    streams: &[Stream],
+    cli: &Cli,
+    size: ByteSize,
+    affinity: &Affinity,
+    reporter: &mut Reporter,
+) {
+    let previous = affinity::current_thread_cpus();
+    if let Some(node) = expr.numa_node()
+        && let Err(e) = affinity::bind_current_thread(&numa_node_cpus(node))
+    {
+        log_warn!("can't bind to NUMA node {}: {}", node, e);
+    }
+    let mut scenario = expr.build(streams, streams_per_device(cli), size.as_usize(), affinity);
+    let result = runner.run(&mut *scenario);
+    reporter.report(&result);
+    if expr.numa_node().is_some() {
+        let _ = affinity::bind_current_thread(&previous);
+    }
+}
 
-        let t0 = std::time::Instant::now();
-        streams[0].memcpy_async(&gpu_bufs[0], &pinned_bufs[0]);
-        streams[0].record_event(&events[0]);
-        for (stream, event, gpu_buf) in izip!(&streams, &events, &gpu_bufs).skip(1) {
-            stream.wait_for_event(event);
-            stream.memcpy_async(gpu_buf, &gpu_bufs[0]);
+/// Writes out results, records them in the history and compares them with
+/// the baseline, exiting with status 1 on a regression.
+fn finish(cli: &Cli, env: &Environment, reporter: Reporter, baseline: Option<&[HistoryRow]>) {
+    let results = reporter.finish();
+
+    if cli.alloc_report {
+        for row in accounting::usage() {
+            log!(
+                "GPU{} {:?}: {} allocations, {} total, peak {}, {} live",
+                row.device_id,
+                row.address_space,
+                row.usage.allocations,
+                ByteSize::from(row.usage.allocated_bytes),
+                ByteSize::from(row.usage.peak_bytes),
+                ByteSize::from(row.usage.live_bytes)
+            );
         }
+        accounting::warn_leaks(None);
+    }
 
-        let t1 = std::time::Instant::now();
-        for stream in &streams {
-            stream.synchronize();
+    if let Some(path) = &cli.history {
+        history::append(path, env, &results).unwrap();
+    }
+    if let Some(baseline) = baseline {
+        let current = HistoryRow::from_results(env, &results);
+        if report_comparison(baseline, &current, cli) {
+            std::process::exit(1);
         }
-        let t2 = std::time::Instant::now();
-
-        let copy_time = t1.duration_since(t0);
-        let sync_time = t2.duration_since(t1);
-        log!("--- Copy time: {:?}, Sync time: {:?}", copy_time, sync_time);
-
-        free_bufs(&streams, pageable_bufs, pinned_bufs, gpu_bufs);
     }
 }
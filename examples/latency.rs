@@ -0,0 +1,105 @@
+use clap::{CommandFactory, Parser, error::ErrorKind};
+use cudarc::driver::sys::CUctx_flags_enum;
+
+use cuda_gists::devices::DeviceSelector;
+use cuda_gists::latency::{self, LatencyStats};
+use cuda_gists::report::{self, Environment, Format};
+use cuda_gists::*;
+
+/// Per-copy latency and API overhead, reported as percentiles.
+#[derive(Debug, Parser)]
+#[command(name = "latency")]
+struct Cli {
+    /// Copy size for the tiny-copy measurements.
+    #[arg(long, default_value = "4")]
+    size: ByteSize,
+
+    /// Timed operations per measurement.
+    #[arg(long, default_value_t = 1000)]
+    iters: usize,
+
+    /// Devices by ordinal, GPU UUID (or prefix), PCI bus id or MIG id, or `all`; ping-pong across devices uses the first two.
+    #[arg(long, default_value = "0,1")]
+    devices: DeviceSelector,
+
+    /// Result format: text, json, csv or markdown.
+    #[arg(long, default_value = "text")]
+    format: Format,
+}
+
+fn main() {
+    let cli = Cli::parse();
+
+    if cli.iters == 0 {
+        Cli::command()
+            .error(ErrorKind::InvalidValue, "--iters must be at least 1")
+            .exit();
+    }
+
+    let ordinals = cli
+        .devices
+        .select()
+        .unwrap_or_else(|e| Cli::command().error(ErrorKind::InvalidValue, e).exit());
+    if ordinals.is_empty() {
+        Cli::command()
+            .error(ErrorKind::InvalidValue, "no devices selected")
+            .exit();
+    }
+
+    log!("Hello from latency");
+
+    let size = cli.size.as_usize();
+    let dev0 = ordinals[0];
+    let ctx = Context::with_flags(dev0, 0);
+    let stream = ctx.create_stream();
+    let other = ctx.create_stream();
+    let pinned = stream.create_buffer_async(size, AddressSpace::Pinned);
+    let pageable = stream.create_buffer_async(size, AddressSpace::Cpu);
+    let gpu = stream.create_buffer_async(size, AddressSpace::Device);
+    let gpu2 = stream.create_buffer_async(size, AddressSpace::Device);
+    stream.synchronize();
+
+    let mut stats: Vec<LatencyStats> = vec![
+        latency::memcpy_issue(&stream, &gpu, &pinned, cli.iters),
+        latency::memcpy_issue(&stream, &gpu, &pageable, cli.iters),
+        latency::memcpy_issue(&stream, &gpu2, &gpu, cli.iters),
+        latency::round_trip(&stream, &pinned, &gpu, cli.iters),
+        latency::round_trip(&stream, &pageable, &gpu, cli.iters),
+        latency::event_ping_pong(&stream, &other, cli.iters),
+    ];
+
+    if let Some(&dev1) = ordinals.get(1) {
+        let ctx1 = Context::with_flags(dev1, 0);
+        let stream1 = ctx1.create_stream();
+        stats.push(latency::event_ping_pong(&stream, &stream1, cli.iters));
+        ctx1.destroy();
+    }
+
+    for (name, flags) in [
+        ("auto", CUctx_flags_enum::CU_CTX_SCHED_AUTO),
+        ("spin", CUctx_flags_enum::CU_CTX_SCHED_SPIN),
+        ("yield", CUctx_flags_enum::CU_CTX_SCHED_YIELD),
+        ("blocking", CUctx_flags_enum::CU_CTX_SCHED_BLOCKING_SYNC),
+    ] {
+        let ctx = Context::with_flags(dev0, flags as u32);
+        let stream = ctx.create_stream();
+        let host = stream.create_buffer_async(size, AddressSpace::Pinned);
+        let dev = stream.create_buffer_async(size, AddressSpace::Device);
+        stream.synchronize();
+        for mut s in latency::sync_cost(&stream, &dev, &host, cli.iters) {
+            s.name = format!("{} (sched {})", s.name, name);
+            stats.push(s);
+        }
+        stream.free_buffer_sync(&host);
+        stream.free_buffer_sync(&dev);
+        ctx.destroy();
+    }
+
+    for buf in [&pinned, &pageable, &gpu, &gpu2] {
+        stream.free_buffer_sync(buf);
+    }
+    ctx.destroy();
+
+    let env = Environment::collect(&ordinals);
+    report::write_latency(&mut std::io::stdout(), cli.format, &env, &stats).unwrap();
+}
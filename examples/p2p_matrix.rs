@@ -0,0 +1,96 @@
+use clap::{CommandFactory, Parser, error::ErrorKind};
+
+use cuda_gists::devices::DeviceSelector;
+use cuda_gists::p2p::{self, Matrix};
+use cuda_gists::report::{self, Environment, Format};
+use cuda_gists::*;
+
+/// Bandwidth and latency between every pair of devices, with peer access
+/// disabled and enabled.
+#[derive(Debug, Parser)]
+#[command(name = "p2p-matrix")]
+struct Cli {
+    /// Devices by ordinal, GPU UUID (or prefix), PCI bus id or MIG id, or `all`.
+    #[arg(long, default_value = "all")]
+    devices: DeviceSelector,
+
+    /// Copy size for the bandwidth matrices.
+    #[arg(long, default_value = "64MiB")]
+    size: ByteSize,
+
+    /// Copies timed per cell.
+    #[arg(long, default_value_t = 20)]
+    iters: usize,
+
+    /// Tiny copies timed per latency cell.
+    #[arg(long, default_value_t = 1000)]
+    latency_iters: usize,
+
+    /// Result format: text, json, csv or markdown.
+    #[arg(long, default_value = "text")]
+    format: Format,
+}
+
+fn main() {
+    let cli = Cli::parse();
+
+    let ordinals = cli
+        .devices
+        .select()
+        .unwrap_or_else(|e| Cli::command().error(ErrorKind::InvalidValue, e).exit());
+    if ordinals.is_empty() {
+        Cli::command()
+            .error(ErrorKind::InvalidValue, "no devices selected")
+            .exit();
+    }
+    if cli.iters == 0 || cli.latency_iters == 0 {
+        Cli::command()
+            .error(ErrorKind::InvalidValue, "iteration counts must be positive")
+            .exit();
+    }
+
+    log!("Hello from p2p-matrix");
+
+    let ctxs = ordinals
+        .iter()
+        .map(|&d| Context::new(d))
+        .collect::<Vec<_>>();
+    let streams = ctxs.iter().map(|c| c.create_stream()).collect::<Vec<_>>();
+    let size = cli.size.as_usize();
+
+    let mut matrices = Vec::new();
+    for enabled in [false, true] {
+        let access = p2p::set_all_peer_access(&ctxs, enabled);
+        let suffix = if enabled {
+            "P2P enabled"
+        } else {
+            "P2P disabled"
+        };
+        let label = |mut m: Matrix| {
+            m.name = format!("{}, {}", m.name, suffix);
+            m
+        };
+        if enabled {
+            matrices.push(Matrix {
+                name: String::from("Peer access"),
+                unit: "bool",
+                devices: ordinals.clone(),
+                values: access
+                    .iter()
+                    .map(|row| row.iter().map(|&a| Some(a as u8 as f64)).collect())
+                    .collect(),
+            });
+        }
+        matrices.push(label(p2p::bandwidth_matrix(
+            &streams, size, cli.iters, false,
+        )));
+        matrices.push(label(p2p::bandwidth_matrix(
+            &streams, size, cli.iters, true,
+        )));
+        matrices.push(label(p2p::latency_matrix(&streams, cli.latency_iters)));
+    }
+    p2p::set_all_peer_access(&ctxs, false);
+
+    let env = Environment::collect(&ordinals);
+    report::write_matrices(&mut std::io::stdout(), cli.format, &env, &matrices).unwrap();
+}
@@ -1,10 +1,18 @@
 use cudarc::driver::sys;
 
+use cuda_gists::devices::DeviceSelector;
 use cuda_gists::*;
 
 fn main() {
-    // set env var CUDA_VISIBLE_DEVICES=0,1,2,3
-    unsafe { std::env::set_var("CUDA_VISIBLE_DEVICES", "0,1") };
+    // Devices to expose, e.g. `peer 0,1` or `peer GPU-8e5c,GPU-1a2b`; this has
+    // to reach CUDA_VISIBLE_DEVICES before the driver is initialized.
+    let selector: DeviceSelector = std::env::args()
+        .nth(1)
+        .as_deref()
+        .unwrap_or("0,1")
+        .parse()
+        .unwrap_or_else(|e| panic!("{}", e));
+    selector.export_visible().unwrap();
 
     unsafe { sys::cuInit(0) }.result().unwrap();
 
@@ -20,10 +28,8 @@ fn main() {
     let ctxs = unsafe {
         let mut ctxs: [*mut sys::CUctx_st; MAX_NUM_DEVICES] =
             [std::ptr::null_mut(); MAX_NUM_DEVICES];
-        for i in 0..n_devices {
-            sys::cuCtxCreate_v2(&mut ctxs[i], 0, i as i32)
-                .result()
-                .unwrap();
+        for (i, ctx) in ctxs.iter_mut().enumerate().take(n_devices) {
+            sys::cuCtxCreate_v2(ctx, 0, i as i32).result().unwrap();
         }
         ctxs
     };
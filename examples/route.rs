@@ -0,0 +1,80 @@
+use std::time::Instant;
+
+use clap::{CommandFactory, Parser, error::ErrorKind};
+
+use cuda_gists::devices::DeviceSelector;
+use cuda_gists::p2p;
+use cuda_gists::planner::TransferPlanner;
+use cuda_gists::topology::{Sysfs, Topology};
+use cuda_gists::*;
+
+/// Plans and times a copy between every pair of devices, explaining the
+/// route taken.
+#[derive(Debug, Parser)]
+#[command(name = "route")]
+struct Cli {
+    /// Devices by ordinal, GPU UUID (or prefix), PCI bus id or MIG id, or `all`.
+    #[arg(long, default_value = "all")]
+    devices: DeviceSelector,
+
+    #[arg(long, default_value = "256MiB")]
+    size: ByteSize,
+
+    /// Pipeline chunk size for staged and multi-hop routes.
+    #[arg(long, default_value = "4MiB")]
+    chunk: ByteSize,
+
+    /// Leave peer access disabled, forcing staged routes.
+    #[arg(long)]
+    no_peer: bool,
+}
+
+fn main() {
+    let cli = Cli::parse();
+    let devices = cli
+        .devices
+        .select()
+        .unwrap_or_else(|e| Cli::command().error(ErrorKind::InvalidValue, e).exit());
+
+    log!("Hello from route");
+
+    let ctxs = devices.iter().map(|&d| Context::new(d)).collect::<Vec<_>>();
+    let streams = ctxs.iter().map(|c| c.create_stream()).collect::<Vec<_>>();
+    let peer_access = p2p::set_all_peer_access(&ctxs, !cli.no_peer);
+    let mut planner = TransferPlanner::new(&streams, peer_access)
+        .topology(Topology::discover(&Sysfs::default(), &devices))
+        .chunk_size(cli.chunk.as_usize());
+
+    let size = cli.size.as_usize();
+    let bufs = streams
+        .iter()
+        .map(|s| s.create_buffer_async(size, AddressSpace::Device))
+        .collect::<Vec<_>>();
+    for s in &streams {
+        s.synchronize();
+    }
+
+    for (i, src) in bufs.iter().enumerate() {
+        for (j, dst) in bufs.iter().enumerate().filter(|&(j, _)| j != i) {
+            // First copy allocates bounce buffers; time the second.
+            planner.copy(dst, src);
+            streams[j].synchronize();
+            let t0 = Instant::now();
+            let plan = planner.copy(dst, src);
+            streams[j].synchronize();
+            let elapsed = t0.elapsed();
+            log!(
+                "GPU{} -> GPU{}: {} ({})",
+                devices[i],
+                devices[j],
+                Bandwidth::from_transfer(cli.size, elapsed),
+                plan
+            );
+        }
+    }
+
+    planner.release();
+    for (s, buf) in streams.iter().zip(&bufs) {
+        s.free_buffer_sync(buf);
+    }
+}
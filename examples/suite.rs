@@ -0,0 +1,208 @@
+use clap::{CommandFactory, Parser, error::ErrorKind};
+
+use cuda_gists::affinity::{Affinity, CpuBinding};
+use cuda_gists::bench::{BenchResult, Runner, Scenario, glob_match};
+use cuda_gists::devices::DeviceSelector;
+use cuda_gists::multiproc::{self, Synchronized};
+use cuda_gists::report::{Environment, Format, Reporter};
+use cuda_gists::suite::{Concurrency, PRESETS, ScenarioSpec, Suite};
+use cuda_gists::topology::{Sysfs, Topology};
+use cuda_gists::*;
+
+/// Runs a benchmark suite described in TOML.
+#[derive(Debug, Parser)]
+#[command(name = "suite")]
+struct Cli {
+    /// A built-in suite (`quick`, `full`) or a path to a TOML file.
+    #[arg(default_value = "quick")]
+    suite: String,
+
+    /// Devices to use instead of the suite's own `devices`.
+    #[arg(long)]
+    devices: Option<DeviceSelector>,
+
+    /// Scenarios to run, by name or glob (default: all).
+    #[arg(long, value_delimiter = ',')]
+    scenario: Vec<String>,
+
+    /// Result format: text, json, csv or markdown.
+    #[arg(long, default_value = "text")]
+    format: Format,
+
+    /// Write results here instead of stdout.
+    #[arg(long)]
+    output: Option<std::path::PathBuf>,
+
+    /// Bind copy-issuing threads to CPUs: `none`, `local` or a CPU list.
+    #[arg(long, default_value = "none")]
+    cpu_bind: CpuBinding,
+
+    /// Validate the suite against the devices and exit.
+    #[arg(long)]
+    check: bool,
+
+    /// List presets and the suite's scenarios and exit.
+    #[arg(long)]
+    list: bool,
+
+    /// Set on the children of a `processes` scenario: the one to run.
+    #[arg(long, hide = true)]
+    child_scenario: Option<String>,
+}
+
+fn runner(suite: &Suite, spec: &ScenarioSpec) -> Runner {
+    Runner {
+        warmup: spec.warmup.unwrap_or(suite.warmup),
+        iterations: spec.iterations.unwrap_or(suite.iterations),
+    }
+}
+
+/// Re-runs this program for `spec` once per device and aggregates the
+/// children's CSV.
+fn run_processes(spec: &ScenarioSpec, n_devices: usize) -> Vec<BenchResult> {
+    let args = std::env::args_os().skip(1).collect::<Vec<_>>();
+    let results = multiproc::spawn(n_devices, |_| {
+        let mut command = std::process::Command::new(std::env::current_exe().unwrap());
+        command.args(&args).arg("--child-scenario").arg(&spec.name);
+        command
+    })
+    .unwrap();
+    for result in &results {
+        if !result.status.success() {
+            std::process::exit(1);
+        }
+    }
+    multiproc::aggregate(&results)
+}
+
+fn main() {
+    let mut cli = Cli::parse();
+
+    let suite = Suite::load_or_preset(&cli.suite).unwrap_or_else(|e| {
+        Cli::command()
+            .error(
+                ErrorKind::InvalidValue,
+                format!("can't load suite {}: {}", cli.suite, e),
+            )
+            .exit()
+    });
+
+    if cli.list {
+        let presets = PRESETS.iter().map(|(name, _)| *name).collect::<Vec<_>>();
+        println!("presets: {}", presets.join(", "));
+        println!("suite {}:", suite.name);
+        for spec in &suite.scenarios {
+            let sizes = spec.sizes.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+            println!(
+                "  {:<18} {:?} -> {:?}, {:?}, {}",
+                spec.name,
+                spec.src,
+                spec.dst,
+                spec.concurrency,
+                sizes.join(",")
+            );
+        }
+        return;
+    }
+
+    // As a child of a `processes` scenario: one device, results as CSV.
+    let child = multiproc::child();
+    if child.is_some() {
+        cli.format = Format::Csv;
+        cli.output = None;
+    }
+
+    let selected = suite
+        .scenarios
+        .iter()
+        .filter(|spec| match &cli.child_scenario {
+            Some(name) => spec.name == *name,
+            None => {
+                cli.scenario.is_empty() || cli.scenario.iter().any(|p| glob_match(p, &spec.name))
+            }
+        })
+        .collect::<Vec<_>>();
+    for pattern in &cli.scenario {
+        if !suite.scenarios.iter().any(|s| glob_match(pattern, &s.name)) {
+            Cli::command()
+                .error(
+                    ErrorKind::InvalidValue,
+                    format!("no scenario matches {:?} (see --list)", pattern),
+                )
+                .exit();
+        }
+    }
+
+    let selector = match cli.devices.clone() {
+        Some(selector) => selector,
+        None => suite
+            .device_selector()
+            .unwrap_or_else(|e| Cli::command().error(ErrorKind::InvalidValue, e).exit()),
+    };
+    let mut ordinals = selector
+        .select()
+        .unwrap_or_else(|e| Cli::command().error(ErrorKind::InvalidValue, e).exit());
+    if let Err(e) = suite.validate(ordinals.len()) {
+        Cli::command().error(ErrorKind::InvalidValue, e).exit();
+    }
+    if cli.check {
+        log!(
+            "Suite {} is valid for {} device(s)",
+            suite.name,
+            ordinals.len()
+        );
+        return;
+    }
+    if let Some(child) = &child {
+        ordinals = vec![ordinals[child.rank]];
+    }
+
+    log!("Running suite {}", suite.name);
+
+    let affinity = Affinity::resolve(
+        &cli.cpu_bind,
+        &ordinals,
+        &Topology::discover(&Sysfs::default(), &ordinals),
+    );
+    affinity.bind_current_thread(ordinals[0]);
+
+    let ctxs = ordinals
+        .iter()
+        .map(|&i| Context::new(i))
+        .collect::<Vec<_>>();
+    let per_device = suite.streams_per_device();
+    let streams = ctxs
+        .iter()
+        .flat_map(|ctx| (0..per_device).map(|_| ctx.create_stream()))
+        .collect::<Vec<_>>();
+    for stream in &streams {
+        stream.synchronize();
+    }
+
+    let out: Box<dyn std::io::Write> = match &cli.output {
+        Some(path) => Box::new(std::fs::File::create(path).unwrap()),
+        None => Box::new(std::io::stdout()),
+    };
+    let mut env = Environment::collect(&ordinals);
+    env.cpu_binding = affinity.describe();
+    let mut reporter = Reporter::new(cli.format, env, out);
+
+    for spec in selected {
+        if spec.concurrency == Concurrency::Processes && child.is_none() {
+            for result in run_processes(spec, ordinals.len()) {
+                reporter.report(&result);
+            }
+            continue;
+        }
+        let runner = runner(&suite, spec);
+        for size in &spec.sizes {
+            let scenario = spec
+                .build(&streams, per_device, ctxs.len(), size.as_usize())
+                .affinity(affinity.clone());
+            let mut scenario = Synchronized::new(scenario, child.as_ref().map(|c| &c.barrier));
+            let result = runner.run(&mut scenario as &mut dyn Scenario);
+            reporter.report(&result);
+        }
+    }
+    reporter.finish();
+}
@@ -0,0 +1,48 @@
+use clap::{CommandFactory, Parser, error::ErrorKind};
+
+use cuda_gists::devices::DeviceSelector;
+use cuda_gists::topology::{Sysfs, Topology};
+
+/// Where each GPU sits in the PCI hierarchy and which CPUs are local to it.
+#[derive(Debug, Parser)]
+#[command(name = "topology")]
+struct Cli {
+    /// Devices by ordinal, GPU UUID (or prefix), PCI bus id or MIG id, or `all`.
+    #[arg(long, default_value = "all")]
+    devices: DeviceSelector,
+
+    /// Read sysfs from here instead of /sys.
+    #[arg(long, default_value = "/sys")]
+    sysfs: std::path::PathBuf,
+}
+
+fn main() {
+    let cli = Cli::parse();
+    let devices = cli
+        .devices
+        .select()
+        .unwrap_or_else(|e| Cli::command().error(ErrorKind::InvalidValue, e).exit());
+
+    let topology = Topology::discover(&Sysfs::new(cli.sysfs), &devices);
+    print!("{}", topology);
+    for &d in &devices {
+        println!("GPU{} local CPUs: {:?}", d, topology.local_cpus(d));
+    }
+    println!();
+    print!("{:>6}", "");
+    for &b in &devices {
+        print!("{:>14}", format!("GPU{}", b));
+    }
+    println!();
+    for &a in &devices {
+        print!("{:>6}", format!("GPU{}", a));
+        for &b in &devices {
+            if a == b {
+                print!("{:>14}", "X");
+            } else {
+                print!("{:>14}", format!("{:?}", topology.relation(a, b)));
+            }
+        }
+        println!();
+    }
+}
@@ -0,0 +1,254 @@
+//! Bookkeeping of every live `Buffer`: who allocated it, where and how big,
+//! with per-context totals and high-water marks, so forgotten buffers can be
+//! found with `leaks`.
+
+use std::collections::HashMap;
+use std::panic::Location;
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::*;
+
+/// A buffer that has been allocated and not yet freed.
+#[derive(Debug, Clone)]
+pub struct LiveBuffer {
+    pub addr: u64,
+    pub size: usize,
+    pub address_space: AddressSpace,
+    pub device_id: i32,
+    /// `CUcontext` and `CUstream` the buffer was created on, as addresses.
+    pub ctx: usize,
+    pub stream: usize,
+    pub label: Option<String>,
+    /// The call that allocated it.
+    pub location: &'static Location<'static>,
+    pub created: Instant,
+}
+
+impl LiveBuffer {
+    pub fn age(&self) -> Duration {
+        self.created.elapsed()
+    }
+}
+
+impl std::fmt::Display for LiveBuffer {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {:?} at {:#x}",
+            ByteSize::from(self.size),
+            self.address_space,
+            self.addr
+        )?;
+        if let Some(label) = &self.label {
+            write!(f, " {:?}", label)?;
+        }
+        write!(
+            f,
+            " from {} on stream {:#x}, alive {:.1?}",
+            self.location,
+            self.stream,
+            self.age()
+        )
+    }
+}
+
+/// Allocation totals for one context and address space.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Usage {
+    pub live_buffers: usize,
+    pub live_bytes: usize,
+    /// Highest `live_bytes` seen.
+    pub peak_bytes: usize,
+    /// Buffers allocated so far, freed or not.
+    pub allocations: usize,
+    pub allocated_bytes: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct UsageRow {
+    pub device_id: i32,
+    pub ctx: usize,
+    pub address_space: AddressSpace,
+    pub usage: Usage,
+}
+
+#[derive(Default)]
+struct Ledger {
+    live: HashMap<(usize, u64), LiveBuffer>,
+    usage: Vec<UsageRow>,
+}
+
+impl Ledger {
+    fn usage(&mut self, buf: &LiveBuffer) -> &mut Usage {
+        let idx = match self
+            .usage
+            .iter()
+            .position(|r| r.ctx == buf.ctx && r.address_space == buf.address_space)
+        {
+            Some(idx) => idx,
+            None => {
+                self.usage.push(UsageRow {
+                    device_id: buf.device_id,
+                    ctx: buf.ctx,
+                    address_space: buf.address_space.clone(),
+                    usage: Usage::default(),
+                });
+                self.usage.len() - 1
+            }
+        };
+        &mut self.usage[idx].usage
+    }
+}
+
+static LEDGER: LazyLock<Mutex<Ledger>> = LazyLock::new(Default::default);
+
+/// Records a new buffer; called by `Stream` when it allocates.
+pub(crate) fn track(stream: &Stream, buf: &Buffer, location: &'static Location<'static>) {
+    let live = LiveBuffer {
+        addr: buf.addr,
+        size: buf.size,
+        address_space: buf.address_space.clone(),
+        device_id: buf.ctx.device_id,
+        ctx: buf.ctx.ctx as usize,
+        stream: stream.stream as usize,
+        label: None,
+        location,
+        created: Instant::now(),
+    };
+    let mut ledger = LEDGER.lock().unwrap();
+    let usage = ledger.usage(&live);
+    usage.live_buffers += 1;
+    usage.live_bytes += live.size;
+    usage.peak_bytes = usage.peak_bytes.max(usage.live_bytes);
+    usage.allocations += 1;
+    usage.allocated_bytes += live.size;
+    ledger.live.insert((live.ctx, live.addr), live);
+}
+
+/// Forgets a freed buffer.
+pub(crate) fn untrack(buf: &Buffer) {
+    let mut ledger = LEDGER.lock().unwrap();
+    if let Some(live) = ledger.live.remove(&(buf.ctx.ctx as usize, buf.addr)) {
+        let usage = ledger.usage(&live);
+        usage.live_buffers -= 1;
+        usage.live_bytes -= live.size;
+    }
+}
+
+/// Drops the records of `ctx`'s live buffers; called when it is destroyed.
+pub(crate) fn forget_context(ctx: &Context) {
+    let ctx = ctx.ctx as usize;
+    let mut ledger = LEDGER.lock().unwrap();
+    ledger.live.retain(|(c, _), _| *c != ctx);
+    for row in ledger.usage.iter_mut().filter(|r| r.ctx == ctx) {
+        row.usage.live_buffers = 0;
+        row.usage.live_bytes = 0;
+    }
+}
+
+impl Buffer {
+    /// Names the buffer in leak reports, e.g. `"h2d staging"`.
+    pub fn set_label(&self, label: impl Into<String>) {
+        if let Some(live) = LEDGER
+            .lock()
+            .unwrap()
+            .live
+            .get_mut(&(self.ctx.ctx as usize, self.addr))
+        {
+            live.label = Some(label.into());
+        }
+    }
+}
+
+/// Totals per context and address space, in first-allocation order.
+pub fn usage() -> Vec<UsageRow> {
+    LEDGER.lock().unwrap().usage.clone()
+}
+
+/// Live bytes in `address_space` across all contexts.
+pub fn live_bytes(address_space: &AddressSpace) -> usize {
+    usage()
+        .iter()
+        .filter(|r| r.address_space == *address_space)
+        .map(|r| r.usage.live_bytes)
+        .sum()
+}
+
+/// Buffers still alive, on `ctx` or on every context, oldest first.
+pub fn live_buffers(ctx: Option<&Context>) -> Vec<LiveBuffer> {
+    let mut live = LEDGER
+        .lock()
+        .unwrap()
+        .live
+        .values()
+        .filter(|b| ctx.is_none_or(|c| c.ctx as usize == b.ctx))
+        .cloned()
+        .collect::<Vec<_>>();
+    live.sort_by_key(|b| b.created);
+    live
+}
+
+/// Buffers still alive, grouped with their context's totals.
+#[derive(Debug, Clone)]
+pub struct LeakReport {
+    pub usage: Vec<UsageRow>,
+    pub buffers: Vec<LiveBuffer>,
+}
+
+impl LeakReport {
+    pub fn is_empty(&self) -> bool {
+        self.buffers.is_empty()
+    }
+}
+
+impl std::fmt::Display for LeakReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        for row in &self.usage {
+            let buffers = self
+                .buffers
+                .iter()
+                .filter(|b| b.ctx == row.ctx && b.address_space == row.address_space)
+                .collect::<Vec<_>>();
+            if buffers.is_empty() {
+                continue;
+            }
+            writeln!(
+                f,
+                "GPU{} {:?}: {} buffers, {} live (peak {})",
+                row.device_id,
+                row.address_space,
+                row.usage.live_buffers,
+                ByteSize::from(row.usage.live_bytes),
+                ByteSize::from(row.usage.peak_bytes)
+            )?;
+            for buf in buffers {
+                writeln!(f, "  {}", buf)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Buffers not yet freed on `ctx`, or on every context.
+pub fn leaks(ctx: Option<&Context>) -> LeakReport {
+    LeakReport {
+        usage: usage()
+            .into_iter()
+            .filter(|r| ctx.is_none_or(|c| c.ctx as usize == r.ctx))
+            .collect(),
+        buffers: live_buffers(ctx),
+    }
+}
+
+/// Logs a warning listing `leaks(ctx)`, if there are any.
+pub fn warn_leaks(ctx: Option<&Context>) {
+    let report = leaks(ctx);
+    if !report.is_empty() {
+        log_warn!(
+            "{} buffer(s) not freed:\n{}",
+            report.buffers.len(),
+            report.to_string().trim_end()
+        );
+    }
+}
@@ -0,0 +1,220 @@
+//! Pinning the threads that drive copies to CPUs near their GPU.
+
+use crate::host::parse_cpu_list;
+use crate::log_warn;
+use crate::topology::Topology;
+
+/// CPUs a `cpu_set_t` can describe.
+pub const MAX_CPUS: usize = libc::CPU_SETSIZE as usize;
+
+/// Restricts the calling thread to `cpus`, which must not be empty.
+pub fn bind_current_thread(cpus: &[usize]) -> std::io::Result<()> {
+    if cpus.is_empty() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "no CPUs to bind to",
+        ));
+    }
+    if let Some(&cpu) = cpus.iter().find(|&&cpu| cpu >= MAX_CPUS) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("CPU {} is beyond the {} a CPU set can hold", cpu, MAX_CPUS),
+        ));
+    }
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        for &cpu in cpus {
+            libc::CPU_SET(cpu, &mut set);
+        }
+        if libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// CPUs the calling thread may run on.
+pub fn current_thread_cpus() -> Vec<usize> {
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        if libc::sched_getaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &mut set) != 0 {
+            return Vec::new();
+        }
+        (0..MAX_CPUS)
+            .filter(|&cpu| libc::CPU_ISSET(cpu, &set))
+            .collect()
+    }
+}
+
+/// Formats CPUs as a kernel CPU list such as `0-7,16-23`; the inverse of
+/// `host::parse_cpu_list`.
+pub fn format_cpu_list(cpus: &[usize]) -> String {
+    let mut cpus = cpus.to_vec();
+    cpus.sort();
+    cpus.dedup();
+    let mut ranges: Vec<(usize, usize)> = Vec::new();
+    for cpu in cpus {
+        match ranges.last_mut() {
+            Some((_, hi)) if *hi + 1 == cpu => *hi = cpu,
+            _ => ranges.push((cpu, cpu)),
+        }
+    }
+    ranges
+        .iter()
+        .map(|&(lo, hi)| {
+            if lo == hi {
+                lo.to_string()
+            } else {
+                format!("{}-{}", lo, hi)
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Where to run threads that drive a device.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum CpuBinding {
+    /// Leave scheduling to the OS.
+    #[default]
+    None,
+    /// The CPUs local to each GPU, per the topology.
+    Local,
+    /// These CPUs, whatever the device.
+    Cpus(Vec<usize>),
+}
+
+impl std::str::FromStr for CpuBinding {
+    type Err = String;
+
+    /// `none`, `local`, or a CPU list like `0-7,16`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(CpuBinding::None),
+            "local" | "numa" => Ok(CpuBinding::Local),
+            list => {
+                let cpus = parse_cpu_list(list)
+                    .map_err(|e| format!("expected none, local or a CPU list: {}", e))?;
+                if cpus.is_empty() {
+                    return Err(format!(
+                        "expected none, local or a CPU list, got {:?}",
+                        list
+                    ));
+                }
+                Ok(CpuBinding::Cpus(cpus))
+            }
+        }
+    }
+}
+
+/// A `CpuBinding` resolved to CPUs per device ordinal.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Affinity {
+    pub devices: Vec<(i32, Vec<usize>)>,
+}
+
+impl Affinity {
+    /// Devices whose local CPUs are unknown stay unbound.
+    pub fn resolve(binding: &CpuBinding, devices: &[i32], topology: &Topology) -> Self {
+        let devices = devices
+            .iter()
+            .map(|&d| {
+                let cpus = match binding {
+                    CpuBinding::None => Vec::new(),
+                    CpuBinding::Local => topology.local_cpus(d),
+                    CpuBinding::Cpus(cpus) => cpus.clone(),
+                };
+                if cpus.is_empty() && *binding == CpuBinding::Local {
+                    log_warn!("no local CPUs known for GPU{}; leaving it unbound", d);
+                }
+                (d, cpus)
+            })
+            .filter(|(_, cpus)| !cpus.is_empty())
+            .collect();
+        Self { devices }
+    }
+
+    pub fn cpus(&self, device_id: i32) -> &[usize] {
+        self.devices
+            .iter()
+            .find(|(d, _)| *d == device_id)
+            .map_or(&[], |(_, cpus)| cpus)
+    }
+
+    /// Binds the calling thread to `device_id`'s CPUs, warning on failure.
+    /// Devices without CPUs are left unbound.
+    pub fn bind_current_thread(&self, device_id: i32) {
+        let cpus = self.cpus(device_id);
+        if cpus.is_empty() {
+            return;
+        }
+        if let Err(e) = bind_current_thread(cpus) {
+            log_warn!(
+                "can't bind thread to CPUs {} for GPU{}: {}",
+                format_cpu_list(cpus),
+                device_id,
+                e
+            );
+        }
+    }
+
+    /// e.g. `GPU0=0-15;GPU1=16-31`, or empty if nothing is bound.
+    pub fn describe(&self) -> String {
+        self.devices
+            .iter()
+            .map(|(d, cpus)| format!("GPU{}={}", d, format_cpu_list(cpus)))
+            .collect::<Vec<_>>()
+            .join(";")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_binding() {
+        assert_eq!("none".parse(), Ok(CpuBinding::None));
+        assert_eq!("numa".parse(), Ok(CpuBinding::Local));
+        assert_eq!("0-2,5".parse(), Ok(CpuBinding::Cpus(vec![0, 1, 2, 5])));
+        assert_eq!("1023".parse(), Ok(CpuBinding::Cpus(vec![1023])));
+    }
+
+    #[test]
+    fn reject_bad_binding() {
+        for bad in ["", "7-0", "5000", "0-1024", "x", "1-", "local,0"] {
+            assert!(bad.parse::<CpuBinding>().is_err(), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn reject_huge_range_without_expanding_it() {
+        let err = "0-18446744073709551615".parse::<CpuBinding>().unwrap_err();
+        assert!(err.contains("out of range"), "{}", err);
+        assert!(parse_cpu_list(&format!("0-{}", MAX_CPUS)).is_err());
+        assert_eq!(
+            parse_cpu_list(&format!("0-{}", MAX_CPUS - 1))
+                .unwrap()
+                .len(),
+            MAX_CPUS
+        );
+    }
+
+    #[test]
+    fn bind_out_of_range() {
+        let err = bind_current_thread(&[MAX_CPUS]).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn bind_nothing_is_an_error() {
+        let err = bind_current_thread(&[]).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn cpu_list_round_trip() {
+        let list = "0-3,8,10-11";
+        assert_eq!(format_cpu_list(&parse_cpu_list(list).unwrap()), list);
+    }
+}
@@ -0,0 +1,373 @@
+//! Allocation that degrades instead of panicking when memory runs out: retry
+//! after the caller frees something, fall back to other address spaces, or
+//! settle for a smaller buffer.
+
+use std::panic::Location;
+use std::sync::Arc;
+
+use cudarc::driver::DriverError;
+
+use crate::*;
+
+/// Sizes found by shrinking are multiples of this, and the search stops once
+/// it is this close to the largest size that fits.
+pub const SHRINK_GRANULARITY: usize = 2 * 1024 * 1024;
+
+/// Called with the address space and size of a failed allocation; returns
+/// whether it released any memory, in which case the allocation is retried.
+pub type Reclaim = Arc<dyn Fn(&AddressSpace, usize) -> bool + Send + Sync>;
+
+/// What to do when an allocation fails. The default fails straight away.
+#[derive(Clone, Default)]
+pub struct AllocPolicy {
+    pub reclaim: Option<Reclaim>,
+    /// Address spaces to try, in order, when the requested one fails.
+    pub fallback: Vec<AddressSpace>,
+    /// Accept buffers down to this size when no space fits the full size.
+    pub min_size: Option<usize>,
+}
+
+impl std::fmt::Debug for AllocPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("AllocPolicy")
+            .field("reclaim", &self.reclaim.is_some())
+            .field("fallback", &self.fallback)
+            .field("min_size", &self.min_size)
+            .finish()
+    }
+}
+
+impl AllocPolicy {
+    pub fn reclaim(
+        mut self,
+        reclaim: impl Fn(&AddressSpace, usize) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.reclaim = Some(Arc::new(reclaim));
+        self
+    }
+
+    pub fn fallback(mut self, spaces: &[AddressSpace]) -> Self {
+        self.fallback = spaces.to_vec();
+        self
+    }
+
+    /// Falls back between host spaces: `Pinned` to `Registered`, then `Cpu`;
+    /// `Registered` and `Cpu` to each other. `Device` has no fallback.
+    pub fn host_fallback(self) -> Self {
+        self.fallback(&[AddressSpace::Registered, AddressSpace::Cpu])
+    }
+
+    pub fn shrink_to(mut self, min_size: usize) -> Self {
+        self.min_size = Some(min_size);
+        self
+    }
+
+    /// The spaces to try for `requested`, in order. Host fallbacks are only
+    /// used for host requests and vice versa.
+    fn spaces(&self, requested: &AddressSpace) -> Vec<AddressSpace> {
+        let mut spaces = vec![requested.clone()];
+        for space in &self.fallback {
+            if space.is_host() == requested.is_host() && !spaces.contains(space) {
+                spaces.push(space.clone());
+            }
+        }
+        spaces
+    }
+}
+
+/// A failed attempt.
+#[derive(Debug, Clone)]
+pub struct AllocFailure {
+    pub address_space: AddressSpace,
+    pub size: usize,
+    pub error: DriverError,
+}
+
+/// A buffer from `Stream::allocate`, with what was asked for and what failed
+/// on the way.
+#[derive(Debug, Clone)]
+pub struct Allocation {
+    pub buffer: Buffer,
+    pub requested_space: AddressSpace,
+    pub requested_size: usize,
+    pub failures: Vec<AllocFailure>,
+}
+
+impl Allocation {
+    pub fn fell_back(&self) -> bool {
+        self.buffer.address_space != self.requested_space
+    }
+
+    pub fn shrunk(&self) -> bool {
+        self.buffer.size < self.requested_size
+    }
+
+    pub fn is_degraded(&self) -> bool {
+        self.fell_back() || self.shrunk()
+    }
+}
+
+impl std::fmt::Display for Allocation {
+    /// e.g. `8.00 GiB Pinned -> 4.00 GiB Registered after 3 failed attempts`.
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {:?}",
+            ByteSize::from(self.requested_size),
+            self.requested_space
+        )?;
+        if self.is_degraded() {
+            write!(
+                f,
+                " -> {} {:?}",
+                ByteSize::from(self.buffer.size),
+                self.buffer.address_space
+            )?;
+        }
+        if !self.failures.is_empty() {
+            write!(f, " after {} failed attempts", self.failures.len())?;
+        }
+        Ok(())
+    }
+}
+
+/// Every attempt failed.
+#[derive(Debug, Clone)]
+pub struct AllocError {
+    pub requested_space: AddressSpace,
+    pub requested_size: usize,
+    pub failures: Vec<AllocFailure>,
+}
+
+impl std::fmt::Display for AllocError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "failed to allocate {} of {:?} memory",
+            ByteSize::from(self.requested_size),
+            self.requested_space
+        )?;
+        if let Some(last) = self.failures.last() {
+            write!(
+                f,
+                " ({} attempts, last {} {:?}: {})",
+                self.failures.len(),
+                ByteSize::from(last.size),
+                last.address_space,
+                last.error
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for AllocError {}
+
+/// The largest size below `size` (which is known not to fit) and at least
+/// `min_size` for which `fits` holds, to within `SHRINK_GRANULARITY`: halves
+/// until something fits, then bisects between that and the smallest failure.
+/// Candidates are multiples of `SHRINK_GRANULARITY` or `min_size` itself.
+fn largest_fitting(
+    size: usize,
+    min_size: usize,
+    mut fits: impl FnMut(usize) -> bool,
+) -> Option<usize> {
+    let mut largest = None;
+    let mut failed = size;
+    let mut candidate = failed;
+    while largest.is_none() && candidate > min_size {
+        candidate = (candidate / 2 / SHRINK_GRANULARITY * SHRINK_GRANULARITY).max(min_size);
+        if fits(candidate) {
+            largest = Some(candidate);
+        } else {
+            failed = candidate;
+        }
+    }
+    let mut largest = largest?;
+    while failed - largest > SHRINK_GRANULARITY {
+        let mid = (largest + (failed - largest) / 2) / SHRINK_GRANULARITY * SHRINK_GRANULARITY;
+        if mid <= largest {
+            break;
+        }
+        if fits(mid) {
+            largest = mid;
+        } else {
+            failed = mid;
+        }
+    }
+    Some(largest)
+}
+
+impl Stream {
+    /// Allocates `size` bytes in `address_space` according to `policy`: the
+    /// full size is tried in each space (retrying once after a successful
+    /// reclaim), then, with `min_size`, the largest size that fits, space by
+    /// space.
+    #[track_caller]
+    pub fn allocate(
+        &self,
+        size: usize,
+        address_space: AddressSpace,
+        options: &HostOptions,
+        policy: &AllocPolicy,
+    ) -> Result<Allocation, AllocError> {
+        let location = Location::caller();
+        let mut failures = Vec::new();
+        let attempt = |space: &AddressSpace, size: usize, failures: &mut Vec<AllocFailure>| {
+            let result = self.create_tracked(size, space.clone(), options, location);
+            if let Err(error) = &result {
+                failures.push(AllocFailure {
+                    address_space: space.clone(),
+                    size,
+                    error: *error,
+                });
+            }
+            result.ok()
+        };
+        let done = |buffer: Buffer, failures: Vec<AllocFailure>| Allocation {
+            buffer,
+            requested_space: address_space.clone(),
+            requested_size: size,
+            failures,
+        };
+
+        let spaces = policy.spaces(&address_space);
+        for space in &spaces {
+            if let Some(buffer) = attempt(space, size, &mut failures) {
+                return Ok(done(buffer, failures));
+            }
+            if let Some(reclaim) = &policy.reclaim
+                && reclaim(space, size)
+                && let Some(buffer) = attempt(space, size, &mut failures)
+            {
+                return Ok(done(buffer, failures));
+            }
+        }
+
+        if let Some(min_size) = policy
+            .min_size
+            .map(|min| min.max(1))
+            .filter(|&min| min < size)
+        {
+            // Probes skip prefaulting, fault injection and accounting; only
+            // the final allocation is a real one.
+            let probe_options = HostOptions {
+                prefault: Prefault::None,
+                ..*options
+            };
+            for space in &spaces {
+                // Probes are freed right away so they don't count against the
+                // next one.
+                let probe = |size: usize| match self.create_untracked(
+                    size,
+                    space.clone(),
+                    &probe_options,
+                ) {
+                    Ok(buffer) => {
+                        self.free_untracked(&buffer);
+                        true
+                    }
+                    Err(error) => {
+                        failures.push(AllocFailure {
+                            address_space: space.clone(),
+                            size,
+                            error,
+                        });
+                        false
+                    }
+                };
+                let Some(fits) = largest_fitting(size, min_size, probe) else {
+                    continue;
+                };
+                if let Some(buffer) = attempt(space, fits, &mut failures) {
+                    return Ok(done(buffer, failures));
+                }
+            }
+        }
+
+        Err(AllocError {
+            requested_space: address_space,
+            requested_size: size,
+            failures,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MIB: usize = 1024 * 1024;
+
+    /// `largest_fitting` with `limit` bytes available, and the probe sizes.
+    fn shrink(size: usize, min_size: usize, limit: usize) -> (Option<usize>, Vec<usize>) {
+        let mut probes = Vec::new();
+        let fits = largest_fitting(size, min_size, |s| {
+            probes.push(s);
+            s <= limit
+        });
+        (fits, probes)
+    }
+
+    #[test]
+    fn spaces_keep_the_request_first_and_stay_on_its_side() {
+        let policy = AllocPolicy::default().host_fallback();
+        assert_eq!(
+            policy.spaces(&AddressSpace::Pinned),
+            [
+                AddressSpace::Pinned,
+                AddressSpace::Registered,
+                AddressSpace::Cpu
+            ]
+        );
+        assert_eq!(
+            policy.spaces(&AddressSpace::Registered),
+            [AddressSpace::Registered, AddressSpace::Cpu]
+        );
+        assert_eq!(
+            policy.spaces(&AddressSpace::Cpu),
+            [AddressSpace::Cpu, AddressSpace::Registered]
+        );
+        assert_eq!(policy.spaces(&AddressSpace::Device), [AddressSpace::Device]);
+        let device = AllocPolicy::default().fallback(&[AddressSpace::Pinned, AddressSpace::Device]);
+        assert_eq!(device.spaces(&AddressSpace::Device), [AddressSpace::Device]);
+        assert_eq!(
+            AllocPolicy::default().spaces(&AddressSpace::Pinned).len(),
+            1
+        );
+    }
+
+    #[test]
+    fn shrinks_to_within_the_granularity() {
+        let limit = 700 * MIB + 12345;
+        let (fits, probes) = shrink(1024 * MIB, MIB, limit);
+        let fits = fits.unwrap();
+        assert!(fits <= limit && limit - fits < SHRINK_GRANULARITY, "{fits}");
+        assert_eq!(fits % SHRINK_GRANULARITY, 0);
+        assert!(
+            probes.iter().all(|p| p % SHRINK_GRANULARITY == 0),
+            "{probes:?}"
+        );
+        assert_eq!(probes[0], 512 * MIB);
+        assert!(probes.len() < 12, "{probes:?}");
+    }
+
+    #[test]
+    fn shrinking_stops_at_min_size() {
+        // Only the minimum fits; it isn't a multiple of the granularity.
+        let (fits, probes) = shrink(64 * MIB, 3 * MIB + 1, 3 * MIB + 1);
+        assert_eq!(fits, Some(3 * MIB + 1));
+        assert!(probes.iter().all(|&p| p > 3 * MIB), "{probes:?}");
+        // Not even the minimum fits.
+        assert_eq!(shrink(64 * MIB, 8 * MIB, 4 * MIB).0, None);
+        // Small requests halve straight to the minimum.
+        assert_eq!(shrink(3 * MIB, 1, MIB).0, Some(1));
+    }
+
+    #[test]
+    fn shrinking_never_probes_the_failed_size() {
+        let (fits, probes) = shrink(10 * MIB, MIB, usize::MAX);
+        assert_eq!(fits, Some(8 * MIB));
+        assert!(probes.iter().all(|&p| p < 10 * MIB), "{probes:?}");
+    }
+}
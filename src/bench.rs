@@ -0,0 +1,894 @@
+//! Benchmark scenarios and a runner that times them uniformly.
+
+use std::time::{Duration, Instant};
+
+use crate::affinity::Affinity;
+use crate::alloc::AllocPolicy;
+use crate::workers::DeviceWorkerPool;
+use crate::*;
+
+/// A benchmarked pattern of work. The runner calls `setup`, `run`, `sync` and
+/// `teardown` once per iteration, timing `run` (issuing the work) and `sync`
+/// (waiting for it) separately.
+pub trait Scenario {
+    fn name(&self) -> String;
+
+    /// Bytes moved by one iteration, used to compute bandwidth.
+    fn bytes(&self) -> ByteSize;
+
+    /// The copies one iteration performs, for reporting.
+    fn copies(&self) -> Vec<CopyInfo> {
+        Vec::new()
+    }
+
+    /// GPU-measured duration of each copy in the last iteration, in the order
+    /// of `copies`, if the scenario measures them. Called after `sync`.
+    fn copy_times(&self) -> Vec<Duration> {
+        Vec::new()
+    }
+
+    fn setup(&mut self) {}
+
+    fn run(&mut self);
+
+    fn sync(&mut self) {}
+
+    fn teardown(&mut self) {}
+}
+
+/// Source and destination of one copy, with driver device ordinals.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CopyInfo {
+    pub src: AddressSpace,
+    pub src_device: i32,
+    pub dst: AddressSpace,
+    pub dst_device: i32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Direction {
+    H2D,
+    D2H,
+    D2D,
+    H2H,
+}
+
+impl CopyInfo {
+    pub fn direction(&self) -> Direction {
+        match (self.src.is_host(), self.dst.is_host()) {
+            (true, false) => Direction::H2D,
+            (false, true) => Direction::D2H,
+            (false, false) => Direction::D2D,
+            (true, true) => Direction::H2H,
+        }
+    }
+}
+
+impl std::fmt::Display for CopyInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{:?}@{}->{:?}@{}",
+            self.src, self.src_device, self.dst, self.dst_device
+        )
+    }
+}
+
+impl std::str::FromStr for CopyInfo {
+    type Err = String;
+
+    /// Parses the `Display` form, e.g. `Pinned@0->Device@1`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let endpoint = |e: &str| -> Result<(AddressSpace, i32), String> {
+            let (space, device) = e
+                .split_once('@')
+                .ok_or_else(|| format!("expected SPACE@DEVICE, got {:?}", e))?;
+            let device = device
+                .parse()
+                .map_err(|_| format!("invalid device {:?}", device))?;
+            Ok((space.parse()?, device))
+        };
+        let (src, dst) = s
+            .split_once("->")
+            .ok_or_else(|| format!("expected SRC->DST, got {:?}", s))?;
+        let ((src, src_device), (dst, dst_device)) = (endpoint(src)?, endpoint(dst)?);
+        Ok(Self {
+            src,
+            src_device,
+            dst,
+            dst_device,
+        })
+    }
+}
+
+/// A scenario whose per-copy transfer size can be changed between runs.
+pub trait Resizable: Scenario {
+    fn set_size(&mut self, size: usize);
+}
+
+/// Sizes from `min` to `max` (inclusive), each `factor` times the previous.
+pub fn sweep_sizes(min: ByteSize, max: ByteSize, factor: f64) -> Vec<ByteSize> {
+    assert!(factor > 1.0 && min.bytes() > 0 && min <= max);
+    let mut sizes = Vec::new();
+    let mut size = min.bytes() as f64;
+    while size.round() as u64 <= max.bytes() {
+        let bytes = ByteSize(size.round() as u64);
+        if sizes.last() != Some(&bytes) {
+            sizes.push(bytes);
+        }
+        size *= factor;
+    }
+    if sizes.last() != Some(&max) {
+        sizes.push(max);
+    }
+    sizes
+}
+
+/// One size of a sweep.
+#[derive(Debug, Clone)]
+pub struct SweepPoint {
+    /// Per-copy transfer size.
+    pub size: ByteSize,
+    pub bandwidth: Bandwidth,
+    /// Median time for one iteration.
+    pub latency: Duration,
+}
+
+impl SweepPoint {
+    pub fn from_result(size: ByteSize, result: &BenchResult) -> Self {
+        Self {
+            size,
+            bandwidth: Bandwidth::from_transfer(result.bytes, result.median_total_time()),
+            latency: result.median_total_time(),
+        }
+    }
+}
+
+/// n½: the size at which bandwidth first reaches half of the peak over the
+/// sweep, interpolated on a log scale between neighbouring sizes. `None`
+/// without any bandwidth to take half of.
+pub fn half_bandwidth_point(points: &[SweepPoint]) -> Option<ByteSize> {
+    let peak = points.iter().map(|p| p.bandwidth.0).fold(0.0, f64::max);
+    if peak <= 0.0 {
+        return None;
+    }
+    let half = peak / 2.0;
+    let idx = points.iter().position(|p| p.bandwidth.0 >= half)?;
+    if idx == 0 {
+        return Some(points[0].size);
+    }
+    let (lo, hi) = (&points[idx - 1], &points[idx]);
+    let t = (half - lo.bandwidth.0) / (hi.bandwidth.0 - lo.bandwidth.0);
+    let (log_lo, log_hi) = ((lo.size.0 as f64).ln(), (hi.size.0 as f64).ln());
+    Some(ByteSize(
+        (log_lo + t * (log_hi - log_lo)).exp().round() as u64
+    ))
+}
+
+#[derive(Debug, Clone)]
+pub struct Sample {
+    pub iteration: usize,
+    pub copy_time: Duration,
+    pub sync_time: Duration,
+    pub total_time: Duration,
+    pub bandwidth: Bandwidth,
+    /// GPU-measured time of each copy (see `Scenario::copy_times`).
+    pub copy_times: Vec<Duration>,
+}
+
+#[derive(Debug, Clone)]
+pub struct BenchResult {
+    pub scenario: String,
+    pub copies: Vec<CopyInfo>,
+    pub bytes: ByteSize,
+    pub warmup: usize,
+    pub samples: Vec<Sample>,
+}
+
+impl BenchResult {
+    pub fn mean_bandwidth(&self) -> Bandwidth {
+        if self.samples.is_empty() {
+            return Bandwidth::default();
+        }
+        let total: Duration = self.samples.iter().map(|s| s.total_time).sum();
+        Bandwidth::from_transfer(self.bytes * self.samples.len() as u64, total)
+    }
+
+    pub fn best_bandwidth(&self) -> Bandwidth {
+        self.samples
+            .iter()
+            .map(|s| s.bandwidth)
+            .fold(Bandwidth::default(), |a, b| if b > a { b } else { a })
+    }
+
+    /// Bandwidth per copy direction in one sample, summing the rates of
+    /// concurrent copies. Empty unless the copies were GPU-timed.
+    pub fn sample_direction_bandwidths(&self, sample: &Sample) -> Vec<(Direction, Bandwidth)> {
+        if sample.copy_times.len() != self.copies.len() || self.copies.is_empty() {
+            return Vec::new();
+        }
+        let per_copy = ByteSize(self.bytes.bytes() / self.copies.len() as u64);
+        let mut directions = self
+            .copies
+            .iter()
+            .map(|c| c.direction())
+            .collect::<Vec<_>>();
+        directions.sort();
+        directions.dedup();
+        directions
+            .into_iter()
+            .map(|direction| {
+                let bandwidth = self
+                    .copies
+                    .iter()
+                    .zip(&sample.copy_times)
+                    .filter(|(c, _)| c.direction() == direction)
+                    .map(|(_, t)| Bandwidth::from_transfer(per_copy, *t))
+                    .sum();
+                (direction, bandwidth)
+            })
+            .collect()
+    }
+
+    /// `sample_direction_bandwidths` averaged over samples.
+    pub fn direction_bandwidths(&self) -> Vec<(Direction, Bandwidth)> {
+        let per_sample = self
+            .samples
+            .iter()
+            .map(|s| self.sample_direction_bandwidths(s))
+            .filter(|d| !d.is_empty())
+            .collect::<Vec<_>>();
+        let Some(first) = per_sample.first() else {
+            return Vec::new();
+        };
+        first
+            .iter()
+            .enumerate()
+            .map(|(idx, (direction, _))| {
+                let total: Bandwidth = per_sample.iter().map(|d| d[idx].1).sum();
+                (*direction, Bandwidth(total.0 / per_sample.len() as f64))
+            })
+            .collect()
+    }
+
+    pub fn median_total_time(&self) -> Duration {
+        let mut times = self
+            .samples
+            .iter()
+            .map(|s| s.total_time)
+            .collect::<Vec<_>>();
+        times.sort();
+        times.get(times.len() / 2).copied().unwrap_or_default()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Runner {
+    /// Iterations run and discarded before measuring.
+    pub warmup: usize,
+    pub iterations: usize,
+}
+
+impl Default for Runner {
+    fn default() -> Self {
+        Self {
+            warmup: 0,
+            iterations: 3,
+        }
+    }
+}
+
+impl Runner {
+    pub fn run(&self, scenario: &mut dyn Scenario) -> BenchResult {
+        assert!(
+            self.iterations > 0,
+            "a benchmark needs at least one iteration"
+        );
+        let name = scenario.name();
+        let bytes = scenario.bytes();
+        log!("Benchmarking {} ({})", name, bytes);
+
+        for _ in 0..self.warmup {
+            self.iteration(scenario);
+        }
+
+        let mut samples = Vec::with_capacity(self.iterations);
+        for iteration in 0..self.iterations {
+            let (copy_time, sync_time, copy_times) = self.iteration(scenario);
+            // Setup may have shrunk the scenario (see `AllocPolicy`).
+            let bytes = scenario.bytes();
+            let total_time = copy_time + sync_time;
+            let bandwidth = Bandwidth::from_transfer(bytes, total_time);
+            log!(
+                "--- Copy time: {:?}, Sync time: {:?}, Bandwidth: {}",
+                copy_time,
+                sync_time,
+                bandwidth
+            );
+            samples.push(Sample {
+                iteration,
+                copy_time,
+                sync_time,
+                total_time,
+                bandwidth,
+                copy_times,
+            });
+        }
+
+        let result = BenchResult {
+            scenario: name,
+            copies: scenario.copies(),
+            bytes: scenario.bytes(),
+            warmup: self.warmup,
+            samples,
+        };
+        for (direction, bandwidth) in result.direction_bandwidths() {
+            log!("--- {:?}: {}", direction, bandwidth);
+        }
+        result
+    }
+
+    /// Runs `scenario` at each of `sizes`, returning one result per size.
+    pub fn sweep<S: Resizable>(
+        &self,
+        scenario: &mut S,
+        sizes: &[ByteSize],
+    ) -> (Vec<BenchResult>, Vec<SweepPoint>) {
+        let mut results = Vec::with_capacity(sizes.len());
+        let mut points = Vec::with_capacity(sizes.len());
+        for &size in sizes {
+            scenario.set_size(size.as_usize());
+            let result = self.run(scenario);
+            points.push(SweepPoint::from_result(size, &result));
+            results.push(result);
+        }
+        for point in &points {
+            log!(
+                "--- {:>12}: {:>14}, latency {:?}",
+                point.size.to_string(),
+                point.bandwidth.to_string(),
+                point.latency
+            );
+        }
+        if let Some(n_half) = half_bandwidth_point(&points) {
+            log!("--- n½: {}", n_half);
+        }
+        (results, points)
+    }
+
+    fn iteration(&self, scenario: &mut dyn Scenario) -> (Duration, Duration, Vec<Duration>) {
+        scenario.setup();
+        let t0 = Instant::now();
+        scenario.run();
+        let t1 = Instant::now();
+        scenario.sync();
+        let t2 = Instant::now();
+        let copy_times = scenario.copy_times();
+        scenario.teardown();
+        (t1.duration_since(t0), t2.duration_since(t1), copy_times)
+    }
+}
+
+/// Matches `name` against a shell-style pattern with `*` and `?`.
+pub fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let name = name.chars().collect::<Vec<_>>();
+    let (mut p, mut n) = (0, 0);
+    let mut backtrack = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match backtrack {
+                Some((bp, bn)) => {
+                    p = bp + 1;
+                    n = bn + 1;
+                    backtrack = Some((bp, bn + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// One side of a copy: a buffer in `address_space` owned by `streams[device]`.
+/// Endpoints differing only in `slot` get separate buffers.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Endpoint {
+    pub address_space: AddressSpace,
+    pub device: usize,
+    pub slot: usize,
+}
+
+impl Endpoint {
+    pub fn slot(mut self, slot: usize) -> Self {
+        self.slot = slot;
+        self
+    }
+
+    pub fn device(device: usize) -> Self {
+        Self {
+            address_space: AddressSpace::Device,
+            device,
+            slot: 0,
+        }
+    }
+
+    pub fn pinned(device: usize) -> Self {
+        Self {
+            address_space: AddressSpace::Pinned,
+            device,
+            slot: 0,
+        }
+    }
+
+    pub fn registered(device: usize) -> Self {
+        Self {
+            address_space: AddressSpace::Registered,
+            device,
+            slot: 0,
+        }
+    }
+
+    pub fn cpu(device: usize) -> Self {
+        Self {
+            address_space: AddressSpace::Cpu,
+            device,
+            slot: 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CopyOp {
+    pub dst: Endpoint,
+    pub src: Endpoint,
+    /// Index into the scenario's streams to issue the copy on.
+    pub stream: usize,
+    /// Index of an earlier copy that must complete before this one starts.
+    pub after: Option<usize>,
+}
+
+/// A set of same-sized copies between freshly allocated buffers. Each distinct
+/// endpoint gets one buffer, so copies can share a source or destination.
+pub struct Transfer {
+    pub name: String,
+    pub streams: Vec<Stream>,
+    pub size: usize,
+    pub host_options: HostOptions,
+    pub copies: Vec<CopyOp>,
+    /// Issue each copy (and synchronize it) from its own thread.
+    pub threaded: bool,
+    /// CPUs the `threaded` workers are bound to, per device.
+    pub affinity: Affinity,
+    /// Threads for `threaded`, one per copy, started on first use.
+    workers: Option<DeviceWorkerPool>,
+    /// Allocate buffers of at least this size once and keep them across
+    /// iterations, copying through views of `size` bytes. Freed by `release`.
+    pub persistent: Option<usize>,
+    /// Record timing events around each copy to report per-copy GPU time.
+    pub timed: bool,
+    /// How buffers are allocated when memory runs short. A buffer that ends
+    /// up smaller shrinks the whole transfer.
+    pub alloc_policy: AllocPolicy,
+    /// Endpoints whose buffers fell back to another address space.
+    fallbacks: Vec<(Endpoint, AddressSpace)>,
+    buffers: Vec<(Endpoint, Buffer)>,
+    events: Vec<Option<Event>>,
+    timing_events: Vec<(Event, Event)>,
+}
+
+impl Transfer {
+    pub fn new(name: impl Into<String>, streams: &[Stream], size: usize) -> Self {
+        Self {
+            name: name.into(),
+            streams: streams.to_vec(),
+            size,
+            host_options: HostOptions::default(),
+            copies: Vec::new(),
+            threaded: false,
+            affinity: Affinity::default(),
+            workers: None,
+            persistent: None,
+            timed: false,
+            alloc_policy: AllocPolicy::default(),
+            fallbacks: Vec::new(),
+            buffers: Vec::new(),
+            events: Vec::new(),
+            timing_events: Vec::new(),
+        }
+    }
+
+    pub fn copy(self, dst: Endpoint, src: Endpoint, stream: usize) -> Self {
+        self.copy_after(dst, src, stream, None)
+    }
+
+    pub fn copy_after(
+        mut self,
+        dst: Endpoint,
+        src: Endpoint,
+        stream: usize,
+        after: Option<usize>,
+    ) -> Self {
+        assert!(after.is_none_or(|idx| idx < self.copies.len()));
+        self.copies.push(CopyOp {
+            dst,
+            src,
+            stream,
+            after,
+        });
+        self
+    }
+
+    pub fn host_options(mut self, host_options: HostOptions) -> Self {
+        self.host_options = host_options;
+        self
+    }
+
+    pub fn threaded(mut self) -> Self {
+        self.threaded = true;
+        self
+    }
+
+    pub fn affinity(mut self, affinity: Affinity) -> Self {
+        self.affinity = affinity;
+        self
+    }
+
+    pub fn timed(mut self) -> Self {
+        self.timed = true;
+        self
+    }
+
+    pub fn alloc_policy(mut self, alloc_policy: AllocPolicy) -> Self {
+        self.alloc_policy = alloc_policy;
+        self
+    }
+
+    /// Keeps `capacity`-byte buffers alive across iterations; see `persistent`.
+    pub fn persistent(mut self, capacity: usize) -> Self {
+        self.persistent = Some(capacity);
+        self
+    }
+
+    /// Frees buffers kept by a persistent transfer and destroys its events;
+    /// the next `setup` creates them again.
+    pub fn release(&mut self) {
+        for (endpoint, buf) in self.buffers.drain(..) {
+            self.streams[endpoint.device].free_buffer_sync(&buf);
+        }
+        for stream in &self.streams {
+            stream.synchronize();
+        }
+        for event in self.events.drain(..).flatten() {
+            event.destroy();
+        }
+        for (start, end) in self.timing_events.drain(..) {
+            start.destroy();
+            end.destroy();
+        }
+    }
+
+    fn buffer(&self, endpoint: &Endpoint) -> Buffer {
+        let buf = &self.buffers.iter().find(|(e, _)| e == endpoint).unwrap().1;
+        if buf.size == self.size {
+            buf.clone()
+        } else {
+            buf.view(0, self.size)
+        }
+    }
+
+    fn alloc(&mut self, endpoint: &Endpoint) {
+        if self.buffers.iter().any(|(e, _)| e == endpoint) {
+            return;
+        }
+        let stream = &self.streams[endpoint.device];
+        let allocation = stream
+            .allocate(
+                self.persistent.unwrap_or(0).max(self.size),
+                endpoint.address_space.clone(),
+                &self.host_options,
+                &self.alloc_policy,
+            )
+            .unwrap_or_else(|e| panic!("{}: {}", self.name, e));
+        if allocation.is_degraded() {
+            log_warn!("{}: allocated {}", self.name, allocation);
+        }
+        let fell_back = allocation.fell_back();
+        let buf = allocation.buffer;
+        buf.set_label(format!("{} slot {}", self.name, endpoint.slot));
+        if buf.size < self.size {
+            self.size = buf.size;
+        }
+        if fell_back && !self.fallbacks.iter().any(|(e, _)| e == endpoint) {
+            self.fallbacks
+                .push((endpoint.clone(), buf.address_space.clone()));
+        }
+        if buf.address_space == AddressSpace::Pinned {
+            host::prefault(buf.addr, buf.size, buf.pages, self.host_options.prefault);
+        }
+        self.buffers.push((endpoint.clone(), buf));
+    }
+}
+
+impl Scenario for Transfer {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn bytes(&self) -> ByteSize {
+        ByteSize::from(self.size) * self.copies.len() as u64
+    }
+
+    fn copies(&self) -> Vec<CopyInfo> {
+        let space = |endpoint: &Endpoint| {
+            self.fallbacks
+                .iter()
+                .find(|(e, _)| e == endpoint)
+                .map_or(&endpoint.address_space, |(_, space)| space)
+                .clone()
+        };
+        self.copies
+            .iter()
+            .map(|c| CopyInfo {
+                src: space(&c.src),
+                src_device: self.streams[c.src.device].ctx.device_id,
+                dst: space(&c.dst),
+                dst_device: self.streams[c.dst.device].ctx.device_id,
+            })
+            .collect()
+    }
+
+    fn copy_times(&self) -> Vec<Duration> {
+        self.timing_events
+            .iter()
+            .map(|(start, end)| end.elapsed_since(start))
+            .collect()
+    }
+
+    fn setup(&mut self) {
+        for copy in self.copies.clone() {
+            self.alloc(&copy.src);
+            self.alloc(&copy.dst);
+        }
+        if self.events.is_empty() {
+            self.events = (0..self.copies.len())
+                .map(|idx| {
+                    self.copies
+                        .iter()
+                        .any(|c| c.after == Some(idx))
+                        .then(|| self.streams[self.copies[idx].stream].ctx.create_event())
+                })
+                .collect();
+        }
+        if self.timed && self.timing_events.is_empty() {
+            self.timing_events = self
+                .copies
+                .iter()
+                .map(|c| {
+                    let ctx = &self.streams[c.stream].ctx;
+                    (ctx.create_timing_event(), ctx.create_timing_event())
+                })
+                .collect();
+        }
+        for stream in &self.streams {
+            stream.synchronize();
+        }
+    }
+
+    fn run(&mut self) {
+        if self.threaded {
+            assert!(self.copies.iter().all(|c| c.after.is_none()));
+            let jobs = self
+                .copies
+                .iter()
+                .enumerate()
+                .map(|(idx, copy)| {
+                    (
+                        self.streams[copy.stream].clone(),
+                        self.buffer(&copy.src),
+                        self.buffer(&copy.dst),
+                        self.timing_events.get(idx).cloned(),
+                    )
+                })
+                .collect::<Vec<_>>();
+            let workers = self.workers.get_or_insert_with(|| {
+                let ctxs = self
+                    .copies
+                    .iter()
+                    .map(|c| self.streams[c.stream].ctx.clone())
+                    .collect::<Vec<_>>();
+                DeviceWorkerPool::with_affinity(&ctxs, &self.affinity)
+            });
+            let results = workers.run_all(move |idx, _| {
+                let (stream, src, dst, timing) = &jobs[idx];
+                if let Some((start, _)) = timing {
+                    stream.record_event(start);
+                }
+                stream.memcpy_async(dst, src);
+                if let Some((_, end)) = timing {
+                    stream.record_event(end);
+                }
+                stream.synchronize();
+            });
+            for result in results {
+                if let Err(panic) = result {
+                    std::panic::resume_unwind(panic);
+                }
+            }
+            return;
+        }
+
+        for (idx, copy) in self.copies.iter().enumerate() {
+            let stream = &self.streams[copy.stream];
+            if let Some(after) = copy.after {
+                stream.wait_for_event(self.events[after].as_ref().unwrap());
+            }
+            if let Some((start, _)) = self.timing_events.get(idx) {
+                stream.record_event(start);
+            }
+            stream.memcpy_async(&self.buffer(&copy.dst), &self.buffer(&copy.src));
+            if let Some((_, end)) = self.timing_events.get(idx) {
+                stream.record_event(end);
+            }
+            if let Some(event) = &self.events[idx] {
+                stream.record_event(event);
+            }
+        }
+    }
+
+    fn sync(&mut self) {
+        for stream in &self.streams {
+            stream.synchronize();
+        }
+    }
+
+    fn teardown(&mut self) {
+        if self.persistent.is_none() {
+            self.release();
+        }
+    }
+}
+
+impl Resizable for Transfer {
+    fn set_size(&mut self, size: usize) {
+        if self.persistent.is_some_and(|capacity| size > capacity) {
+            self.release();
+            self.persistent = Some(size);
+        } else if self.buffers.iter().any(|(_, buf)| buf.size < size) {
+            // Shrunk by the allocation policy; try the full size again.
+            self.release();
+        }
+        self.size = size;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn point(size: ByteSize, gb_s: f64) -> SweepPoint {
+        SweepPoint {
+            size,
+            bandwidth: Bandwidth(gb_s * 1e9),
+            latency: Duration::ZERO,
+        }
+    }
+
+    #[test]
+    fn sweep_endpoints() {
+        assert_eq!(
+            sweep_sizes(ByteSize::kib(1), ByteSize::kib(8), 2.0),
+            [1, 2, 4, 8].map(ByteSize::kib)
+        );
+        assert_eq!(
+            sweep_sizes(ByteSize::kib(4), ByteSize::kib(4), 2.0),
+            [ByteSize::kib(4)]
+        );
+    }
+
+    #[test]
+    fn sweep_non_power_of_two_bounds() {
+        assert_eq!(
+            sweep_sizes(ByteSize(3), ByteSize(100), 2.0),
+            [3, 6, 12, 24, 48, 96, 100].map(ByteSize)
+        );
+        assert_eq!(
+            sweep_sizes(ByteSize(1000), ByteSize(5000), 4.0),
+            [1000, 4000, 5000].map(ByteSize)
+        );
+        // Small factors don't repeat sizes that round the same.
+        assert_eq!(
+            sweep_sizes(ByteSize(1), ByteSize(4), 1.1),
+            [1, 2, 3, 4].map(ByteSize)
+        );
+    }
+
+    #[test]
+    #[should_panic]
+    fn sweep_rejects_reversed_bounds() {
+        sweep_sizes(ByteSize::kib(8), ByteSize::kib(1), 2.0);
+    }
+
+    #[test]
+    fn half_bandwidth_at_first_point() {
+        let points = [point(ByteSize::kib(1), 6.0), point(ByteSize::kib(2), 10.0)];
+        assert_eq!(half_bandwidth_point(&points), Some(ByteSize::kib(1)));
+    }
+
+    #[test]
+    fn half_bandwidth_never_reached() {
+        assert_eq!(half_bandwidth_point(&[]), None);
+        let flat = [point(ByteSize::kib(1), 0.0), point(ByteSize::kib(2), 0.0)];
+        assert_eq!(half_bandwidth_point(&flat), None);
+    }
+
+    #[test]
+    fn half_bandwidth_interpolates_on_log_scale() {
+        // Half of 8 GB/s lies halfway between 2 and 6 GB/s, so halfway
+        // between 1 KiB and 4 KiB on a log scale.
+        let points = [
+            point(ByteSize::kib(1), 2.0),
+            point(ByteSize::kib(4), 6.0),
+            point(ByteSize::kib(16), 8.0),
+        ];
+        assert_eq!(half_bandwidth_point(&points), Some(ByteSize::kib(2)));
+
+        // Exactly reaching half picks that point.
+        let points = [
+            point(ByteSize::kib(1), 1.0),
+            point(ByteSize::kib(4), 4.0),
+            point(ByteSize::kib(16), 8.0),
+        ];
+        assert_eq!(half_bandwidth_point(&points), Some(ByteSize::kib(4)));
+    }
+
+    #[test]
+    fn half_bandwidth_uses_first_crossing() {
+        // A dip after crossing half doesn't move the point.
+        let points = [
+            point(ByteSize::kib(1), 1.0),
+            point(ByteSize::kib(2), 5.0),
+            point(ByteSize::kib(4), 2.0),
+            point(ByteSize::kib(8), 8.0),
+        ];
+        let n_half = half_bandwidth_point(&points).unwrap();
+        assert!(n_half > ByteSize::kib(1) && n_half < ByteSize::kib(2));
+    }
+
+    #[test]
+    fn glob_matches_literals_and_question_marks() {
+        assert!(glob_match("h2d", "h2d"));
+        assert!(!glob_match("h2d", "h2d-pinned"));
+        assert!(glob_match("h?d", "h2d"));
+        assert!(!glob_match("h?d", "hd"));
+        assert!(!glob_match("h?d", "h22d"));
+    }
+
+    #[test]
+    fn glob_star_matches_any_run() {
+        assert!(glob_match("*", ""));
+        assert!(glob_match("*", "anything"));
+        assert!(glob_match("h2d-*", "h2d-"));
+        assert!(glob_match("h2d-*", "h2d-pinned"));
+        assert!(glob_match("h2d-**", "h2d-pinned"));
+        assert!(!glob_match("h2d-*", "d2h-pinned"));
+        assert!(glob_match("*-pinned", "d2h-pinned"));
+        assert!(glob_match("*2*-*d", "h2d-pinned"));
+        assert!(!glob_match("*2*-*x", "h2d-pinned"));
+    }
+
+    #[test]
+    fn glob_empty_pattern_matches_only_empty_names() {
+        assert!(glob_match("", ""));
+        assert!(!glob_match("", "h2d"));
+        assert!(!glob_match("?", ""));
+    }
+}
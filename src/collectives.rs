@@ -0,0 +1,567 @@
+//! Multi-GPU collectives built from `memcpy_async` and events only, so they
+//! run on the copy engines and need no kernels.
+//!
+//! Every function issues its copies asynchronously and returns. A rank's
+//! copies are issued on its own stream, so synchronizing the ranks' streams
+//! waits for the collective; `gather` additionally makes the root stream wait.
+
+use std::collections::HashMap;
+
+use crate::bench::{CopyInfo, Scenario};
+use crate::*;
+
+/// A participant: a stream and the buffer it sends from or receives into.
+#[derive(Debug, Clone)]
+pub struct Rank {
+    pub stream: Stream,
+    pub buffer: Buffer,
+}
+
+/// Events reused across calls, keyed by context. Reusing an event is safe once
+/// every `wait_for_event` on it has been issued, which holds between calls.
+/// The events are destroyed with the pool.
+#[derive(Debug, Default)]
+pub struct EventPool {
+    events: HashMap<usize, (Vec<Event>, usize)>,
+}
+
+impl EventPool {
+    /// Records a fresh event on `stream`.
+    pub fn record(&mut self, stream: &Stream) -> Event {
+        let (events, next) = self.events.entry(stream.ctx.ctx as usize).or_default();
+        if *next == events.len() {
+            events.push(stream.ctx.create_event());
+        }
+        let event = events[*next].clone();
+        *next += 1;
+        stream.record_event(&event);
+        event
+    }
+
+    /// Makes every event available again; called at the start of each collective.
+    pub fn reset(&mut self) {
+        for (_, next) in self.events.values_mut() {
+            *next = 0;
+        }
+    }
+}
+
+impl Drop for EventPool {
+    fn drop(&mut self) {
+        for (events, _) in self.events.drain().map(|(_, pool)| pool) {
+            for event in events {
+                event.destroy();
+            }
+        }
+    }
+}
+
+/// How `broadcast` forwards data from the root to the ranks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Broadcast {
+    /// Every rank copies from the root.
+    Flat,
+    /// Rank `i` copies from rank `i - 1`, rank 0 from the root.
+    Chain,
+    /// Binary tree: rank `i` copies from rank `(i - 1) / 2`, rank 0 from the root.
+    Tree,
+}
+
+impl Broadcast {
+    /// The rank that `rank` receives from, or `None` for the root.
+    fn parent(self, rank: usize) -> Option<usize> {
+        match (self, rank) {
+            (_, 0) | (Broadcast::Flat, _) => None,
+            (Broadcast::Chain, i) => Some(i - 1),
+            (Broadcast::Tree, i) => Some((i - 1) / 2),
+        }
+    }
+}
+
+impl std::fmt::Display for Broadcast {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let name = match self {
+            Broadcast::Flat => "flat",
+            Broadcast::Chain => "chain",
+            Broadcast::Tree => "tree",
+        };
+        f.write_str(name)
+    }
+}
+
+impl std::str::FromStr for Broadcast {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "flat" => Ok(Broadcast::Flat),
+            "chain" => Ok(Broadcast::Chain),
+            "tree" => Ok(Broadcast::Tree),
+            _ => Err(format!(
+                "unknown broadcast {:?} (expected flat, chain or tree)",
+                s
+            )),
+        }
+    }
+}
+
+/// Byte range of chunk `idx` when `size` is split into `chunks` pieces.
+fn chunk(size: usize, chunks: usize, idx: usize) -> (usize, usize) {
+    let start = size * idx / chunks;
+    (start, size * (idx + 1) / chunks - start)
+}
+
+/// Copies the root's buffer into every rank's buffer. With `chunks > 1` each
+/// chunk is forwarded as soon as it arrives, pipelining chains and trees.
+pub fn broadcast(
+    root: &Rank,
+    ranks: &[Rank],
+    algorithm: Broadcast,
+    chunks: usize,
+    events: &mut EventPool,
+) {
+    assert!(chunks > 0 && chunks <= root.buffer.size);
+    assert!(ranks.iter().all(|r| r.buffer.size == root.buffer.size));
+    events.reset();
+    let size = root.buffer.size;
+    let ready = events.record(&root.stream);
+    // arrived[i][c]: chunk c has landed in rank i
+    let mut arrived: Vec<Vec<Event>> = Vec::with_capacity(ranks.len());
+    for (i, rank) in ranks.iter().enumerate() {
+        let parent = algorithm.parent(i);
+        let src = parent.map_or(root, |p| &ranks[p]);
+        let upstream = parent.map(|p| &arrived[p]);
+        let mut done = Vec::with_capacity(chunks);
+        for c in 0..chunks {
+            let (offset, len) = chunk(size, chunks, c);
+            match upstream {
+                Some(upstream) => rank.stream.wait_for_event(&upstream[c]),
+                None if c == 0 => rank.stream.wait_for_event(&ready),
+                None => {}
+            }
+            rank.stream.memcpy_async(
+                &rank.buffer.view(offset, len),
+                &src.buffer.view(offset, len),
+            );
+            done.push(events.record(&rank.stream));
+        }
+        arrived.push(done);
+    }
+}
+
+/// Copies slice `i` of the root's buffer into rank `i`.
+pub fn scatter(root: &Rank, ranks: &[Rank], events: &mut EventPool) {
+    let size = ranks.first().map_or(0, |r| r.buffer.size);
+    assert_eq!(root.buffer.size, size * ranks.len());
+    events.reset();
+    let ready = events.record(&root.stream);
+    for (i, rank) in ranks.iter().enumerate() {
+        rank.stream.wait_for_event(&ready);
+        rank.stream
+            .memcpy_async(&rank.buffer, &root.buffer.view(i * size, size));
+    }
+}
+
+/// Copies rank `i`'s buffer into slice `i` of the root's buffer.
+pub fn gather(root: &Rank, ranks: &[Rank], events: &mut EventPool) {
+    let size = ranks.first().map_or(0, |r| r.buffer.size);
+    assert_eq!(root.buffer.size, size * ranks.len());
+    events.reset();
+    let ready = events.record(&root.stream);
+    for (i, rank) in ranks.iter().enumerate() {
+        rank.stream.wait_for_event(&ready);
+        rank.stream
+            .memcpy_async(&root.buffer.view(i * size, size), &rank.buffer);
+        root.stream.wait_for_event(&events.record(&rank.stream));
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AllGather {
+    /// Every rank copies every other rank's slice directly.
+    Direct,
+    /// `n - 1` steps; in each, rank `i` copies from rank `i - 1` the slice it
+    /// received in the previous step.
+    Ring,
+}
+
+impl std::fmt::Display for AllGather {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(match self {
+            AllGather::Direct => "direct",
+            AllGather::Ring => "ring",
+        })
+    }
+}
+
+/// In step `step` of a ring all-gather over `n` ranks, the rank `rank` copies
+/// from and the slice it copies.
+fn ring_step(rank: usize, n: usize, step: usize) -> (usize, usize) {
+    ((rank + n - 1) % n, (rank + n - 1 - step) % n)
+}
+
+/// Each rank's buffer holds `n` slices and starts with its own data in slice
+/// `i`; afterwards every rank holds all slices.
+pub fn all_gather(ranks: &[Rank], algorithm: AllGather, events: &mut EventPool) {
+    let n = ranks.len();
+    let total = ranks.first().map_or(0, |r| r.buffer.size);
+    assert!(ranks.iter().all(|r| r.buffer.size == total) && total.is_multiple_of(n.max(1)));
+    let size = total / n.max(1);
+    let slice = |rank: &Rank, idx: usize| rank.buffer.view(idx * size, size);
+    events.reset();
+    let mut ready = ranks
+        .iter()
+        .map(|r| events.record(&r.stream))
+        .collect::<Vec<_>>();
+    match algorithm {
+        AllGather::Direct => {
+            for (i, rank) in ranks.iter().enumerate() {
+                for (j, peer) in ranks.iter().enumerate().filter(|&(j, _)| j != i) {
+                    rank.stream.wait_for_event(&ready[j]);
+                    rank.stream.memcpy_async(&slice(rank, j), &slice(peer, j));
+                }
+            }
+        }
+        AllGather::Ring => {
+            for step in 0..n.saturating_sub(1) {
+                let arrived = ranks
+                    .iter()
+                    .enumerate()
+                    .map(|(i, rank)| {
+                        let (prev, idx) = ring_step(i, n, step);
+                        rank.stream.wait_for_event(&ready[prev]);
+                        rank.stream
+                            .memcpy_async(&slice(rank, idx), &slice(&ranks[prev], idx));
+                        events.record(&rank.stream)
+                    })
+                    .collect();
+                ready = arrived;
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Broadcast(Broadcast),
+    Scatter,
+    Gather,
+    AllGather(AllGather),
+}
+
+impl std::fmt::Display for Op {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Op::Broadcast(b) => write!(f, "broadcast-{}", b),
+            Op::Scatter => f.write_str("scatter"),
+            Op::Gather => f.write_str("gather"),
+            Op::AllGather(a) => write!(f, "all-gather-{}", a),
+        }
+    }
+}
+
+/// Benchmarks one collective with `size` bytes per rank. The root buffer is
+/// allocated in `root_space` on `root`'s context; rank `i` gets device memory
+/// on `streams[i]`.
+pub struct Collective {
+    pub op: Op,
+    pub root: Stream,
+    pub root_space: AddressSpace,
+    pub streams: Vec<Stream>,
+    pub size: usize,
+    /// Pipeline chunks for `Broadcast`.
+    pub chunks: usize,
+    root_rank: Option<Rank>,
+    ranks: Vec<Rank>,
+    events: EventPool,
+}
+
+impl Collective {
+    pub fn new(op: Op, root: &Stream, streams: &[Stream], size: usize) -> Self {
+        Self {
+            op,
+            root: root.clone(),
+            root_space: AddressSpace::Pinned,
+            streams: streams.to_vec(),
+            size,
+            chunks: 1,
+            root_rank: None,
+            ranks: Vec::new(),
+            events: EventPool::default(),
+        }
+    }
+
+    pub fn root_space(mut self, root_space: AddressSpace) -> Self {
+        self.root_space = root_space;
+        self
+    }
+
+    pub fn chunks(mut self, chunks: usize) -> Self {
+        self.chunks = chunks;
+        self
+    }
+
+    /// Number of `size`-byte copies one iteration performs.
+    fn n_copies(&self) -> usize {
+        let n = self.streams.len();
+        match self.op {
+            Op::Broadcast(_) | Op::Scatter | Op::Gather => n,
+            Op::AllGather(_) => n * n.saturating_sub(1),
+        }
+    }
+}
+
+impl Scenario for Collective {
+    fn name(&self) -> String {
+        match self.op {
+            Op::Broadcast(_) if self.chunks > 1 => {
+                format!("{} x{} chunks", self.op, self.chunks)
+            }
+            _ => self.op.to_string(),
+        }
+    }
+
+    fn bytes(&self) -> ByteSize {
+        ByteSize::from(self.size) * self.n_copies() as u64
+    }
+
+    fn copies(&self) -> Vec<CopyInfo> {
+        let root = self.root.ctx.device_id;
+        let n = self.streams.len();
+        let device = |s: &Stream| s.ctx.device_id;
+        let copy = |src, src_device, dst, dst_device| CopyInfo {
+            src,
+            src_device,
+            dst,
+            dst_device,
+        };
+        match self.op {
+            Op::Broadcast(b) => (0..n)
+                .map(|i| match b.parent(i) {
+                    Some(p) => copy(
+                        AddressSpace::Device,
+                        device(&self.streams[p]),
+                        AddressSpace::Device,
+                        device(&self.streams[i]),
+                    ),
+                    None => copy(
+                        self.root_space.clone(),
+                        root,
+                        AddressSpace::Device,
+                        device(&self.streams[i]),
+                    ),
+                })
+                .collect(),
+            Op::Scatter => self
+                .streams
+                .iter()
+                .map(|s| {
+                    copy(
+                        self.root_space.clone(),
+                        root,
+                        AddressSpace::Device,
+                        device(s),
+                    )
+                })
+                .collect(),
+            Op::Gather => self
+                .streams
+                .iter()
+                .map(|s| {
+                    copy(
+                        AddressSpace::Device,
+                        device(s),
+                        self.root_space.clone(),
+                        root,
+                    )
+                })
+                .collect(),
+            Op::AllGather(_) => self
+                .streams
+                .iter()
+                .flat_map(|dst| {
+                    self.streams
+                        .iter()
+                        .filter(move |src| !std::ptr::eq(*src, dst))
+                        .map(move |src| {
+                            copy(
+                                AddressSpace::Device,
+                                device(src),
+                                AddressSpace::Device,
+                                device(dst),
+                            )
+                        })
+                })
+                .collect(),
+        }
+    }
+
+    fn setup(&mut self) {
+        let n = self.streams.len();
+        let (root_size, rank_size) = match self.op {
+            Op::Broadcast(_) => (self.size, self.size),
+            Op::Scatter | Op::Gather => (self.size * n, self.size),
+            Op::AllGather(_) => (0, self.size * n),
+        };
+        if root_size > 0 {
+            self.root_rank = Some(Rank {
+                stream: self.root.clone(),
+                buffer: self
+                    .root
+                    .create_buffer_async(root_size, self.root_space.clone()),
+            });
+        }
+        self.ranks = self
+            .streams
+            .iter()
+            .map(|s| Rank {
+                stream: s.clone(),
+                buffer: s.create_buffer_async(rank_size, AddressSpace::Device),
+            })
+            .collect();
+        self.root.synchronize();
+        for stream in &self.streams {
+            stream.synchronize();
+        }
+    }
+
+    fn run(&mut self) {
+        let ranks = &self.ranks;
+        let events = &mut self.events;
+        match (self.op, &self.root_rank) {
+            (Op::Broadcast(b), Some(root)) => broadcast(root, ranks, b, self.chunks, events),
+            (Op::Scatter, Some(root)) => scatter(root, ranks, events),
+            (Op::Gather, Some(root)) => gather(root, ranks, events),
+            (Op::AllGather(a), _) => all_gather(ranks, a, events),
+            _ => unreachable!(),
+        }
+    }
+
+    fn sync(&mut self) {
+        self.root.synchronize();
+        for stream in &self.streams {
+            stream.synchronize();
+        }
+    }
+
+    fn teardown(&mut self) {
+        if let Some(root) = self.root_rank.take() {
+            root.stream.free_buffer_sync(&root.buffer);
+        }
+        for rank in self.ranks.drain(..) {
+            rank.stream.free_buffer_sync(&rank.buffer);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stream(device_id: i32) -> Stream {
+        Stream {
+            ctx: Context {
+                ctx: std::ptr::null_mut(),
+                device_id,
+            },
+            stream: std::ptr::null_mut(),
+        }
+    }
+
+    fn collective(op: Op, n: i32) -> Collective {
+        let streams = (0..n).map(stream).collect::<Vec<_>>();
+        Collective::new(op, &stream(0), &streams, 1 << 20)
+    }
+
+    #[test]
+    fn chunks_cover_the_buffer() {
+        for (size, chunks) in [(10, 1), (10, 3), (7, 7), (1 << 20, 6)] {
+            let mut next = 0;
+            for idx in 0..chunks {
+                let (offset, len) = chunk(size, chunks, idx);
+                assert_eq!(offset, next, "size {size}, chunk {idx}");
+                assert!(len > 0);
+                next = offset + len;
+            }
+            assert_eq!(next, size);
+        }
+        assert_eq!(chunk(10, 3, 0), (0, 3));
+        assert_eq!(chunk(10, 3, 2), (6, 4));
+    }
+
+    #[test]
+    fn broadcast_parents() {
+        let parents = |b: Broadcast| (0..7).map(|i| b.parent(i)).collect::<Vec<_>>();
+        assert_eq!(parents(Broadcast::Flat), [None; 7]);
+        assert_eq!(
+            parents(Broadcast::Chain),
+            [None, Some(0), Some(1), Some(2), Some(3), Some(4), Some(5)]
+        );
+        assert_eq!(
+            parents(Broadcast::Tree),
+            [None, Some(0), Some(0), Some(1), Some(1), Some(2), Some(2)]
+        );
+    }
+
+    #[test]
+    fn ring_all_gather_forwards_what_arrived() {
+        for n in 1..=6 {
+            // held[i][j]: rank i has slice j
+            let mut held = (0..n)
+                .map(|i| (0..n).map(|j| i == j).collect::<Vec<_>>())
+                .collect::<Vec<_>>();
+            for step in 0..n - 1 {
+                let before = held.clone();
+                for (i, slices) in held.iter_mut().enumerate() {
+                    let (prev, idx) = ring_step(i, n, step);
+                    assert!(before[prev][idx], "n {n}, step {step}, rank {i}");
+                    assert!(!slices[idx], "n {n}, step {step}, rank {i}");
+                    slices[idx] = true;
+                }
+            }
+            assert!(held.iter().flatten().all(|&h| h), "n {n}");
+        }
+    }
+
+    #[test]
+    fn broadcast_names_round_trip() {
+        for b in [Broadcast::Flat, Broadcast::Chain, Broadcast::Tree] {
+            assert_eq!(b.to_string().parse(), Ok(b));
+        }
+        assert!("ring".parse::<Broadcast>().is_err());
+    }
+
+    #[test]
+    fn copies_match_the_byte_count() {
+        for op in [
+            Op::Broadcast(Broadcast::Tree),
+            Op::Scatter,
+            Op::Gather,
+            Op::AllGather(AllGather::Ring),
+        ] {
+            let c = collective(op, 4);
+            assert_eq!(c.copies().len(), c.n_copies(), "{op}");
+            assert_eq!(c.bytes(), ByteSize::from(c.size) * c.n_copies() as u64);
+        }
+        assert_eq!(
+            collective(Op::AllGather(AllGather::Direct), 4).n_copies(),
+            12
+        );
+        assert_eq!(
+            collective(Op::AllGather(AllGather::Direct), 1).n_copies(),
+            0
+        );
+    }
+
+    #[test]
+    fn tree_broadcast_copies_between_parents_and_children() {
+        let copies = collective(Op::Broadcast(Broadcast::Tree), 3).copies();
+        let devices = copies
+            .iter()
+            .map(|c| (c.src_device, c.dst_device))
+            .collect::<Vec<_>>();
+        assert_eq!(devices, [(0, 0), (0, 1), (0, 2)]);
+        assert_eq!(copies[0].src, AddressSpace::Pinned);
+        assert_eq!(copies[1].src, AddressSpace::Device);
+    }
+}
@@ -0,0 +1,459 @@
+//! Selecting devices by ordinal, UUID, PCI bus id or MIG instance, the way
+//! `CUDA_VISIBLE_DEVICES` does, and mapping them to driver ordinals.
+//!
+//! A `DeviceSelector` is parsed without touching the driver, so it can be
+//! validated (and exported as `CUDA_VISIBLE_DEVICES`) before `INIT` runs.
+
+use std::sync::atomic::Ordering;
+
+use crate::topology::{is_bus_id, normalize_bus_id};
+use crate::*;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeviceId {
+    /// Position in the enumeration order (see `visible_devices`).
+    Ordinal(usize),
+    /// `GPU-` UUID or a unique prefix of one, in `device_uuid`'s form: an
+    /// uppercase `GPU-` and lowercase hex.
+    Uuid(String),
+    /// Normalized PCI bus id, e.g. `0000:17:00.0`.
+    PciBusId(String),
+    /// `MIG-<uuid>` or `MIG-GPU-<uuid>/<gi>/<ci>`. Only usable through
+    /// `CUDA_VISIBLE_DEVICES`, where the instance appears as its own device.
+    Mig(String),
+}
+
+impl std::fmt::Display for DeviceId {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            DeviceId::Ordinal(o) => write!(f, "{}", o),
+            DeviceId::Uuid(u) | DeviceId::PciBusId(u) | DeviceId::Mig(u) => f.write_str(u),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SelectError {
+    Parse(String),
+    /// An ordinal past the number of visible devices.
+    OutOfRange {
+        ordinal: usize,
+        count: usize,
+    },
+    NotFound(DeviceId),
+    /// A UUID prefix matching several devices.
+    Ambiguous(DeviceId, Vec<String>),
+    /// The selection needs `CUDA_VISIBLE_DEVICES`, but the driver is already
+    /// initialized.
+    AlreadyInitialized,
+}
+
+impl std::fmt::Display for SelectError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            SelectError::Parse(msg) => f.write_str(msg),
+            SelectError::OutOfRange { ordinal, count } => write!(
+                f,
+                "device {} requested but only {} GPU(s) present",
+                ordinal, count
+            ),
+            SelectError::NotFound(id) => write!(f, "no device matches {}", id),
+            SelectError::Ambiguous(id, matches) => {
+                write!(f, "{} matches several devices: {}", id, matches.join(", "))
+            }
+            SelectError::AlreadyInitialized => f.write_str(
+                "CUDA is already initialized; device visibility must be set before first use",
+            ),
+        }
+    }
+}
+
+impl std::error::Error for SelectError {}
+
+impl std::str::FromStr for DeviceId {
+    type Err = SelectError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let lower = s.to_ascii_lowercase();
+        if let Ok(ordinal) = s.parse() {
+            Ok(DeviceId::Ordinal(ordinal))
+        } else if lower.starts_with("mig-") {
+            if s.len() == 4 {
+                return Err(SelectError::Parse(format!("invalid MIG id {:?}", s)));
+            }
+            // MIG identifiers are case-sensitive in CUDA_VISIBLE_DEVICES.
+            Ok(DeviceId::Mig(format!("MIG-{}", &s[4..])))
+        } else if let Some(uuid) = lower.strip_prefix("gpu-") {
+            if uuid.is_empty() || !uuid.chars().all(|c| c.is_ascii_hexdigit() || c == '-') {
+                return Err(SelectError::Parse(format!("invalid GPU UUID {:?}", s)));
+            }
+            Ok(DeviceId::Uuid(format!("GPU-{}", uuid)))
+        } else if lower.matches(':').count() >= 1 && lower.contains('.') {
+            // With or without the domain: `17:00.0` means `0000:17:00.0`.
+            let full = if lower.matches(':').count() == 1 {
+                format!("0000:{}", lower)
+            } else {
+                lower
+            };
+            let bus_id = normalize_bus_id(&full);
+            if !is_bus_id(&bus_id) {
+                return Err(SelectError::Parse(format!("invalid PCI bus id {:?}", s)));
+            }
+            Ok(DeviceId::PciBusId(bus_id))
+        } else {
+            Err(SelectError::Parse(format!(
+                "expected an ordinal, GPU-<uuid>, MIG-<id> or PCI bus id, got {:?}",
+                s
+            )))
+        }
+    }
+}
+
+/// A comma-separated list of `DeviceId`s, or `all`.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct DeviceSelector {
+    /// Empty means every device.
+    pub ids: Vec<DeviceId>,
+}
+
+impl std::str::FromStr for DeviceSelector {
+    type Err = SelectError;
+
+    /// Parses and `check`s the selection.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.trim().is_empty() || s.trim() == "all" {
+            return Ok(Self::default());
+        }
+        let ids = s
+            .split(',')
+            .map(str::parse)
+            .collect::<Result<Vec<DeviceId>, _>>()?;
+        let selector = Self { ids };
+        selector.check()?;
+        Ok(selector)
+    }
+}
+
+impl std::fmt::Display for DeviceSelector {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if self.ids.is_empty() {
+            return f.write_str("all");
+        }
+        let ids = self.ids.iter().map(|id| id.to_string()).collect::<Vec<_>>();
+        f.write_str(&ids.join(","))
+    }
+}
+
+/// Whether devices are numbered by PCI bus id (`CUDA_DEVICE_ORDER=PCI_BUS_ID`)
+/// rather than the driver's default fastest-first order.
+pub fn pci_bus_order() -> bool {
+    std::env::var("CUDA_DEVICE_ORDER").is_ok_and(|v| v == "PCI_BUS_ID")
+}
+
+#[derive(Debug, Clone)]
+pub struct VisibleDevice {
+    /// Driver ordinal, for `Context::new`.
+    pub ordinal: i32,
+    pub uuid: String,
+    pub pci_bus_id: String,
+}
+
+/// Devices the driver exposes, in user-facing order: PCI bus order if
+/// `CUDA_DEVICE_ORDER=PCI_BUS_ID`, otherwise driver order.
+pub fn visible_devices() -> Vec<VisibleDevice> {
+    let mut devices = (0..device_count() as i32)
+        .map(|ordinal| VisibleDevice {
+            ordinal,
+            uuid: device_uuid(ordinal),
+            pci_bus_id: normalize_bus_id(&pci_bus_id(ordinal)),
+        })
+        .collect::<Vec<_>>();
+    if pci_bus_order() {
+        devices.sort_by(|a, b| a.pci_bus_id.cmp(&b.pci_bus_id));
+    }
+    devices
+}
+
+impl DeviceSelector {
+    pub fn has_mig(&self) -> bool {
+        self.ids.iter().any(|id| matches!(id, DeviceId::Mig(_)))
+    }
+
+    /// What can be checked without the driver: no device is named twice,
+    /// and MIG instances aren't mixed with PCI bus ids, which
+    /// `CUDA_VISIBLE_DEVICES` can't express.
+    pub fn check(&self) -> Result<(), SelectError> {
+        for (i, id) in self.ids.iter().enumerate() {
+            if self.ids[..i].contains(id) {
+                return Err(SelectError::Parse(format!("device {} selected twice", id)));
+            }
+        }
+        if self.has_mig()
+            && let Some(id) = self
+                .ids
+                .iter()
+                .find(|id| matches!(id, DeviceId::PciBusId(_)))
+        {
+            return Err(SelectError::Parse(format!(
+                "MIG instances can't be combined with PCI bus ids ({})",
+                id
+            )));
+        }
+        Ok(())
+    }
+
+    /// Restricts the driver to the selected devices by setting
+    /// `CUDA_VISIBLE_DEVICES`, which must happen before `INIT`. Afterwards the
+    /// selection is available as ordinals `0..n`.
+    pub fn export_visible(&self) -> Result<(), SelectError> {
+        if INITIALIZED.load(Ordering::SeqCst) {
+            return Err(SelectError::AlreadyInitialized);
+        }
+        if let Some(id) = self
+            .ids
+            .iter()
+            .find(|id| matches!(id, DeviceId::PciBusId(_)))
+        {
+            return Err(SelectError::Parse(format!(
+                "CUDA_VISIBLE_DEVICES can't select by PCI bus id ({})",
+                id
+            )));
+        }
+        if !self.ids.is_empty() {
+            // Safe as long as no other thread reads the environment; call
+            // this early in `main`.
+            unsafe { std::env::set_var("CUDA_VISIBLE_DEVICES", self.to_string()) };
+        }
+        Ok(())
+    }
+
+    /// Driver ordinals of the selected devices, in selection order. MIG
+    /// instances must be selected with `export_visible` instead.
+    pub fn resolve(&self) -> Result<Vec<i32>, SelectError> {
+        self.check()?;
+        if self.has_mig() {
+            return Err(SelectError::Parse(String::from(
+                "MIG instances can only be selected through CUDA_VISIBLE_DEVICES",
+            )));
+        }
+        self.resolve_in(&visible_devices())
+    }
+
+    /// Like `resolve`, against a given device list.
+    pub fn resolve_in(&self, visible: &[VisibleDevice]) -> Result<Vec<i32>, SelectError> {
+        if self.ids.is_empty() {
+            return Ok(visible.iter().map(|d| d.ordinal).collect());
+        }
+        self.ids
+            .iter()
+            .map(|id| {
+                let matches = visible
+                    .iter()
+                    .filter(|d| match id {
+                        DeviceId::Uuid(prefix) => d
+                            .uuid
+                            .to_ascii_lowercase()
+                            .starts_with(&prefix.to_ascii_lowercase()),
+                        DeviceId::PciBusId(bus_id) => d.pci_bus_id == *bus_id,
+                        _ => false,
+                    })
+                    .collect::<Vec<_>>();
+                match (id, matches.as_slice()) {
+                    (DeviceId::Ordinal(o), _) => {
+                        visible
+                            .get(*o)
+                            .map(|d| d.ordinal)
+                            .ok_or(SelectError::OutOfRange {
+                                ordinal: *o,
+                                count: visible.len(),
+                            })
+                    }
+                    (_, [d]) => Ok(d.ordinal),
+                    (_, []) => Err(SelectError::NotFound(id.clone())),
+                    (_, many) => Err(SelectError::Ambiguous(
+                        id.clone(),
+                        many.iter().map(|d| d.uuid.clone()).collect(),
+                    )),
+                }
+            })
+            .collect()
+    }
+
+    /// Driver ordinals of the selection. A selection with MIG instances is
+    /// exported as `CUDA_VISIBLE_DEVICES` first, so it must be made before
+    /// `INIT`; the instances then appear as every visible device.
+    pub fn select(&self) -> Result<Vec<i32>, SelectError> {
+        if self.has_mig() {
+            self.export_visible()?;
+            return DeviceSelector::default().resolve();
+        }
+        self.resolve()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(s: &str) -> DeviceId {
+        s.parse().unwrap()
+    }
+
+    fn selector(s: &str) -> DeviceSelector {
+        s.parse().unwrap()
+    }
+
+    fn visible() -> Vec<VisibleDevice> {
+        [
+            (
+                1,
+                "GPU-aaaa1111-0000-0000-0000-000000000000",
+                "0000:17:00.0",
+            ),
+            (
+                0,
+                "GPU-aaaa2222-0000-0000-0000-000000000000",
+                "0000:65:00.0",
+            ),
+            (
+                2,
+                "GPU-bbbb3333-0000-0000-0000-000000000000",
+                "0000:ca:00.0",
+            ),
+        ]
+        .into_iter()
+        .map(|(ordinal, uuid, pci_bus_id)| VisibleDevice {
+            ordinal,
+            uuid: String::from(uuid),
+            pci_bus_id: String::from(pci_bus_id),
+        })
+        .collect()
+    }
+
+    #[test]
+    fn parses_ordinals() {
+        assert_eq!(id("0"), DeviceId::Ordinal(0));
+        assert_eq!(id(" 12 "), DeviceId::Ordinal(12));
+        assert!("-1".parse::<DeviceId>().is_err());
+    }
+
+    #[test]
+    fn parses_uuids_lowercase() {
+        assert_eq!(
+            id("GPU-AB12-cd34"),
+            DeviceId::Uuid(String::from("GPU-ab12-cd34"))
+        );
+        for bad in ["GPU-", "GPU-xyz", "GPU-ab 12"] {
+            assert!(bad.parse::<DeviceId>().is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn parses_mig_ids_case_sensitively() {
+        assert_eq!(
+            id("mig-GPU-Ab12/1/0"),
+            DeviceId::Mig(String::from("MIG-GPU-Ab12/1/0"))
+        );
+        assert!("MIG-".parse::<DeviceId>().is_err());
+    }
+
+    #[test]
+    fn parses_pci_bus_ids() {
+        let full = DeviceId::PciBusId(String::from("0000:17:00.0"));
+        assert_eq!(id("17:00.0"), full);
+        assert_eq!(id("0000:17:00.0"), full);
+        assert_eq!(id("00000000:17:00.0"), full);
+        assert_eq!(id("0000:CA:00.0"), id("ca:00.0"));
+        for bad in ["zz:00.0", "1:2.3", "0000:17:00:0.0", "17:00.00"] {
+            assert!(bad.parse::<DeviceId>().is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_forms() {
+        for bad in ["", "gpu", "all", "0x1"] {
+            assert!(bad.parse::<DeviceId>().is_err(), "{bad:?}");
+        }
+    }
+
+    #[test]
+    fn all_and_empty_select_every_device() {
+        assert_eq!(selector("all"), DeviceSelector::default());
+        assert_eq!(selector(" "), DeviceSelector::default());
+        assert_eq!(selector("all").to_string(), "all");
+        assert!("0,all".parse::<DeviceSelector>().is_err());
+        assert!("0,,1".parse::<DeviceSelector>().is_err());
+    }
+
+    #[test]
+    fn selector_round_trips() {
+        let s = selector("1, GPU-AB12,17:00.0");
+        assert_eq!(s.ids.len(), 3);
+        assert_eq!(s.to_string(), "1,GPU-ab12,0000:17:00.0");
+        assert_eq!(selector(&s.to_string()), s);
+    }
+
+    #[test]
+    fn check_rejects_duplicates_and_mig_with_bus_ids() {
+        let dup = "0,1,0".parse::<DeviceSelector>().unwrap_err();
+        assert_eq!(dup.to_string(), "device 0 selected twice");
+        assert!("17:00.0,0000:17:00.0".parse::<DeviceSelector>().is_err());
+        assert!("MIG-abc,17:00.0".parse::<DeviceSelector>().is_err());
+        assert!(selector("MIG-abc,MIG-def").has_mig());
+    }
+
+    #[test]
+    fn resolve_in_maps_to_driver_ordinals() {
+        let visible = visible();
+        assert_eq!(selector("all").resolve_in(&visible).unwrap(), [1, 0, 2]);
+        assert_eq!(
+            selector("gpu-bbbb,0000:17:00.0,1")
+                .resolve_in(&visible)
+                .unwrap(),
+            [2, 1, 0]
+        );
+    }
+
+    #[test]
+    fn uuids_match_device_uuid_format() {
+        let visible = visible();
+        let full = "GPU-aaaa2222-0000-0000-0000-000000000000";
+        for s in [
+            full,
+            "GPU-AAAA2222-0000-0000-0000-000000000000",
+            "gpu-aaaa2",
+        ] {
+            assert_eq!(selector(s).resolve_in(&visible).unwrap(), [0], "{s}");
+        }
+        // What `export_visible` puts in CUDA_VISIBLE_DEVICES.
+        assert_eq!(selector(&full.to_ascii_uppercase()).to_string(), full);
+    }
+
+    #[test]
+    fn resolve_in_reports_unmatched_selections() {
+        let visible = visible();
+        assert_eq!(
+            selector("3").resolve_in(&visible),
+            Err(SelectError::OutOfRange {
+                ordinal: 3,
+                count: 3
+            })
+        );
+        assert_eq!(
+            selector("0,3")
+                .resolve_in(&visible)
+                .unwrap_err()
+                .to_string(),
+            "device 3 requested but only 3 GPU(s) present"
+        );
+        assert_eq!(
+            selector("gpu-cc").resolve_in(&visible),
+            Err(SelectError::NotFound(id("gpu-cc")))
+        );
+        assert!(matches!(
+            selector("gpu-aaaa").resolve_in(&visible),
+            Err(SelectError::Ambiguous(_, matches)) if matches.len() == 2
+        ));
+    }
+}
@@ -0,0 +1,897 @@
+//! A compact language for ad-hoc transfer scenarios, e.g.
+//!
+//! ```text
+//! pinned@numa1 -> dev:2 x4streams
+//! dev:0 <-> pinned x2threads
+//! dev:0 => dev:1,dev:2,dev:3 (chain) x8chunks
+//! dev:1,dev:2 => pinned (gather)
+//! ```
+//!
+//! A side is an address space (`dev`/`gpu`, `pinned`, `pageable`/`cpu`,
+//! `registered`), an optional `:N` device index (the device a host buffer
+//! belongs to defaults to the other side's) and, for host memory, an
+//! optional `@numaN` placement. Device indices refer to the selected
+//! devices, not driver ordinals.
+//!
+//! `->` copies every source to every target, `<->` does the same in both
+//! directions at once, and `=>` runs a collective from `collectives`:
+//! a broadcast (`flat`, `chain` or `tree`, default `flat`), `scatter`, or
+//! `gather` when several sources feed one target. `xN`/`xNstreams` issues
+//! `N` concurrent copies per pair, `xNthreads` does so from `N` threads, and
+//! `xNchunks` pipelines a broadcast.
+
+use std::ops::Range;
+
+use crate::affinity::Affinity;
+use crate::bench::{Endpoint, Scenario, Transfer};
+use crate::collectives::{Broadcast, Collective, Op};
+use crate::host::numa_node_cpus;
+use crate::*;
+
+/// A parse error with the byte range of the offending token.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExprError {
+    pub input: String,
+    pub span: Range<usize>,
+    pub message: String,
+}
+
+impl std::fmt::Display for ExprError {
+    /// The message, then the expression with the token underlined.
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let width = self.input[self.span.clone()].chars().count().max(1);
+        let offset = self.input[..self.span.start].chars().count();
+        writeln!(f, "{}", self.message)?;
+        writeln!(f, "  {}", self.input)?;
+        write!(f, "  {}{}", " ".repeat(offset), "^".repeat(width))
+    }
+}
+
+impl std::error::Error for ExprError {}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Word(String),
+    Number(usize),
+    Colon,
+    At,
+    Comma,
+    LParen,
+    RParen,
+    Arrow,
+    BiArrow,
+    FatArrow,
+    End,
+}
+
+impl Token {
+    fn describe(&self) -> String {
+        match self {
+            Token::Word(w) => format!("`{}`", w),
+            Token::Number(n) => format!("`{}`", n),
+            Token::Colon => String::from("`:`"),
+            Token::At => String::from("`@`"),
+            Token::Comma => String::from("`,`"),
+            Token::LParen => String::from("`(`"),
+            Token::RParen => String::from("`)`"),
+            Token::Arrow => String::from("`->`"),
+            Token::BiArrow => String::from("`<->`"),
+            Token::FatArrow => String::from("`=>`"),
+            Token::End => String::from("end of expression"),
+        }
+    }
+}
+
+fn lex(input: &str) -> Result<Vec<(Token, Range<usize>)>, ExprError> {
+    let bytes = input.as_bytes();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        let start = i;
+        let c = bytes[i];
+        let token = if c.is_ascii_whitespace() {
+            i += 1;
+            continue;
+        } else if input[i..].starts_with("<->") {
+            i += 3;
+            Token::BiArrow
+        } else if input[i..].starts_with("->") {
+            i += 2;
+            Token::Arrow
+        } else if input[i..].starts_with("=>") {
+            i += 2;
+            Token::FatArrow
+        } else if c.is_ascii_digit() {
+            while i < bytes.len() && bytes[i].is_ascii_digit() {
+                i += 1;
+            }
+            match input[start..i].parse() {
+                Ok(n) => Token::Number(n),
+                Err(_) => {
+                    return Err(ExprError {
+                        input: input.to_string(),
+                        span: start..i,
+                        message: String::from("number too large"),
+                    });
+                }
+            }
+        } else if c.is_ascii_alphabetic() || c == b'_' {
+            while i < bytes.len() && (bytes[i].is_ascii_alphanumeric() || bytes[i] == b'_') {
+                i += 1;
+            }
+            Token::Word(input[start..i].to_ascii_lowercase())
+        } else {
+            i += input[i..].chars().next().map_or(1, char::len_utf8);
+            match c {
+                b':' => Token::Colon,
+                b'@' => Token::At,
+                b',' => Token::Comma,
+                b'(' => Token::LParen,
+                b')' => Token::RParen,
+                _ => {
+                    return Err(ExprError {
+                        input: input.to_string(),
+                        span: start..i,
+                        message: format!("unexpected character {:?}", &input[start..i]),
+                    });
+                }
+            }
+        };
+        tokens.push((token, start..i));
+    }
+    tokens.push((Token::End, input.len()..input.len()));
+    Ok(tokens)
+}
+
+/// One side of a copy.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Side {
+    pub space: AddressSpace,
+    /// Index into the selected devices; `None` for host memory that belongs
+    /// to the device on the other side.
+    pub device: Option<usize>,
+    /// NUMA node host pages are placed on.
+    pub numa_node: Option<usize>,
+}
+
+impl std::fmt::Display for Side {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let space = match self.space {
+            AddressSpace::Device => "dev",
+            AddressSpace::Pinned => "pinned",
+            AddressSpace::Registered => "registered",
+            AddressSpace::Cpu => "pageable",
+        };
+        f.write_str(space)?;
+        if let Some(device) = self.device {
+            write!(f, ":{}", device)?;
+        }
+        if let Some(node) = self.numa_node {
+            write!(f, "@numa{}", node)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Arrow {
+    /// `->`
+    Copy,
+    /// `<->`
+    Bidirectional,
+    /// `=>`
+    Collective,
+}
+
+impl std::fmt::Display for Arrow {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(match self {
+            Arrow::Copy => "->",
+            Arrow::Bidirectional => "<->",
+            Arrow::Collective => "=>",
+        })
+    }
+}
+
+/// A parsed expression.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TransferExpr {
+    pub sources: Vec<Side>,
+    pub arrow: Arrow,
+    pub targets: Vec<Side>,
+    /// Concurrent copies per source/target pair.
+    pub streams: usize,
+    /// Issue the copies from one thread each.
+    pub threaded: bool,
+    /// Set for `=>`.
+    pub op: Option<Op>,
+    /// Pipeline chunks for a broadcast.
+    pub chunks: usize,
+}
+
+struct Parser<'a> {
+    input: &'a str,
+    tokens: Vec<(Token, Range<usize>)>,
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> &Token {
+        &self.tokens[self.pos].0
+    }
+
+    fn span(&self) -> Range<usize> {
+        self.tokens[self.pos].1.clone()
+    }
+
+    fn next(&mut self) -> (Token, Range<usize>) {
+        let token = self.tokens[self.pos].clone();
+        if token.0 != Token::End {
+            self.pos += 1;
+        }
+        token
+    }
+
+    fn error<T>(&self, span: Range<usize>, message: impl Into<String>) -> Result<T, ExprError> {
+        Err(ExprError {
+            input: self.input.to_string(),
+            span,
+            message: message.into(),
+        })
+    }
+
+    fn side(&mut self) -> Result<Side, ExprError> {
+        let (token, span) = self.next();
+        let space = match &token {
+            Token::Word(w) => match w.as_str() {
+                "dev" | "gpu" | "device" => AddressSpace::Device,
+                "pinned" => AddressSpace::Pinned,
+                "pageable" | "cpu" | "host" => AddressSpace::Cpu,
+                "registered" | "reg" => AddressSpace::Registered,
+                _ => {
+                    return self.error(
+                        span,
+                        format!(
+                            "unknown address space `{}` (expected dev, pinned, pageable or registered)",
+                            w
+                        ),
+                    );
+                }
+            },
+            _ => {
+                return self.error(
+                    span,
+                    format!("expected an address space, found {}", token.describe()),
+                );
+            }
+        };
+        let mut device = None;
+        if *self.peek() == Token::Colon {
+            self.next();
+            match self.next() {
+                (Token::Number(n), _) => device = Some(n),
+                (token, span) => {
+                    return self.error(
+                        span,
+                        format!("expected a device index, found {}", token.describe()),
+                    );
+                }
+            }
+        }
+        let mut numa_node = None;
+        if *self.peek() == Token::At {
+            let at = self.next().1;
+            let (token, span) = self.next();
+            let node = match &token {
+                Token::Word(w) => w.strip_prefix("numa").and_then(|n| n.parse().ok()),
+                _ => None,
+            };
+            match node {
+                Some(_) if space == AddressSpace::Device => {
+                    return self.error(at.start..span.end, "device memory has no NUMA placement");
+                }
+                Some(node) => numa_node = Some(node),
+                None => {
+                    return self.error(
+                        span,
+                        format!(
+                            "expected a placement like `numa1`, found {}",
+                            token.describe()
+                        ),
+                    );
+                }
+            }
+        }
+        if space == AddressSpace::Device && device.is_none() {
+            device = Some(0);
+        }
+        Ok(Side {
+            space,
+            device,
+            numa_node,
+        })
+    }
+
+    fn sides(&mut self) -> Result<Vec<(Side, Range<usize>)>, ExprError> {
+        let mut sides = Vec::new();
+        loop {
+            let start = self.span().start;
+            let side = self.side()?;
+            let end = self.tokens[self.pos - 1].1.end;
+            sides.push((side, start..end));
+            if *self.peek() != Token::Comma {
+                return Ok(sides);
+            }
+            self.next();
+        }
+    }
+}
+
+/// `x4`, `x4streams`, `x2threads` or `x8chunks`.
+fn parse_count(word: &str) -> Option<(usize, &str)> {
+    let rest = word.strip_prefix('x')?;
+    let digits = rest
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(rest.len());
+    let n = rest[..digits].parse().ok()?;
+    Some((n, &rest[digits..]))
+}
+
+impl std::str::FromStr for TransferExpr {
+    type Err = ExprError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut p = Parser {
+            input: s,
+            tokens: lex(s)?,
+            pos: 0,
+        };
+        let sources = p.sides()?;
+        let arrow = match p.next() {
+            (Token::Arrow, _) => Arrow::Copy,
+            (Token::BiArrow, _) => Arrow::Bidirectional,
+            (Token::FatArrow, _) => Arrow::Collective,
+            (token, span) => {
+                return p.error(
+                    span,
+                    format!("expected `->`, `<->` or `=>`, found {}", token.describe()),
+                );
+            }
+        };
+        let targets = p.sides()?;
+
+        let mut streams = None;
+        let mut threaded = false;
+        let mut chunks = None;
+        let mut pattern = None;
+        loop {
+            let (token, span) = p.next();
+            match token {
+                Token::End => break,
+                Token::Word(w) => {
+                    let Some((n, unit)) = parse_count(&w) else {
+                        return p.error(
+                            span,
+                            format!(
+                                "unknown modifier `{}` (expected xNstreams, xNthreads, xNchunks or a (pattern))",
+                                w
+                            ),
+                        );
+                    };
+                    if n == 0 {
+                        return p.error(span, "count must be at least 1");
+                    }
+                    match unit {
+                        "" | "stream" | "streams" | "thread" | "threads" if streams.is_some() => {
+                            return p.error(span, "stream count given twice");
+                        }
+                        "" | "stream" | "streams" => streams = Some(n),
+                        "thread" | "threads" => {
+                            streams = Some(n);
+                            threaded = true;
+                        }
+                        "chunk" | "chunks" => chunks = Some((n, span.clone())),
+                        _ => {
+                            return p.error(
+                                span,
+                                format!(
+                                    "unknown unit `{}` (expected streams, threads or chunks)",
+                                    unit
+                                ),
+                            );
+                        }
+                    }
+                    if arrow == Arrow::Collective && streams.is_some() {
+                        return p.error(span, "collectives use one stream per device");
+                    }
+                }
+                Token::LParen => {
+                    let (token, name_span) = p.next();
+                    let Token::Word(name) = token else {
+                        return p.error(
+                            name_span,
+                            format!("expected a pattern name, found {}", token.describe()),
+                        );
+                    };
+                    let (token, close) = p.next();
+                    if token != Token::RParen {
+                        return p.error(close, format!("expected `)`, found {}", token.describe()));
+                    }
+                    if arrow != Arrow::Collective {
+                        return p.error(
+                            span.start..name_span.end + 1,
+                            format!("`({})` needs a collective `=>`", name),
+                        );
+                    }
+                    let op = match name.as_str() {
+                        "scatter" => Op::Scatter,
+                        "gather" => Op::Gather,
+                        other => match other.parse() {
+                            Ok(b) => Op::Broadcast(b),
+                            Err(_) => {
+                                return p.error(
+                                    name_span,
+                                    format!(
+                                        "unknown pattern `{}` (expected flat, chain, tree, scatter or gather)",
+                                        name
+                                    ),
+                                );
+                            }
+                        },
+                    };
+                    pattern = Some(op);
+                }
+                token => {
+                    return p.error(
+                        span,
+                        format!("expected a modifier, found {}", token.describe()),
+                    );
+                }
+            }
+        }
+
+        let mut op = None;
+        if arrow == Arrow::Collective {
+            let gather = sources.len() > 1 && targets.len() == 1;
+            let o = pattern.unwrap_or(if gather {
+                Op::Gather
+            } else {
+                Op::Broadcast(Broadcast::Flat)
+            });
+            let (root, ranks) = if o == Op::Gather {
+                (&targets, &sources)
+            } else {
+                (&sources, &targets)
+            };
+            if root.len() != 1 {
+                return p.error(
+                    root[1].1.clone(),
+                    format!(
+                        "{} takes a single {}",
+                        o,
+                        if o == Op::Gather { "target" } else { "source" }
+                    ),
+                );
+            }
+            if let Some((_, span)) = ranks.iter().find(|(r, _)| r.space != AddressSpace::Device) {
+                return p.error(span.clone(), "collective ranks must be device memory");
+            }
+            if let Some((_, span)) = &chunks
+                && !matches!(o, Op::Broadcast(_))
+            {
+                return p.error(span.clone(), "only broadcasts can be chunked");
+            }
+            op = Some(o);
+        } else if let Some((_, span)) = &chunks {
+            return p.error(span.clone(), "only broadcasts can be chunked");
+        }
+
+        Ok(TransferExpr {
+            sources: sources.into_iter().map(|(s, _)| s).collect(),
+            arrow,
+            targets: targets.into_iter().map(|(s, _)| s).collect(),
+            streams: streams.unwrap_or(1),
+            threaded,
+            op,
+            chunks: chunks.map_or(1, |(n, _)| n),
+        })
+    }
+}
+
+impl std::fmt::Display for TransferExpr {
+    /// The canonical form, used as the scenario name.
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let list = |sides: &[Side]| {
+            sides
+                .iter()
+                .map(|s| s.to_string())
+                .collect::<Vec<_>>()
+                .join(",")
+        };
+        write!(
+            f,
+            "{} {} {}",
+            list(&self.sources),
+            self.arrow,
+            list(&self.targets)
+        )?;
+        match self.op {
+            Some(Op::Broadcast(b)) => write!(f, " ({})", b)?,
+            Some(op) => write!(f, " ({})", op)?,
+            None => {}
+        }
+        if self.streams > 1 {
+            let unit = if self.threaded { "threads" } else { "streams" };
+            write!(f, " x{}{}", self.streams, unit)?;
+        } else if self.threaded {
+            f.write_str(" x1threads")?;
+        }
+        if self.chunks > 1 {
+            write!(f, " x{}chunks", self.chunks)?;
+        }
+        Ok(())
+    }
+}
+
+impl TransferExpr {
+    /// Number of selected devices the expression refers to.
+    pub fn devices_needed(&self) -> usize {
+        self.sources
+            .iter()
+            .chain(&self.targets)
+            .filter_map(|s| s.device)
+            .map(|d| d + 1)
+            .max()
+            .unwrap_or(1)
+    }
+
+    /// Streams needed per device.
+    pub fn streams_per_device(&self) -> usize {
+        match self.arrow {
+            Arrow::Bidirectional => self.streams * 2,
+            _ => self.streams,
+        }
+    }
+
+    /// The NUMA node host memory is placed on, if any side asks for one.
+    pub fn numa_node(&self) -> Option<usize> {
+        self.sources
+            .iter()
+            .chain(&self.targets)
+            .find_map(|s| s.numa_node)
+    }
+
+    /// Pageable and registered pages are first-touched by threads on the
+    /// requested node; pinned memory is placed when allocated, so callers
+    /// should also run on the node (see `numa_node`).
+    pub fn host_options(&self) -> HostOptions {
+        let prefault = match self.numa_node() {
+            Some(node) => Prefault::ParallelTouch {
+                threads: numa_node_cpus(node).len().max(1),
+                numa_node: Some(node),
+            },
+            None => Prefault::None,
+        };
+        HostOptions {
+            prefault,
+            ..Default::default()
+        }
+    }
+
+    /// Builds the scenario over `streams`, which holds `per_device`
+    /// consecutive streams for each selected device.
+    pub fn build(
+        &self,
+        streams: &[Stream],
+        per_device: usize,
+        size: usize,
+        affinity: &Affinity,
+    ) -> Box<dyn Scenario> {
+        let name = self.to_string();
+        if let Some(op) = self.op {
+            let (root, ranks) = if op == Op::Gather {
+                (&self.targets[0], &self.sources)
+            } else {
+                (&self.sources[0], &self.targets)
+            };
+            let stream = |side: &Side| streams[side.device.unwrap_or(0) * per_device].clone();
+            let ranks = ranks.iter().map(stream).collect::<Vec<_>>();
+            let root_stream = match root.device {
+                Some(_) => stream(root),
+                None => ranks[0].clone(),
+            };
+            let collective = Collective::new(op, &root_stream, &ranks, size)
+                .root_space(root.space.clone())
+                .chunks(self.chunks);
+            return Box::new(collective);
+        }
+
+        let mut t = Transfer::new(name, streams, size)
+            .host_options(self.host_options())
+            .affinity(affinity.clone());
+        for src in &self.sources {
+            for dst in &self.targets {
+                // Host memory belongs to, and copies are issued from, the
+                // device side; a host-to-host copy uses the first device.
+                let device = |side: &Side, other: &Side| side.device.or(other.device).unwrap_or(0);
+                let (s, d) = (device(src, dst), device(dst, src));
+                let issuer = if src.space == AddressSpace::Device {
+                    s
+                } else {
+                    d
+                };
+                let endpoint = |side: &Side, device: usize, slot: usize| Endpoint {
+                    address_space: side.space.clone(),
+                    device: device * per_device,
+                    slot,
+                };
+                for k in 0..self.streams {
+                    t = t.copy(
+                        endpoint(dst, d, k),
+                        endpoint(src, s, k),
+                        issuer * per_device + k,
+                    );
+                    if self.arrow == Arrow::Bidirectional {
+                        let slot = self.streams + k;
+                        t = t.copy(
+                            endpoint(src, s, slot),
+                            endpoint(dst, d, slot),
+                            issuer * per_device + slot,
+                        );
+                    }
+                }
+            }
+        }
+        if self.arrow == Arrow::Bidirectional {
+            t = t.timed();
+        }
+        if self.threaded {
+            t = t.threaded();
+        }
+        Box::new(t)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(s: &str) -> TransferExpr {
+        s.parse().unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// The error message and the text its span covers.
+    fn error(s: &str) -> (String, String) {
+        let e = s.parse::<TransferExpr>().unwrap_err();
+        assert_eq!(e.input, s);
+        (e.message, s[e.span].to_string())
+    }
+
+    fn side(space: AddressSpace, device: Option<usize>, numa_node: Option<usize>) -> Side {
+        Side {
+            space,
+            device,
+            numa_node,
+        }
+    }
+
+    #[test]
+    fn copy() {
+        let e = parse("pinned@numa1 -> dev:2 x4streams");
+        assert_eq!(e.sources, [side(AddressSpace::Pinned, None, Some(1))]);
+        assert_eq!(e.arrow, Arrow::Copy);
+        assert_eq!(e.targets, [side(AddressSpace::Device, Some(2), None)]);
+        assert_eq!((e.streams, e.threaded, e.op, e.chunks), (4, false, None, 1));
+        assert_eq!(e.devices_needed(), 3);
+        assert_eq!(e.numa_node(), Some(1));
+    }
+
+    #[test]
+    fn bidirectional_threads() {
+        let e = parse("GPU <-> Pageable x2threads");
+        assert_eq!(e.sources, [side(AddressSpace::Device, Some(0), None)]);
+        assert_eq!(e.arrow, Arrow::Bidirectional);
+        assert_eq!(e.targets, [side(AddressSpace::Cpu, None, None)]);
+        assert_eq!((e.streams, e.threaded), (2, true));
+        assert_eq!(e.streams_per_device(), 4);
+    }
+
+    #[test]
+    fn collectives() {
+        let e = parse("dev:0 => dev:1,dev:2,dev:3 (chain) x8chunks");
+        assert_eq!(e.op, Some(Op::Broadcast(Broadcast::Chain)));
+        assert_eq!((e.targets.len(), e.chunks), (3, 8));
+
+        assert_eq!(parse("dev:1,dev:2 => pinned").op, Some(Op::Gather));
+        assert_eq!(
+            parse("pinned => dev:0,dev:1").op,
+            Some(Op::Broadcast(Broadcast::Flat))
+        );
+        assert_eq!(
+            parse("pinned => dev:0,dev:1 (scatter)").op,
+            Some(Op::Scatter)
+        );
+    }
+
+    #[test]
+    fn canonical_form_round_trips() {
+        for (input, canonical) in [
+            ("pinned@numa1->gpu:2 x4", "pinned@numa1 -> dev:2 x4streams"),
+            ("reg <-> dev x1threads", "registered <-> dev:0 x1threads"),
+            (
+                "dev:0 => dev:1, dev:2 (tree) x2chunks",
+                "dev:0 => dev:1,dev:2 (tree) x2chunks",
+            ),
+            ("dev:1,dev:2 => host", "dev:1,dev:2 => pageable (gather)"),
+        ] {
+            let e = parse(input);
+            assert_eq!(e.to_string(), canonical);
+            assert_eq!(parse(canonical), e);
+        }
+    }
+
+    #[test]
+    fn lex_errors() {
+        assert_eq!(
+            error("dev:99999999999999999999999 -> pinned"),
+            (
+                "number too large".to_string(),
+                "99999999999999999999999".to_string()
+            )
+        );
+        assert_eq!(
+            error("dev -> pinned é"),
+            ("unexpected character \"é\"".to_string(), "é".to_string())
+        );
+    }
+
+    #[test]
+    fn side_errors() {
+        let cases = [
+            (
+                "vram -> dev",
+                "unknown address space `vram` (expected dev, pinned, pageable or registered)",
+                "vram",
+            ),
+            ("-> dev", "expected an address space, found `->`", "->"),
+            (
+                "dev -> ",
+                "expected an address space, found end of expression",
+                "",
+            ),
+            ("dev:x -> pinned", "expected a device index, found `x`", "x"),
+            (
+                "dev@numa1 -> pinned",
+                "device memory has no NUMA placement",
+                "@numa1",
+            ),
+            (
+                "pinned@node1 -> dev",
+                "expected a placement like `numa1`, found `node1`",
+                "node1",
+            ),
+        ];
+        for (input, message, at) in cases {
+            assert_eq!(
+                error(input),
+                (message.to_string(), at.to_string()),
+                "{}",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn modifier_errors() {
+        let cases = [
+            (
+                "dev pinned",
+                "expected `->`, `<->` or `=>`, found `pinned`",
+                "pinned",
+            ),
+            (
+                "dev -> pinned fast",
+                "unknown modifier `fast` (expected xNstreams, xNthreads, xNchunks or a (pattern))",
+                "fast",
+            ),
+            ("dev -> pinned x0", "count must be at least 1", "x0"),
+            (
+                "dev -> pinned x2 x2threads",
+                "stream count given twice",
+                "x2threads",
+            ),
+            (
+                "dev -> pinned x2bytes",
+                "unknown unit `bytes` (expected streams, threads or chunks)",
+                "x2bytes",
+            ),
+            (
+                "pinned => dev:0,dev:1 x2",
+                "collectives use one stream per device",
+                "x2",
+            ),
+            (
+                "pinned => dev:0 (2)",
+                "expected a pattern name, found `2`",
+                "2",
+            ),
+            (
+                "pinned => dev:0 (chain",
+                "expected `)`, found end of expression",
+                "",
+            ),
+            (
+                "dev:0 -> dev:1 (chain)",
+                "`(chain)` needs a collective `=>`",
+                "(chain)",
+            ),
+            (
+                "pinned => dev:0 (ring)",
+                "unknown pattern `ring` (expected flat, chain, tree, scatter or gather)",
+                "ring",
+            ),
+            ("dev -> pinned )", "expected a modifier, found `)`", ")"),
+        ];
+        for (input, message, at) in cases {
+            assert_eq!(
+                error(input),
+                (message.to_string(), at.to_string()),
+                "{}",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn collective_errors() {
+        let cases = [
+            (
+                "dev:0 => dev:1,dev:2 (gather)",
+                "gather takes a single target",
+                "dev:2",
+            ),
+            (
+                "dev:0,dev:1 => dev:2,dev:3",
+                "broadcast-flat takes a single source",
+                "dev:1",
+            ),
+            (
+                "dev:0,pinned => dev:2 (scatter)",
+                "scatter takes a single source",
+                "pinned",
+            ),
+            (
+                "dev:0 => dev:1,pinned@numa0",
+                "collective ranks must be device memory",
+                "pinned@numa0",
+            ),
+            (
+                "pinned => dev:0,dev:1 (scatter) x2chunks",
+                "only broadcasts can be chunked",
+                "x2chunks",
+            ),
+            (
+                "pinned -> dev:0 x4chunks",
+                "only broadcasts can be chunked",
+                "x4chunks",
+            ),
+        ];
+        for (input, message, at) in cases {
+            assert_eq!(
+                error(input),
+                (message.to_string(), at.to_string()),
+                "{}",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn error_display_underlines_span() {
+        let e = "dev -> vram".parse::<TransferExpr>().unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "unknown address space `vram` (expected dev, pinned, pageable or registered)\n  dev -> vram\n         ^^^^"
+        );
+    }
+}
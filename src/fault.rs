@@ -0,0 +1,325 @@
+//! Programmable driver faults, for exercising error paths without broken
+//! hardware. Faults are injected in front of the real driver calls made by
+//! `Stream`, `Event` and `p2p`, so a GPU is still needed; an injected error
+//! surfaces exactly as the driver returning it would.
+//!
+//! Plans are per thread, so tests running in parallel don't see each other's
+//! faults. Jobs submitted to a `DeviceWorkerPool` run under the submitting
+//! thread's plan and share its call counts; other threads are not affected.
+//!
+//! Failures surface through the `try_` variants (`Stream::try_memcpy_async`,
+//! `Stream::try_synchronize`, ...); the plain ones panic on them.
+//!
+//! ```ignore
+//! let _faults = FaultPlan::new()
+//!     .fail_nth(Call::Alloc, 2, sys::CUresult::CUDA_ERROR_OUT_OF_MEMORY)
+//!     .delay(Call::Synchronize, Duration::from_millis(50))
+//!     .install();
+//! ```
+
+use std::cell::RefCell;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use cudarc::driver::{DriverError, sys};
+
+use crate::{Stream, log_debug};
+
+/// Driver calls faults can be attached to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Call {
+    /// Any `create_buffer_async`/`create_host_buffer`.
+    Alloc,
+    /// `Stream::memcpy_async`.
+    Memcpy,
+    /// `Stream::synchronize` and `Event::synchronize`.
+    Synchronize,
+    /// `p2p::can_access_peer`; any error reports the pair as unsupported.
+    PeerAccess,
+}
+
+const CALLS: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fault {
+    Error(sys::CUresult),
+    /// Make the call complete this much later: allocations, peer checks and
+    /// synchronizations return late, and a copy holds up its stream after
+    /// it has been issued, as a slow transfer would.
+    Delay(Duration),
+}
+
+#[derive(Debug, Clone)]
+struct Rule {
+    call: Call,
+    /// 1-based call number to fire on, or every call.
+    nth: Option<usize>,
+    fault: Fault,
+}
+
+/// Faults to inject, matched in the order they were added.
+#[derive(Debug, Clone, Default)]
+pub struct FaultPlan {
+    rules: Vec<Rule>,
+}
+
+impl FaultPlan {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Fails the `n`th `call` (counting from 1) with `error`.
+    pub fn fail_nth(mut self, call: Call, n: usize, error: sys::CUresult) -> Self {
+        assert!(n > 0, "calls are counted from 1");
+        self.rules.push(Rule {
+            call,
+            nth: Some(n),
+            fault: Fault::Error(error),
+        });
+        self
+    }
+
+    /// Fails every `call` with `error`.
+    pub fn fail_all(mut self, call: Call, error: sys::CUresult) -> Self {
+        self.rules.push(Rule {
+            call,
+            nth: None,
+            fault: Fault::Error(error),
+        });
+        self
+    }
+
+    /// Delays every `call` by `delay`.
+    pub fn delay(mut self, call: Call, delay: Duration) -> Self {
+        self.rules.push(Rule {
+            call,
+            nth: None,
+            fault: Fault::Delay(delay),
+        });
+        self
+    }
+
+    /// Makes the CUDA peer check fail for every pair.
+    pub fn no_peer_access(self) -> Self {
+        self.fail_all(
+            Call::PeerAccess,
+            sys::CUresult::CUDA_ERROR_PEER_ACCESS_UNSUPPORTED,
+        )
+    }
+
+    /// Activates the plan on this thread until the guard is dropped,
+    /// replacing any active plan and resetting the call counts.
+    pub fn install(self) -> FaultGuard {
+        let active = Arc::new(Mutex::new(Active {
+            plan: self,
+            counts: [0; CALLS],
+        }));
+        ACTIVE.with_borrow_mut(|current| *current = Some(active));
+        FaultGuard { _private: () }
+    }
+}
+
+struct Active {
+    plan: FaultPlan,
+    counts: [usize; CALLS],
+}
+
+thread_local! {
+    static ACTIVE: RefCell<Option<Arc<Mutex<Active>>>> = const { RefCell::new(None) };
+}
+
+/// This thread's plan, to be applied on another thread with `enter`.
+#[derive(Clone, Default)]
+pub(crate) struct Inherited(Option<Arc<Mutex<Active>>>);
+
+impl Inherited {
+    pub(crate) fn current() -> Self {
+        Self(ACTIVE.with_borrow(|active| active.clone()))
+    }
+
+    /// Makes the plan active on this thread until the guard is dropped,
+    /// when the thread's own plan is restored.
+    pub(crate) fn enter(&self) -> Entered {
+        let previous = ACTIVE.with_borrow_mut(|active| std::mem::replace(active, self.0.clone()));
+        Entered { previous }
+    }
+}
+
+pub(crate) struct Entered {
+    previous: Option<Arc<Mutex<Active>>>,
+}
+
+impl Drop for Entered {
+    fn drop(&mut self) {
+        ACTIVE.with_borrow_mut(|active| *active = self.previous.take());
+    }
+}
+
+/// Deactivates the installed plan on drop.
+#[must_use = "the plan is removed when the guard is dropped"]
+pub struct FaultGuard {
+    _private: (),
+}
+
+impl Drop for FaultGuard {
+    fn drop(&mut self) {
+        ACTIVE.with_borrow_mut(|active| *active = None);
+    }
+}
+
+/// How many times `call` has been made on this thread since the plan was
+/// installed.
+pub fn count(call: Call) -> usize {
+    ACTIVE.with_borrow(|active| {
+        active
+            .as_ref()
+            .map_or(0, |a| a.lock().unwrap().counts[call as usize])
+    })
+}
+
+/// Counts `call` and applies the first matching rule: returns the error for
+/// a failure, or the delay to add to the call's completion.
+pub fn inject(call: Call) -> Result<Option<Duration>, DriverError> {
+    let fault = ACTIVE.with_borrow(|active| {
+        let mut active = active.as_ref()?.lock().unwrap();
+        active.counts[call as usize] += 1;
+        let n = active.counts[call as usize];
+        active
+            .plan
+            .rules
+            .iter()
+            .find(|r| r.call == call && r.nth.is_none_or(|nth| nth == n))
+            .map(|r| r.fault)
+    });
+    match fault {
+        Some(Fault::Error(error)) => {
+            log_debug!("Injecting {:?} into {:?}", error, call);
+            Err(DriverError(error))
+        }
+        Some(Fault::Delay(delay)) => Ok(Some(delay)),
+        None => Ok(None),
+    }
+}
+
+unsafe extern "C" fn sleep_host_fn(nanos: *mut std::ffi::c_void) {
+    std::thread::sleep(Duration::from_nanos(nanos as u64));
+}
+
+/// Holds up `stream` for `delay` after the work queued so far.
+pub(crate) fn delay_stream(stream: &Stream, delay: Duration) {
+    stream.ctx.set_current();
+    let nanos = delay.as_nanos().min(usize::MAX as u128) as usize;
+    unsafe { sys::cuLaunchHostFunc(stream.stream, Some(sleep_host_fn), nanos as *mut _) }
+        .result()
+        .unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OOM: sys::CUresult = sys::CUresult::CUDA_ERROR_OUT_OF_MEMORY;
+
+    #[test]
+    fn no_plan_injects_nothing() {
+        assert_eq!(inject(Call::Alloc), Ok(None));
+        assert_eq!(count(Call::Alloc), 0);
+    }
+
+    #[test]
+    fn fail_nth_fires_once() {
+        let _faults = FaultPlan::new().fail_nth(Call::Alloc, 2, OOM).install();
+        assert_eq!(inject(Call::Alloc), Ok(None));
+        assert_eq!(inject(Call::Alloc), Err(DriverError(OOM)));
+        assert_eq!(inject(Call::Alloc), Ok(None));
+        assert_eq!(inject(Call::Memcpy), Ok(None));
+        assert_eq!(count(Call::Alloc), 3);
+        assert_eq!(count(Call::Memcpy), 1);
+    }
+
+    #[test]
+    fn first_matching_rule_wins() {
+        let delay = Duration::from_millis(5);
+        let _faults = FaultPlan::new()
+            .fail_nth(Call::Synchronize, 1, OOM)
+            .delay(Call::Synchronize, delay)
+            .install();
+        assert_eq!(inject(Call::Synchronize), Err(DriverError(OOM)));
+        assert_eq!(inject(Call::Synchronize), Ok(Some(delay)));
+    }
+
+    #[test]
+    fn fail_all_and_peer_access() {
+        let _faults = FaultPlan::new().no_peer_access().install();
+        for _ in 0..3 {
+            assert!(inject(Call::PeerAccess).is_err());
+        }
+        assert_eq!(inject(Call::Alloc), Ok(None));
+    }
+
+    #[test]
+    fn guard_drop_and_reinstall_reset_plan() {
+        let faults = FaultPlan::new().fail_all(Call::Memcpy, OOM).install();
+        assert!(inject(Call::Memcpy).is_err());
+        drop(faults);
+        assert_eq!(inject(Call::Memcpy), Ok(None));
+        assert_eq!(count(Call::Memcpy), 0);
+
+        let _faults = FaultPlan::new().fail_nth(Call::Memcpy, 1, OOM).install();
+        assert!(inject(Call::Memcpy).is_err());
+    }
+
+    #[test]
+    #[should_panic(expected = "counted from 1")]
+    fn fail_nth_rejects_zero() {
+        let _ = FaultPlan::new().fail_nth(Call::Alloc, 0, OOM);
+    }
+
+    #[test]
+    fn plans_are_per_thread() {
+        let _faults = FaultPlan::new().fail_all(Call::Alloc, OOM).install();
+        std::thread::spawn(|| assert_eq!(inject(Call::Alloc), Ok(None)))
+            .join()
+            .unwrap();
+        assert!(inject(Call::Alloc).is_err());
+    }
+
+    #[test]
+    fn inherited_plan_shares_counts() {
+        let _faults = FaultPlan::new().fail_nth(Call::Alloc, 2, OOM).install();
+        let inherited = Inherited::current();
+        std::thread::spawn(move || {
+            let _entered = inherited.enter();
+            assert_eq!(inject(Call::Alloc), Ok(None));
+        })
+        .join()
+        .unwrap();
+        assert_eq!(count(Call::Alloc), 1);
+        assert_eq!(inject(Call::Alloc), Err(DriverError(OOM)));
+    }
+
+    #[test]
+    #[ignore = "needs a GPU"]
+    fn allocation_falls_back_after_injected_oom() {
+        let ctx = crate::Context::new(0);
+        let stream = ctx.create_stream();
+        let _faults = FaultPlan::new().fail_nth(Call::Alloc, 1, OOM).install();
+        let allocation = stream
+            .allocate(
+                1 << 20,
+                crate::AddressSpace::Pinned,
+                &Default::default(),
+                &crate::alloc::AllocPolicy::default().host_fallback(),
+            )
+            .unwrap();
+        assert_eq!(
+            allocation.buffer.address_space,
+            crate::AddressSpace::Registered
+        );
+        assert_eq!(allocation.failures.len(), 1);
+        stream.free_buffer_sync(&allocation.buffer);
+
+        let _faults = FaultPlan::new().fail_all(Call::Synchronize, OOM).install();
+        assert_eq!(stream.try_synchronize(), Err(DriverError(OOM)));
+    }
+}
@@ -0,0 +1,648 @@
+//! A DAG of copies, memsets and host callbacks. Nodes name the nodes they
+//! depend on; the scheduler places them on streams and inserts only the
+//! events needed to honour dependencies across streams.
+//!
+//! Nodes are added in dependency order (a node can only depend on earlier
+//! ones), which is also the order they are issued in.
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use cudarc::driver::sys;
+
+use crate::bench::{CopyInfo, Scenario};
+use crate::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct NodeId(pub usize);
+
+/// A host callback. It runs on a driver thread and must not call CUDA.
+pub type HostFn = Arc<Mutex<dyn FnMut() + Send>>;
+
+#[derive(Clone)]
+pub enum NodeKind {
+    Copy { dst: Buffer, src: Buffer },
+    Memset { buf: Buffer, value: u8 },
+    Host(HostFn),
+}
+
+impl NodeKind {
+    /// The device whose stream issues the node: the device side of a copy,
+    /// or `None` for host callbacks, which can run on any stream.
+    fn device_id(&self) -> Option<i32> {
+        match self {
+            NodeKind::Copy { dst, src } if dst.address_space == AddressSpace::Device => {
+                Some(dst.ctx.device_id)
+            }
+            NodeKind::Copy { src, .. } => Some(src.ctx.device_id),
+            NodeKind::Memset { buf, .. } => Some(buf.ctx.device_id),
+            NodeKind::Host(_) => None,
+        }
+    }
+}
+
+struct Node {
+    label: String,
+    kind: NodeKind,
+    deps: Vec<usize>,
+}
+
+/// Where each node runs and what it waits for.
+#[derive(Debug, Clone, Default)]
+pub struct Schedule {
+    /// Index into the graph's streams, per node.
+    pub streams: Vec<usize>,
+    /// Nodes whose completion event each node waits on before starting.
+    pub waits: Vec<Vec<usize>>,
+}
+
+impl Schedule {
+    /// Nodes that record an event because some node waits on them.
+    pub fn recorded(&self) -> Vec<usize> {
+        let mut recorded = self.waits.concat();
+        recorded.sort();
+        recorded.dedup();
+        recorded
+    }
+}
+
+/// The chain of nodes that took longest in the last execution, following
+/// dependencies and stream order.
+#[derive(Debug, Clone)]
+pub struct CriticalPath {
+    pub nodes: Vec<(NodeId, String, Duration)>,
+    pub total: Duration,
+}
+
+impl std::fmt::Display for CriticalPath {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(f, "Critical path: {:.3?}", self.total)?;
+        for (id, label, time) in &self.nodes {
+            writeln!(f, "  {:>4} {:>10.3?}  {}", id.0, time, label)?;
+        }
+        Ok(())
+    }
+}
+
+pub struct TransferGraph {
+    pub name: String,
+    /// Streams nodes are spread over; each node goes to one on its device.
+    pub streams: Vec<Stream>,
+    /// Record timing events around every node, for `critical_path`.
+    pub timed: bool,
+    nodes: Vec<Node>,
+    schedule: Option<Schedule>,
+    events: Vec<Option<Event>>,
+    timing_events: Vec<(Event, Event)>,
+}
+
+impl TransferGraph {
+    pub fn new(name: impl Into<String>, streams: &[Stream]) -> Self {
+        Self {
+            name: name.into(),
+            streams: streams.to_vec(),
+            timed: false,
+            nodes: Vec::new(),
+            schedule: None,
+            events: Vec::new(),
+            timing_events: Vec::new(),
+        }
+    }
+
+    pub fn timed(mut self) -> Self {
+        self.timed = true;
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    pub fn add(&mut self, label: impl Into<String>, kind: NodeKind, deps: &[NodeId]) -> NodeId {
+        let id = self.nodes.len();
+        for dep in deps {
+            assert!(dep.0 < id, "node {} depends on later node {}", id, dep.0);
+        }
+        let mut deps = deps.iter().map(|d| d.0).collect::<Vec<_>>();
+        deps.sort();
+        deps.dedup();
+        self.nodes.push(Node {
+            label: label.into(),
+            kind,
+            deps,
+        });
+        self.schedule = None;
+        NodeId(id)
+    }
+
+    pub fn copy(&mut self, dst: &Buffer, src: &Buffer, deps: &[NodeId]) -> NodeId {
+        let label = format!(
+            "{:?} GPU{} -> {:?} GPU{} ({})",
+            src.address_space,
+            src.ctx.device_id,
+            dst.address_space,
+            dst.ctx.device_id,
+            ByteSize::from(src.size)
+        );
+        let kind = NodeKind::Copy {
+            dst: dst.clone(),
+            src: src.clone(),
+        };
+        self.add(label, kind, deps)
+    }
+
+    pub fn memset(&mut self, buf: &Buffer, value: u8, deps: &[NodeId]) -> NodeId {
+        let label = format!(
+            "memset {:?} GPU{} ({})",
+            buf.address_space,
+            buf.ctx.device_id,
+            ByteSize::from(buf.size)
+        );
+        let kind = NodeKind::Memset {
+            buf: buf.clone(),
+            value,
+        };
+        self.add(label, kind, deps)
+    }
+
+    pub fn host(
+        &mut self,
+        label: impl Into<String>,
+        f: impl FnMut() + Send + 'static,
+        deps: &[NodeId],
+    ) -> NodeId {
+        self.add(label, NodeKind::Host(Arc::new(Mutex::new(f))), deps)
+    }
+
+    pub fn label(&self, id: NodeId) -> &str {
+        &self.nodes[id.0].label
+    }
+
+    /// `deps` without those implied by another dependency.
+    fn reduced_deps(&self, ancestors: &[Vec<bool>], node: usize) -> Vec<usize> {
+        let deps = &self.nodes[node].deps;
+        deps.iter()
+            .copied()
+            .filter(|&d| !deps.iter().any(|&e| e != d && ancestors[e][d]))
+            .collect()
+    }
+
+    /// `ancestors[i][j]`: node `i` depends on node `j`, maybe indirectly.
+    fn ancestors(&self) -> Vec<Vec<bool>> {
+        let n = self.nodes.len();
+        let mut ancestors: Vec<Vec<bool>> = Vec::with_capacity(n);
+        for node in &self.nodes {
+            let mut row = vec![false; n];
+            for &d in &node.deps {
+                row[d] = true;
+                for (k, &ancestor) in ancestors[d].iter().enumerate() {
+                    row[k] |= ancestor;
+                }
+            }
+            ancestors.push(row);
+        }
+        ancestors
+    }
+
+    /// Assigns streams and events. A node continues the stream of one of its
+    /// dependencies when that dependency is the stream's latest node, else
+    /// takes an unused stream on its device, else the least loaded one. A
+    /// cross-stream dependency gets an event unless the stream already
+    /// waited on it, directly or through another event (tracked with a
+    /// vector clock per stream).
+    pub fn schedule(&self) -> Schedule {
+        let n_streams = self.streams.len();
+        let ancestors = self.ancestors();
+        let mut tails: Vec<Option<usize>> = vec![None; n_streams];
+        let mut lengths = vec![0; n_streams];
+        // clocks[s][t]: how many of stream t's nodes are known complete at
+        // the current end of stream s.
+        let mut clocks = vec![vec![0; n_streams]; n_streams];
+        let mut node_clocks: Vec<Vec<usize>> = Vec::with_capacity(self.nodes.len());
+        let mut positions = Vec::with_capacity(self.nodes.len());
+        let mut schedule = Schedule::default();
+
+        for (i, node) in self.nodes.iter().enumerate() {
+            let deps = self.reduced_deps(&ancestors, i);
+            let device_id = node.kind.device_id();
+            let candidates = (0..n_streams)
+                .filter(|&s| device_id.is_none_or(|d| self.streams[s].ctx.device_id == d))
+                .collect::<Vec<_>>();
+            let stream = candidates
+                .iter()
+                .copied()
+                .find(|&s| tails[s].is_some_and(|t| deps.contains(&t)))
+                .or_else(|| candidates.iter().copied().find(|&s| tails[s].is_none()))
+                .or_else(|| candidates.iter().copied().min_by_key(|&s| lengths[s]))
+                .unwrap_or_else(|| {
+                    panic!(
+                        "{}: no stream on GPU{} for node {} ({})",
+                        self.name,
+                        device_id.unwrap_or(-1),
+                        i,
+                        node.label
+                    )
+                });
+
+            let mut waits = Vec::new();
+            for d in deps {
+                let from = schedule.streams[d];
+                if from == stream || clocks[stream][from] >= positions[d] {
+                    continue;
+                }
+                waits.push(d);
+                for t in 0..n_streams {
+                    clocks[stream][t] = clocks[stream][t].max(node_clocks[d][t]);
+                }
+            }
+
+            lengths[stream] += 1;
+            clocks[stream][stream] = lengths[stream];
+            positions.push(lengths[stream]);
+            node_clocks.push(clocks[stream].clone());
+            tails[stream] = Some(i);
+            schedule.streams.push(stream);
+            schedule.waits.push(waits);
+        }
+        schedule
+    }
+
+    fn prepare(&mut self) {
+        if self.schedule.is_some() {
+            return;
+        }
+        self.destroy_events();
+        let schedule = self.schedule();
+        let recorded = schedule.recorded();
+        self.events = (0..self.nodes.len())
+            .map(|i| {
+                recorded
+                    .contains(&i)
+                    .then(|| self.streams[schedule.streams[i]].ctx.create_event())
+            })
+            .collect();
+        self.timing_events = if self.timed {
+            schedule
+                .streams
+                .iter()
+                .map(|&s| {
+                    let ctx = &self.streams[s].ctx;
+                    (ctx.create_timing_event(), ctx.create_timing_event())
+                })
+                .collect()
+        } else {
+            Vec::new()
+        };
+        log_debug!(
+            "{}: {} nodes on {} streams with {} events",
+            self.name,
+            self.nodes.len(),
+            self.streams.len(),
+            recorded.len()
+        );
+        self.schedule = Some(schedule);
+    }
+
+    /// Destroys the events of the previous schedule, if any.
+    fn destroy_events(&mut self) {
+        for event in self.events.drain(..).flatten() {
+            event.destroy();
+        }
+        for (start, end) in self.timing_events.drain(..) {
+            start.destroy();
+            end.destroy();
+        }
+    }
+
+    /// Issues every node asynchronously, scheduling the graph first if it
+    /// changed.
+    pub fn execute(&mut self) {
+        self.prepare();
+        let schedule = self.schedule.as_ref().unwrap();
+        for (i, node) in self.nodes.iter().enumerate() {
+            let stream = &self.streams[schedule.streams[i]];
+            for &d in &schedule.waits[i] {
+                stream.wait_for_event(self.events[d].as_ref().unwrap());
+            }
+            if let Some((start, _)) = self.timing_events.get(i) {
+                stream.record_event(start);
+            }
+            match &node.kind {
+                NodeKind::Copy { dst, src } => stream.memcpy_async(dst, src),
+                NodeKind::Memset { buf, value } => stream.memset_async(buf, *value),
+                NodeKind::Host(f) => launch_host_fn(stream, f),
+            }
+            if let Some((_, end)) = self.timing_events.get(i) {
+                stream.record_event(end);
+            }
+            if let Some(event) = &self.events[i] {
+                stream.record_event(event);
+            }
+        }
+    }
+
+    pub fn synchronize(&self) {
+        for stream in &self.streams {
+            stream.synchronize();
+        }
+    }
+
+    /// GPU time of each node in the last execution, if `timed`.
+    pub fn node_times(&self) -> Vec<Duration> {
+        self.timing_events
+            .iter()
+            .map(|(start, end)| end.elapsed_since(start))
+            .collect()
+    }
+
+    /// The longest chain of the last execution by node time, following
+    /// dependencies and stream order. `None` unless `timed` and executed.
+    pub fn critical_path(&self) -> Option<CriticalPath> {
+        let schedule = self.schedule.as_ref()?;
+        let times = self.node_times();
+        if times.len() != self.nodes.len() {
+            return None;
+        }
+        Some(self.longest_path(schedule, &times))
+    }
+
+    /// The longest chain through `schedule` given each node's time.
+    fn longest_path(&self, schedule: &Schedule, times: &[Duration]) -> CriticalPath {
+        let mut finish = vec![Duration::ZERO; self.nodes.len()];
+        let mut previous: Vec<Option<usize>> = vec![None; self.nodes.len()];
+        let mut tails: Vec<Option<usize>> = vec![None; self.streams.len()];
+        for (i, node) in self.nodes.iter().enumerate() {
+            let stream = schedule.streams[i];
+            let before = node.deps.iter().copied().chain(tails[stream]);
+            previous[i] = before.max_by_key(|&p| finish[p]);
+            finish[i] = previous[i].map_or(Duration::ZERO, |p| finish[p]) + times[i];
+            tails[stream] = Some(i);
+        }
+        let mut last = (0..self.nodes.len()).max_by_key(|&i| finish[i]);
+        let total = last.map_or(Duration::ZERO, |i| finish[i]);
+        let mut nodes = Vec::new();
+        while let Some(i) = last {
+            nodes.push((NodeId(i), self.nodes[i].label.clone(), times[i]));
+            last = previous[i];
+        }
+        nodes.reverse();
+        CriticalPath { nodes, total }
+    }
+}
+
+unsafe extern "C" fn run_host_fn(data: *mut std::ffi::c_void) {
+    let f = unsafe { Box::from_raw(data as *mut HostFn) };
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        (f.lock().unwrap())();
+    }));
+    if result.is_err() {
+        log_error!("host node panicked");
+    }
+}
+
+fn launch_host_fn(stream: &Stream, f: &HostFn) {
+    stream.ctx.set_current();
+    let data = Box::into_raw(Box::new(f.clone()));
+    unsafe { sys::cuLaunchHostFunc(stream.stream, Some(run_host_fn), data as *mut _) }
+        .result()
+        .unwrap();
+}
+
+impl Drop for TransferGraph {
+    fn drop(&mut self) {
+        self.destroy_events();
+    }
+}
+
+impl Scenario for TransferGraph {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn bytes(&self) -> ByteSize {
+        self.nodes
+            .iter()
+            .map(|n| match &n.kind {
+                NodeKind::Copy { src, .. } => ByteSize::from(src.size),
+                _ => ByteSize(0),
+            })
+            .fold(ByteSize(0), |a, b| a + b)
+    }
+
+    fn copies(&self) -> Vec<CopyInfo> {
+        self.nodes
+            .iter()
+            .filter_map(|n| match &n.kind {
+                NodeKind::Copy { dst, src } => Some(CopyInfo {
+                    src: src.address_space.clone(),
+                    src_device: src.ctx.device_id,
+                    dst: dst.address_space.clone(),
+                    dst_device: dst.ctx.device_id,
+                }),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn copy_times(&self) -> Vec<Duration> {
+        self.node_times()
+            .into_iter()
+            .zip(&self.nodes)
+            .filter(|(_, n)| matches!(n.kind, NodeKind::Copy { .. }))
+            .map(|(t, _)| t)
+            .collect()
+    }
+
+    fn run(&mut self) {
+        self.execute();
+    }
+
+    fn sync(&mut self) {
+        self.synchronize();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Streams and buffers with fake handles, enough to schedule a graph
+    /// without a GPU.
+    fn context(device_id: i32) -> Context {
+        Context {
+            ctx: std::ptr::null_mut(),
+            device_id,
+        }
+    }
+
+    fn graph(devices: &[i32]) -> TransferGraph {
+        let streams = devices
+            .iter()
+            .map(|&d| Stream {
+                ctx: context(d),
+                stream: std::ptr::null_mut(),
+            })
+            .collect::<Vec<_>>();
+        TransferGraph::new("test", &streams)
+    }
+
+    fn buffer(device_id: i32) -> Buffer {
+        Buffer {
+            ctx: context(device_id),
+            size: 1 << 20,
+            address_space: AddressSpace::Device,
+            addr: 0,
+            pages: HugePages::None,
+            is_view: false,
+        }
+    }
+
+    fn node(g: &mut TransferGraph, device_id: i32, deps: &[NodeId]) -> NodeId {
+        g.memset(&buffer(device_id), 0, deps)
+    }
+
+    fn waits(schedule: &Schedule) -> Vec<Vec<usize>> {
+        schedule.waits.clone()
+    }
+
+    #[test]
+    fn chain_stays_on_one_stream() {
+        let mut g = graph(&[0, 0]);
+        let a = node(&mut g, 0, &[]);
+        let b = node(&mut g, 0, &[a]);
+        node(&mut g, 0, &[b]);
+        let schedule = g.schedule();
+        assert_eq!(schedule.streams, [0, 0, 0]);
+        assert!(schedule.recorded().is_empty());
+    }
+
+    #[test]
+    fn chain_across_devices() {
+        let mut g = graph(&[0, 1, 2]);
+        let a = node(&mut g, 0, &[]);
+        let b = node(&mut g, 1, &[a]);
+        node(&mut g, 2, &[b]);
+        let schedule = g.schedule();
+        assert_eq!(schedule.streams, [0, 1, 2]);
+        assert_eq!(waits(&schedule), [vec![], vec![0], vec![1]]);
+    }
+
+    #[test]
+    fn diamond() {
+        let mut g = graph(&[0, 1, 2]);
+        let a = node(&mut g, 0, &[]);
+        let b = node(&mut g, 1, &[a]);
+        let c = node(&mut g, 2, &[a]);
+        node(&mut g, 0, &[b, c, a]);
+        let schedule = g.schedule();
+        assert_eq!(schedule.streams, [0, 1, 2, 0]);
+        // The join doesn't wait on `a`: it is implied, and on its stream.
+        assert_eq!(waits(&schedule), [vec![], vec![0], vec![0], vec![1, 2]]);
+        assert_eq!(schedule.recorded(), [0, 1, 2]);
+    }
+
+    #[test]
+    fn fan_in() {
+        let mut g = graph(&[0, 1, 2, 3]);
+        let sources = (0..3).map(|d| node(&mut g, d, &[])).collect::<Vec<_>>();
+        node(&mut g, 3, &sources);
+        let schedule = g.schedule();
+        assert_eq!(schedule.streams, [0, 1, 2, 3]);
+        assert_eq!(waits(&schedule)[3], [0, 1, 2]);
+    }
+
+    #[test]
+    fn fan_out_on_one_device_uses_free_streams() {
+        let mut g = graph(&[0, 0, 0]);
+        let a = node(&mut g, 0, &[]);
+        let b = node(&mut g, 0, &[a]);
+        node(&mut g, 0, &[a]);
+        node(&mut g, 0, &[a]);
+        node(&mut g, 0, &[b]);
+        let schedule = g.schedule();
+        assert_eq!(schedule.streams, [0, 0, 1, 2, 0]);
+        assert_eq!(waits(&schedule), [vec![], vec![], vec![0], vec![0], vec![]]);
+    }
+
+    #[test]
+    fn redundant_dependency_adds_no_event() {
+        let mut g = graph(&[0, 1]);
+        let a = node(&mut g, 0, &[]);
+        let b = node(&mut g, 1, &[a]);
+        node(&mut g, 1, &[a, b]);
+        let schedule = g.schedule();
+        assert_eq!(waits(&schedule), [vec![], vec![0], vec![]]);
+    }
+
+    #[test]
+    fn earlier_wait_covers_dependency() {
+        // `c` needs `a`, which its stream already saw complete by waiting
+        // on `x`, queued after `a` on stream 0.
+        let mut g = graph(&[0, 1]);
+        let a = node(&mut g, 0, &[]);
+        let x = node(&mut g, 0, &[a]);
+        node(&mut g, 1, &[x]);
+        node(&mut g, 1, &[a]);
+        let schedule = g.schedule();
+        assert_eq!(schedule.streams, [0, 0, 1, 1]);
+        assert_eq!(waits(&schedule), [vec![], vec![], vec![1], vec![]]);
+        assert_eq!(schedule.recorded(), [1]);
+    }
+
+    #[test]
+    fn host_nodes_follow_their_dependency() {
+        let mut g = graph(&[0, 1]);
+        let a = node(&mut g, 0, &[]);
+        let b = node(&mut g, 1, &[]);
+        g.host("done", || {}, &[a, b]);
+        let schedule = g.schedule();
+        assert_eq!(schedule.streams[2], 0);
+        assert_eq!(schedule.waits[2], [1]);
+    }
+
+    #[test]
+    #[should_panic(expected = "depends on later node")]
+    fn rejects_forward_dependency() {
+        let mut g = graph(&[0]);
+        node(&mut g, 0, &[NodeId(0)]);
+    }
+
+    #[test]
+    #[should_panic(expected = "no stream on GPU1")]
+    fn rejects_missing_device() {
+        let mut g = graph(&[0]);
+        node(&mut g, 1, &[]);
+        g.schedule();
+    }
+
+    #[test]
+    fn critical_path() {
+        let mut g = graph(&[0, 1, 2]);
+        let a = node(&mut g, 0, &[]);
+        let b = node(&mut g, 1, &[a]);
+        let c = node(&mut g, 2, &[a]);
+        let d = node(&mut g, 0, &[b, c]);
+        let ms = Duration::from_millis;
+        let schedule = g.schedule();
+        let path = g.longest_path(&schedule, &[ms(1), ms(2), ms(5), ms(1)]);
+        assert_eq!(path.total, ms(7));
+        assert_eq!(
+            path.nodes.iter().map(|(id, _, _)| *id).collect::<Vec<_>>(),
+            [a, c, d]
+        );
+        assert!(path.to_string().starts_with("Critical path: 7.000ms"));
+
+        // Stream order counts too: `e` has no dependencies but queues
+        // behind `d` on stream 0.
+        let e = node(&mut g, 0, &[]);
+        let schedule = g.schedule();
+        assert_eq!(schedule.streams[e.0], 0);
+        let path = g.longest_path(&schedule, &[ms(1), ms(2), ms(5), ms(1), ms(1)]);
+        assert_eq!(path.total, ms(8));
+        assert_eq!(path.nodes.last().unwrap().0, e);
+    }
+}
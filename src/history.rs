@@ -0,0 +1,390 @@
+//! Benchmark history in a local CSV file, and comparison of a run against a
+//! baseline run.
+//!
+//! The file has the same columns as `report::Format::Csv`; a run is identified
+//! by its `timestamp`.
+
+use std::io::Write;
+use std::path::Path;
+
+use crate::bench::{BenchResult, glob_match};
+use crate::report::{CSV_HEADER, Environment, csv_row, parse_csv_line};
+
+/// One sample as stored in the history file.
+#[derive(Debug, Clone)]
+pub struct HistoryRow {
+    pub timestamp: u64,
+    pub hostname: String,
+    pub driver_version: i32,
+    pub devices: String,
+    pub scenario: String,
+    pub copies: String,
+    /// Bytes moved per iteration, summed over all copies.
+    pub total_bytes: u64,
+    pub iteration: usize,
+    pub copy_time_s: f64,
+    pub sync_time_s: f64,
+    pub total_time_s: f64,
+    pub bandwidth_gb_s: f64,
+    /// Per-direction bandwidths, e.g. `H2D=12.3456;D2H=11.0000`; may be empty.
+    pub directions: String,
+}
+
+impl HistoryRow {
+    pub fn parse(line: &str) -> Option<Self> {
+        let f = parse_csv_line(line);
+        // Older files lack the trailing direction column.
+        if f.len() != 12 && f.len() != 13 {
+            return None;
+        }
+        Some(Self {
+            timestamp: f[0].parse().ok()?,
+            hostname: f[1].clone(),
+            driver_version: f[2].parse().ok()?,
+            devices: f[3].clone(),
+            scenario: f[4].clone(),
+            copies: f[5].clone(),
+            total_bytes: f[6].parse().ok()?,
+            iteration: f[7].parse().ok()?,
+            copy_time_s: f[8].parse().ok()?,
+            sync_time_s: f[9].parse().ok()?,
+            total_time_s: f[10].parse().ok()?,
+            bandwidth_gb_s: f[11].parse().ok()?,
+            directions: f.get(12).cloned().unwrap_or_default(),
+        })
+    }
+
+    pub fn from_results(env: &Environment, results: &[BenchResult]) -> Vec<Self> {
+        results
+            .iter()
+            .flat_map(|r| r.samples.iter().map(move |s| csv_row(env, r, s)))
+            .filter_map(|line| Self::parse(&line))
+            .collect()
+    }
+
+    /// What a sample is compared by across runs.
+    fn key(&self) -> (&str, &str, u64) {
+        (&self.scenario, &self.copies, self.total_bytes)
+    }
+}
+
+/// Appends a run to the history file, writing the header if the file is new.
+pub fn append(path: &Path, env: &Environment, results: &[BenchResult]) -> std::io::Result<()> {
+    let new = !path.exists() || std::fs::metadata(path)?.len() == 0;
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+    if new {
+        writeln!(file, "{}", CSV_HEADER)?;
+    }
+    for result in results {
+        for sample in &result.samples {
+            writeln!(file, "{}", csv_row(env, result, sample))?;
+        }
+    }
+    Ok(())
+}
+
+/// Loads every row of the history file, skipping the header and bad lines.
+pub fn load(path: &Path) -> std::io::Result<Vec<HistoryRow>> {
+    Ok(std::fs::read_to_string(path)?
+        .lines()
+        .filter_map(HistoryRow::parse)
+        .collect())
+}
+
+/// Distinct run timestamps in the history, oldest first.
+pub fn runs(rows: &[HistoryRow]) -> Vec<u64> {
+    let mut runs = rows.iter().map(|r| r.timestamp).collect::<Vec<_>>();
+    runs.sort();
+    runs.dedup();
+    runs
+}
+
+/// Resolves a run selector: a timestamp, `latest`, or `previous` (the one
+/// before latest).
+pub fn select_run(rows: &[HistoryRow], selector: &str) -> Option<u64> {
+    let runs = runs(rows);
+    match selector {
+        "latest" => runs.last().copied(),
+        "previous" => runs.len().checked_sub(2).map(|i| runs[i]),
+        ts => ts.parse().ok().filter(|ts| runs.contains(ts)),
+    }
+}
+
+pub fn run_rows(rows: &[HistoryRow], timestamp: u64) -> Vec<HistoryRow> {
+    rows.iter()
+        .filter(|r| r.timestamp == timestamp)
+        .cloned()
+        .collect()
+}
+
+/// Allowed relative bandwidth drop, per scenario glob.
+#[derive(Debug, Clone)]
+pub struct Thresholds {
+    pub default: f64,
+    /// `(glob, threshold)`; the first matching glob wins.
+    pub scenarios: Vec<(String, f64)>,
+}
+
+impl Default for Thresholds {
+    fn default() -> Self {
+        Self {
+            default: 0.05,
+            scenarios: Vec::new(),
+        }
+    }
+}
+
+impl Thresholds {
+    pub fn for_scenario(&self, scenario: &str) -> f64 {
+        self.scenarios
+            .iter()
+            .find(|(glob, _)| glob_match(glob, scenario))
+            .map_or(self.default, |(_, t)| *t)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Unchanged,
+    Improved,
+    Regressed,
+    /// Only in the baseline.
+    Missing,
+    /// Only in the current run.
+    New,
+}
+
+#[derive(Debug, Clone)]
+pub struct Comparison {
+    pub scenario: String,
+    pub copies: String,
+    pub total_bytes: u64,
+    pub baseline_gb_s: f64,
+    pub current_gb_s: f64,
+    /// Relative change of the median bandwidth.
+    pub change: f64,
+    /// Relative spread below which changes are treated as noise.
+    pub noise: f64,
+    pub threshold: f64,
+    pub verdict: Verdict,
+}
+
+pub fn median(values: &[f64]) -> f64 {
+    if values.is_empty() {
+        return 0.0;
+    }
+    let mut v = values.to_vec();
+    v.sort_by(f64::total_cmp);
+    let mid = v.len() / 2;
+    if v.len().is_multiple_of(2) {
+        (v[mid - 1] + v[mid]) / 2.0
+    } else {
+        v[mid]
+    }
+}
+
+/// Median absolute deviation relative to the median.
+pub fn relative_mad(values: &[f64]) -> f64 {
+    let m = median(values);
+    if m == 0.0 {
+        return 0.0;
+    }
+    let deviations = values.iter().map(|v| (v - m).abs()).collect::<Vec<_>>();
+    median(&deviations) / m
+}
+
+/// Compares median bandwidth per scenario. A change only counts when it is
+/// beyond both the scenario's threshold and twice the larger relative MAD of
+/// the two runs.
+pub fn compare(
+    baseline: &[HistoryRow],
+    current: &[HistoryRow],
+    thresholds: &Thresholds,
+) -> Vec<Comparison> {
+    let mut keys = Vec::new();
+    for row in baseline.iter().chain(current) {
+        if !keys.contains(&row.key()) {
+            keys.push(row.key());
+        }
+    }
+
+    keys.into_iter()
+        .map(|key| {
+            let bandwidths = |rows: &[HistoryRow]| {
+                rows.iter()
+                    .filter(|r| r.key() == key)
+                    .map(|r| r.bandwidth_gb_s)
+                    .collect::<Vec<_>>()
+            };
+            let base = bandwidths(baseline);
+            let cur = bandwidths(current);
+            let threshold = thresholds.for_scenario(key.0);
+            let baseline_gb_s = median(&base);
+            let current_gb_s = median(&cur);
+            let noise = 2.0 * relative_mad(&base).max(relative_mad(&cur));
+            let change = if baseline_gb_s > 0.0 {
+                current_gb_s / baseline_gb_s - 1.0
+            } else {
+                0.0
+            };
+            let verdict = if base.is_empty() {
+                Verdict::New
+            } else if cur.is_empty() {
+                Verdict::Missing
+            } else if change.abs() <= threshold.max(noise) {
+                Verdict::Unchanged
+            } else if change < 0.0 {
+                Verdict::Regressed
+            } else {
+                Verdict::Improved
+            };
+            Comparison {
+                scenario: key.0.to_string(),
+                copies: key.1.to_string(),
+                total_bytes: key.2,
+                baseline_gb_s,
+                current_gb_s,
+                change,
+                noise,
+                threshold,
+                verdict,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn row(scenario: &str, bandwidth_gb_s: f64) -> HistoryRow {
+        HistoryRow {
+            timestamp: 1,
+            hostname: String::from("host"),
+            driver_version: 12080,
+            devices: String::from("0:GPU"),
+            scenario: scenario.to_string(),
+            copies: String::from("Pinned:0->Device:0"),
+            total_bytes: 1 << 30,
+            iteration: 0,
+            copy_time_s: 0.0,
+            sync_time_s: 0.0,
+            total_time_s: 0.0,
+            bandwidth_gb_s,
+            directions: String::new(),
+        }
+    }
+
+    fn rows(scenario: &str, bandwidths: &[f64]) -> Vec<HistoryRow> {
+        bandwidths.iter().map(|&bw| row(scenario, bw)).collect()
+    }
+
+    fn verdict(baseline: &[f64], current: &[f64], threshold: f64) -> Comparison {
+        let thresholds = Thresholds {
+            default: threshold,
+            scenarios: Vec::new(),
+        };
+        let mut c = compare(&rows("h2d", baseline), &rows("h2d", current), &thresholds);
+        assert_eq!(c.len(), 1);
+        c.remove(0)
+    }
+
+    #[test]
+    fn median_and_mad() {
+        assert_eq!(median(&[]), 0.0);
+        assert_eq!(median(&[3.0]), 3.0);
+        assert_eq!(median(&[3.0, 1.0, 2.0]), 2.0);
+        assert_eq!(median(&[4.0, 1.0, 2.0, 3.0]), 2.5);
+        assert_eq!(relative_mad(&[]), 0.0);
+        assert_eq!(relative_mad(&[5.0]), 0.0);
+        assert_eq!(relative_mad(&[5.0, 5.0, 5.0]), 0.0);
+        assert_eq!(relative_mad(&[0.0, 0.0]), 0.0);
+        // median 10, deviations 1, 0, 1 -> MAD 1
+        assert_eq!(relative_mad(&[9.0, 10.0, 11.0]), 0.1);
+    }
+
+    #[test]
+    fn empty_baseline_is_new() {
+        let c = verdict(&[], &[10.0, 10.0], 0.05);
+        assert_eq!(c.verdict, Verdict::New);
+        assert_eq!(c.change, 0.0);
+
+        let c = verdict(&[10.0], &[], 0.05);
+        assert_eq!(c.verdict, Verdict::Missing);
+        assert!(compare(&[], &[], &Thresholds::default()).is_empty());
+    }
+
+    #[test]
+    fn single_sample() {
+        let c = verdict(&[10.0], &[9.0], 0.05);
+        assert_eq!(c.verdict, Verdict::Regressed);
+        assert_eq!(c.noise, 0.0);
+        assert!((c.change + 0.1).abs() < 1e-9);
+    }
+
+    #[test]
+    fn zero_mad_uses_threshold() {
+        let c = verdict(&[10.0; 5], &[9.6; 5], 0.05);
+        assert_eq!(c.noise, 0.0);
+        assert_eq!(c.verdict, Verdict::Unchanged);
+        assert_eq!(
+            verdict(&[10.0; 5], &[9.4; 5], 0.05).verdict,
+            Verdict::Regressed
+        );
+        assert_eq!(
+            verdict(&[10.0; 5], &[10.6; 5], 0.05).verdict,
+            Verdict::Improved
+        );
+    }
+
+    #[test]
+    fn threshold_boundaries() {
+        // 8% drop: a regression at 5%, within a 10% threshold.
+        assert_eq!(verdict(&[10.0], &[9.2], 0.05).verdict, Verdict::Regressed);
+        assert_eq!(verdict(&[10.0], &[9.2], 0.10).verdict, Verdict::Unchanged);
+        // Exactly at the threshold counts as unchanged.
+        assert_eq!(verdict(&[8.0], &[7.0], 0.125).verdict, Verdict::Unchanged);
+    }
+
+    #[test]
+    fn noise_widens_threshold() {
+        // Baseline MAD is 10% of the median, so changes up to 20% are noise.
+        let noisy = [9.0, 10.0, 11.0];
+        let c = verdict(&noisy, &[8.5, 8.5, 8.5], 0.05);
+        assert!((c.noise - 0.2).abs() < 1e-9);
+        assert_eq!(c.verdict, Verdict::Unchanged);
+        assert_eq!(
+            verdict(&noisy, &[7.5, 7.5, 7.5], 0.05).verdict,
+            Verdict::Regressed
+        );
+    }
+
+    #[test]
+    fn per_scenario_thresholds() {
+        let thresholds = Thresholds {
+            default: 0.05,
+            scenarios: vec![(String::from("p2p*"), 0.2), (String::from("*"), 0.01)],
+        };
+        assert_eq!(thresholds.for_scenario("p2p-0-1"), 0.2);
+        assert_eq!(thresholds.for_scenario("h2d"), 0.01);
+
+        let mut baseline = rows("p2p-0-1", &[10.0]);
+        baseline.extend(rows("h2d", &[10.0]));
+        let mut current = rows("p2p-0-1", &[9.0]);
+        current.extend(rows("h2d", &[9.0]));
+        let verdicts = compare(&baseline, &current, &thresholds)
+            .into_iter()
+            .map(|c| (c.scenario, c.verdict))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            verdicts,
+            [
+                (String::from("p2p-0-1"), Verdict::Unchanged),
+                (String::from("h2d"), Verdict::Regressed),
+            ]
+        );
+    }
+}
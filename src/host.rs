@@ -0,0 +1,270 @@
+use crate::{affinity, log_warn};
+
+/// Page backing for host buffers (`AddressSpace::Cpu` and `AddressSpace::Registered`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HugePages {
+    /// Anonymous mapping with base (usually 4 KB) pages.
+    #[default]
+    None,
+    /// Anonymous mapping with `madvise(MADV_HUGEPAGE)`.
+    Transparent,
+    /// Explicit hugetlbfs pages (`MAP_HUGETLB | MAP_HUGE_2MB`).
+    HugeTlb2M,
+    /// Explicit hugetlbfs pages (`MAP_HUGETLB | MAP_HUGE_1GB`).
+    HugeTlb1G,
+}
+
+const HUGE_2M: usize = 2 * 1024 * 1024;
+const HUGE_1G: usize = 1024 * 1024 * 1024;
+
+impl HugePages {
+    /// Size of the pages backing the allocation.
+    pub fn page_size(self) -> usize {
+        match self {
+            HugePages::None => base_page_size(),
+            HugePages::Transparent | HugePages::HugeTlb2M => HUGE_2M,
+            HugePages::HugeTlb1G => HUGE_1G,
+        }
+    }
+
+    /// Stride at which a first touch faults in every page. Transparent huge
+    /// pages aren't guaranteed, so they're touched at the base page size.
+    pub fn touch_stride(self) -> usize {
+        match self {
+            HugePages::None | HugePages::Transparent => base_page_size(),
+            _ => self.page_size(),
+        }
+    }
+
+    /// Next thing to try when this kind of page can't be had.
+    fn fallback(self) -> Option<HugePages> {
+        match self {
+            HugePages::HugeTlb1G => Some(HugePages::HugeTlb2M),
+            HugePages::HugeTlb2M => Some(HugePages::Transparent),
+            HugePages::Transparent => Some(HugePages::None),
+            HugePages::None => None,
+        }
+    }
+}
+
+/// How host pages get faulted in before the buffer is handed out, so page
+/// fault cost can be kept out of (or deliberately put into) transfer timings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Prefault {
+    /// Leave pages to be faulted in by whoever touches them first.
+    #[default]
+    None,
+    /// `MAP_POPULATE` at mapping time.
+    Populate,
+    /// `madvise(MADV_WILLNEED)` after mapping.
+    WillNeed,
+    /// Write one byte per page from the calling thread.
+    Touch,
+    /// Write one byte per page from `threads` threads, optionally bound to the
+    /// CPUs of `numa_node` so first-touch places the pages there.
+    ParallelTouch {
+        threads: usize,
+        numa_node: Option<usize>,
+    },
+}
+
+/// Options for host memory behind `Cpu` and `Registered` buffers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct HostOptions {
+    pub pages: HugePages,
+    pub prefault: Prefault,
+}
+
+pub fn base_page_size() -> usize {
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
+
+/// Length actually mapped for a buffer of `size` bytes.
+fn mapped_len(size: usize, pages: HugePages) -> usize {
+    size.max(1).div_ceil(pages.page_size()) * pages.page_size()
+}
+
+fn try_alloc(size: usize, pages: HugePages, populate: bool) -> Option<*mut libc::c_void> {
+    let mut flags = libc::MAP_PRIVATE | libc::MAP_ANONYMOUS;
+    match pages {
+        HugePages::None | HugePages::Transparent => {}
+        HugePages::HugeTlb2M => flags |= libc::MAP_HUGETLB | libc::MAP_HUGE_2MB,
+        HugePages::HugeTlb1G => flags |= libc::MAP_HUGETLB | libc::MAP_HUGE_1GB,
+    }
+    // THP has to be requested before the pages are populated.
+    if populate && pages != HugePages::Transparent {
+        flags |= libc::MAP_POPULATE;
+    }
+    let len = mapped_len(size, pages);
+    let ptr = unsafe {
+        libc::mmap(
+            std::ptr::null_mut(),
+            len,
+            libc::PROT_READ | libc::PROT_WRITE,
+            flags,
+            -1,
+            0,
+        )
+    };
+    if ptr == libc::MAP_FAILED {
+        return None;
+    }
+    if pages == HugePages::Transparent
+        && unsafe { libc::madvise(ptr, len, libc::MADV_HUGEPAGE) } != 0
+    {
+        unsafe { libc::munmap(ptr, len) };
+        return None;
+    }
+    Some(ptr)
+}
+
+/// Allocates `size` bytes of host memory, falling back to smaller pages when
+/// the requested kind is unavailable, then prefaults it. Returns the pointer
+/// and the pages obtained.
+pub fn alloc(size: usize, options: &HostOptions) -> (u64, HugePages) {
+    try_alloc_host(size, options)
+        .unwrap_or_else(|| panic!("failed to allocate {} bytes of host memory", size))
+}
+
+/// Like `alloc`, but returns `None` if no kind of page can be mapped.
+pub fn try_alloc_host(size: usize, options: &HostOptions) -> Option<(u64, HugePages)> {
+    let populate = options.prefault == Prefault::Populate;
+    let mut attempt = Some(options.pages);
+    while let Some(candidate) = attempt {
+        if let Some(ptr) = try_alloc(size, candidate, populate) {
+            if candidate != options.pages {
+                log_warn!(
+                    "{:?} pages unavailable for {} bytes, fell back to {:?}",
+                    options.pages,
+                    size,
+                    candidate
+                );
+            }
+            let addr = ptr as u64;
+            if populate && candidate == HugePages::Transparent {
+                prefault(addr, size, candidate, Prefault::Touch);
+            } else {
+                prefault(addr, size, candidate, options.prefault);
+            }
+            return Some((addr, candidate));
+        }
+        attempt = candidate.fallback();
+    }
+    None
+}
+
+pub fn free(addr: u64, size: usize, pages: HugePages) {
+    unsafe { libc::munmap(addr as *mut libc::c_void, mapped_len(size, pages)) };
+}
+
+/// Faults in the pages of an existing host allocation. `Populate` only has an
+/// effect at mapping time and is a no-op here.
+pub fn prefault(addr: u64, size: usize, pages: HugePages, prefault: Prefault) {
+    let stride = pages.touch_stride();
+    match prefault {
+        Prefault::None | Prefault::Populate => {}
+        Prefault::WillNeed => unsafe {
+            libc::madvise(
+                addr as *mut libc::c_void,
+                mapped_len(size, pages),
+                libc::MADV_WILLNEED,
+            );
+        },
+        Prefault::Touch => touch(addr, size, stride),
+        Prefault::ParallelTouch { threads, numa_node } => {
+            let cpus = numa_node.map(numa_node_cpus);
+            if let (Some(node), Some([])) = (numa_node, cpus.as_deref()) {
+                log_warn!("no CPUs known for NUMA node {}; touching unbound", node);
+            }
+            let cpus = cpus.filter(|cpus| !cpus.is_empty());
+            let num_pages = size.div_ceil(stride);
+            let pages_per_thread = num_pages.div_ceil(threads.max(1));
+            std::thread::scope(|s| {
+                for chunk in 0..threads.max(1) {
+                    let start = (chunk * pages_per_thread * stride).min(size);
+                    let end = ((chunk + 1) * pages_per_thread * stride).min(size);
+                    let cpus = cpus.as_deref();
+                    s.spawn(move || {
+                        if let Some(cpus) = cpus
+                            && let Err(e) = affinity::bind_current_thread(cpus)
+                        {
+                            log_warn!("failed to bind thread to cpus {:?}: {}", cpus, e);
+                        }
+                        touch(addr + start as u64, end - start, stride);
+                    });
+                }
+            });
+        }
+    }
+}
+
+/// Writes one byte at the start of every `stride` bytes.
+fn touch(addr: u64, size: usize, stride: usize) {
+    for (idx, offset) in (0..size).step_by(stride).enumerate() {
+        let page_data = (addr as *mut u8).wrapping_add(offset);
+        unsafe { page_data.write_volatile(idx as u8) };
+    }
+}
+
+/// CPUs local to a NUMA node, per `/sys/devices/system/node/node<N>/cpulist`.
+pub fn numa_node_cpus(node: usize) -> Vec<usize> {
+    std::fs::read_to_string(format!("/sys/devices/system/node/node{node}/cpulist"))
+        .ok()
+        .and_then(|s| parse_cpu_list(&s).ok())
+        .unwrap_or_default()
+}
+
+/// Parses a kernel CPU list such as `0-7,16-23`. Reversed ranges, CPUs a
+/// `cpu_set_t` can't hold and anything that isn't a CPU number are errors.
+pub fn parse_cpu_list(s: &str) -> Result<Vec<usize>, String> {
+    let cpu = |s: &str| {
+        let cpu = s
+            .trim()
+            .parse::<usize>()
+            .map_err(|_| format!("invalid CPU {:?}", s))?;
+        if cpu >= affinity::MAX_CPUS {
+            return Err(format!(
+                "CPU {} is out of range (max {})",
+                cpu,
+                affinity::MAX_CPUS - 1
+            ));
+        }
+        Ok(cpu)
+    };
+    let mut cpus = Vec::new();
+    for range in s.trim().split(',').filter(|r| !r.is_empty()) {
+        match range.split_once('-') {
+            Some((lo, hi)) => {
+                let (lo, hi) = (cpu(lo)?, cpu(hi)?);
+                if lo > hi {
+                    return Err(format!("CPU range {:?} is reversed", range));
+                }
+                cpus.extend(lo..=hi);
+            }
+            None => cpus.push(cpu(range)?),
+        }
+    }
+    Ok(cpus)
+}
+
+/// Number of explicit huge pages currently free in the system pool, per
+/// `/sys/kernel/mm/hugepages`. `None` for base or transparent pages.
+pub fn free_huge_pages(pages: HugePages) -> Option<usize> {
+    let dir = match pages {
+        HugePages::HugeTlb2M => "hugepages-2048kB",
+        HugePages::HugeTlb1G => "hugepages-1048576kB",
+        _ => return None,
+    };
+    std::fs::read_to_string(format!("/sys/kernel/mm/hugepages/{dir}/free_hugepages"))
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+}
+
+/// Whether transparent huge pages can be requested with `madvise`.
+pub fn transparent_huge_pages_enabled() -> bool {
+    std::fs::read_to_string("/sys/kernel/mm/transparent_hugepage/enabled")
+        .map(|s| !s.contains("[never]"))
+        .unwrap_or(false)
+}
@@ -0,0 +1,140 @@
+//! Per-operation latency measurements for small copies, round trips, event
+//! ping-pong and stream synchronization. Every operation is timed on its own
+//! so results can be reported as percentiles.
+
+use std::time::{Duration, Instant};
+
+use crate::*;
+
+#[derive(Debug, Clone)]
+pub struct LatencyStats {
+    pub name: String,
+    pub samples: usize,
+    pub min: Duration,
+    pub p50: Duration,
+    pub p90: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+}
+
+impl LatencyStats {
+    pub fn from_samples(name: impl Into<String>, mut samples: Vec<Duration>) -> Self {
+        samples.sort();
+        Self {
+            name: name.into(),
+            samples: samples.len(),
+            min: percentile(&samples, 0.0),
+            p50: percentile(&samples, 50.0),
+            p90: percentile(&samples, 90.0),
+            p99: percentile(&samples, 99.0),
+            max: percentile(&samples, 100.0),
+        }
+    }
+}
+
+/// Nearest-rank percentile of already sorted samples.
+pub fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    let rank = (p / 100.0 * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+/// Untimed operations run before each measurement, so first-use costs
+/// (lazy module loading, page table setup, cold caches) stay out of it.
+pub const WARMUP: usize = 10;
+
+/// Runs `f` `WARMUP` times and waits for `stream` to drain, then times each
+/// of `iters` further calls.
+fn time(stream: &Stream, iters: usize, mut f: impl FnMut()) -> Vec<Duration> {
+    for _ in 0..WARMUP {
+        f();
+    }
+    stream.synchronize();
+    (0..iters)
+        .map(|_| {
+            let t0 = Instant::now();
+            f();
+            t0.elapsed()
+        })
+        .collect()
+}
+
+/// Time to issue one `memcpy_async`, with calls issued back to back and a
+/// single synchronize at the end (API overhead, not completion).
+pub fn memcpy_issue(stream: &Stream, dst: &Buffer, src: &Buffer, iters: usize) -> LatencyStats {
+    let samples = time(stream, iters, || stream.memcpy_async(dst, src));
+    stream.synchronize();
+    LatencyStats::from_samples(
+        format!(
+            "memcpy_async issue {:?} -> {:?}",
+            src.address_space, dst.address_space
+        ),
+        samples,
+    )
+}
+
+/// Host -> device, device -> host, then synchronize.
+pub fn round_trip(stream: &Stream, host: &Buffer, dev: &Buffer, iters: usize) -> LatencyStats {
+    let samples = time(stream, iters, || {
+        stream.memcpy_async(dev, host);
+        stream.memcpy_async(host, dev);
+        stream.synchronize();
+    });
+    LatencyStats::from_samples(
+        format!(
+            "round trip {:?} <-> {:?}",
+            host.address_space, dev.address_space
+        ),
+        samples,
+    )
+}
+
+/// `a` records, `b` waits and records back, `a` waits and synchronizes. The
+/// streams may be on different devices.
+pub fn event_ping_pong(a: &Stream, b: &Stream, iters: usize) -> LatencyStats {
+    let ping = a.ctx.create_event();
+    let pong = b.ctx.create_event();
+    let samples = time(a, iters, || {
+        a.record_event(&ping);
+        b.wait_for_event(&ping);
+        b.record_event(&pong);
+        a.wait_for_event(&pong);
+        a.synchronize();
+    });
+    LatencyStats::from_samples(
+        format!(
+            "event ping-pong dev{} <-> dev{}",
+            a.ctx.device_id, b.ctx.device_id
+        ),
+        samples,
+    )
+}
+
+/// `cuStreamSynchronize` on an idle stream, and after a tiny copy.
+pub fn sync_cost(stream: &Stream, dst: &Buffer, src: &Buffer, iters: usize) -> [LatencyStats; 2] {
+    let idle = time(stream, iters, || stream.synchronize());
+    for _ in 0..WARMUP {
+        stream.memcpy_async(dst, src);
+        stream.synchronize();
+    }
+    let busy = (0..iters)
+        .map(|_| {
+            stream.memcpy_async(dst, src);
+            let t0 = Instant::now();
+            stream.synchronize();
+            t0.elapsed()
+        })
+        .collect();
+    [
+        LatencyStats::from_samples("synchronize idle", idle),
+        LatencyStats::from_samples(
+            format!(
+                "synchronize after {:?} -> {:?}",
+                src.address_space, dst.address_space
+            ),
+            busy,
+        ),
+    ]
+}
@@ -1,37 +1,175 @@
-use cudarc::driver::sys;
-use libc;
+use cudarc::driver::{DriverError, sys};
 use std::mem::MaybeUninit;
+use std::panic::Location;
 use std::sync::LazyLock;
+use std::sync::atomic::{AtomicBool, Ordering};
 
+pub mod accounting;
+pub mod affinity;
+pub mod alloc;
+pub mod bench;
+pub mod collectives;
+pub mod devices;
+pub mod expr;
+#[cfg(feature = "fault-injection")]
+pub mod fault;
+pub mod graph;
+pub mod history;
+pub mod host;
+pub mod latency;
 pub mod log;
+pub mod multiproc;
+pub mod p2p;
+pub mod planner;
+pub mod report;
+pub mod size;
+#[cfg(feature = "suite")]
+pub mod suite;
+pub mod topology;
+pub mod workers;
+
+pub use host::{HostOptions, HugePages, Prefault};
+pub use size::{Bandwidth, ByteSize, Units};
 
 pub fn cu_init() {
     unsafe { sys::cuInit(0) }.result().unwrap();
 }
 
+/// Set once `INIT` has run; environment variables the driver reads (such as
+/// `CUDA_VISIBLE_DEVICES`) no longer take effect after that.
+pub static INITIALIZED: AtomicBool = AtomicBool::new(false);
+
 pub static INIT: LazyLock<()> = LazyLock::new(|| {
-    log!("Initializing CUDA");
+    log_debug!("Initializing CUDA");
+    INITIALIZED.store(true, Ordering::SeqCst);
     cu_init();
 });
 
+/// Number of devices visible to the driver.
+pub fn device_count() -> usize {
+    LazyLock::force(&INIT);
+    let mut count = 0;
+    unsafe { sys::cuDeviceGetCount(&mut count) }
+        .result()
+        .unwrap();
+    count as usize
+}
+
+pub fn driver_version() -> i32 {
+    LazyLock::force(&INIT);
+    let mut version = 0;
+    unsafe { sys::cuDriverGetVersion(&mut version) }
+        .result()
+        .unwrap();
+    version
+}
+
+pub fn device_name(ordinal: i32) -> String {
+    LazyLock::force(&INIT);
+    let mut name = [0 as std::ffi::c_char; 256];
+    unsafe { sys::cuDeviceGetName(name.as_mut_ptr(), name.len() as i32, ordinal) }
+        .result()
+        .unwrap();
+    unsafe { std::ffi::CStr::from_ptr(name.as_ptr()) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// PCI bus id of a device, e.g. `0000:17:00.0`.
+pub fn pci_bus_id(ordinal: i32) -> String {
+    LazyLock::force(&INIT);
+    let mut id = [0 as std::ffi::c_char; 32];
+    unsafe { sys::cuDeviceGetPCIBusId(id.as_mut_ptr(), id.len() as i32, ordinal) }
+        .result()
+        .unwrap();
+    unsafe { std::ffi::CStr::from_ptr(id.as_ptr()) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Device UUID as shown by `nvidia-smi -L`, e.g. `GPU-8e5c3a1f-...`.
+pub fn device_uuid(ordinal: i32) -> String {
+    LazyLock::force(&INIT);
+    let mut uuid = sys::CUuuid { bytes: [0; 16] };
+    unsafe { sys::cuDeviceGetUuid_v2(&mut uuid, ordinal) }
+        .result()
+        .unwrap();
+    let hex = uuid
+        .bytes
+        .iter()
+        .map(|&b| format!("{:02x}", b as u8))
+        .collect::<String>();
+    format!(
+        "GPU-{}-{}-{}-{}-{}",
+        &hex[0..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..32]
+    )
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum AddressSpace {
     Device,
     Pinned,
+    /// Host memory page-locked with `cuMemHostRegister`.
+    Registered,
     Cpu,
 }
 
+impl AddressSpace {
+    pub fn is_host(&self) -> bool {
+        *self != AddressSpace::Device
+    }
+}
+
+impl std::str::FromStr for AddressSpace {
+    type Err = String;
+
+    /// Parses the `Debug` names, e.g. `Pinned`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "Device" => Ok(AddressSpace::Device),
+            "Pinned" => Ok(AddressSpace::Pinned),
+            "Registered" => Ok(AddressSpace::Registered),
+            "Cpu" => Ok(AddressSpace::Cpu),
+            _ => Err(format!("unknown address space {:?}", s)),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Buffer {
     pub ctx: Context,
     pub size: usize,
     pub address_space: AddressSpace,
     pub addr: u64,
+    /// Page backing actually obtained for host memory (`Cpu`/`Registered`).
+    pub pages: HugePages,
+    /// Whether this is a sub-range of another buffer (see `Buffer::view`).
+    pub is_view: bool,
 }
 
 unsafe impl Send for Buffer {}
 unsafe impl Sync for Buffer {}
 
+impl Buffer {
+    /// A `size`-byte window starting `offset` bytes into this buffer. Views
+    /// share the parent's memory and must not be freed.
+    pub fn view(&self, offset: usize, size: usize) -> Buffer {
+        assert!(offset + size <= self.size, "view out of bounds");
+        Buffer {
+            ctx: self.ctx.clone(),
+            size,
+            address_space: self.address_space.clone(),
+            addr: self.addr + offset as u64,
+            pages: self.pages,
+            is_view: true,
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Stream {
     pub ctx: Context,
@@ -42,45 +180,147 @@ unsafe impl Send for Stream {}
 unsafe impl Sync for Stream {}
 
 impl Stream {
+    #[track_caller]
     pub fn create_buffer_async(&self, size: usize, address_space: AddressSpace) -> Buffer {
+        self.create_host_buffer(size, address_space, &HostOptions::default())
+    }
+
+    /// Like `create_buffer_async`, but `Cpu`/`Registered` memory is allocated
+    /// and prefaulted according to `options`.
+    #[track_caller]
+    pub fn create_host_buffer(
+        &self,
+        size: usize,
+        address_space: AddressSpace,
+        options: &HostOptions,
+    ) -> Buffer {
+        self.try_create_host_buffer(size, address_space.clone(), options)
+            .unwrap_or_else(|e| {
+                panic!(
+                    "failed to allocate {} of {:?} memory: {}",
+                    ByteSize::from(size),
+                    address_space,
+                    e
+                )
+            })
+    }
+
+    /// Like `create_host_buffer`, but returns the driver's error instead of
+    /// panicking. Host memory that can't be mapped is reported as
+    /// `CUDA_ERROR_OUT_OF_MEMORY`.
+    #[track_caller]
+    pub fn try_create_host_buffer(
+        &self,
+        size: usize,
+        address_space: AddressSpace,
+        options: &HostOptions,
+    ) -> Result<Buffer, DriverError> {
+        self.create_tracked(size, address_space, options, Location::caller())
+    }
+
+    /// Allocates and records the buffer in `accounting` as made at `location`.
+    fn create_tracked(
+        &self,
+        size: usize,
+        address_space: AddressSpace,
+        options: &HostOptions,
+        location: &'static Location<'static>,
+    ) -> Result<Buffer, DriverError> {
+        #[cfg(feature = "fault-injection")]
+        if let Some(delay) = fault::inject(fault::Call::Alloc)? {
+            std::thread::sleep(delay);
+        }
+        let buf = self.create_untracked(size, address_space, options)?;
+        accounting::track(self, &buf, location);
+        Ok(buf)
+    }
+
+    /// Allocates without injecting faults or recording the buffer, e.g. to
+    /// probe what fits. Free with `free_untracked`.
+    pub(crate) fn create_untracked(
+        &self,
+        size: usize,
+        address_space: AddressSpace,
+        options: &HostOptions,
+    ) -> Result<Buffer, DriverError> {
         self.ctx.set_current();
+        let out_of_memory = DriverError(sys::CUresult::CUDA_ERROR_OUT_OF_MEMORY);
+        let mut obtained = HugePages::None;
         let addr = match address_space {
             AddressSpace::Device => unsafe {
                 let mut pbuffer = MaybeUninit::uninit();
-                sys::cuMemAlloc_v2(pbuffer.as_mut_ptr(), size)
-                    .result()
-                    .unwrap();
+                sys::cuMemAlloc_v2(pbuffer.as_mut_ptr(), size).result()?;
                 pbuffer.assume_init()
             },
             AddressSpace::Pinned => unsafe {
                 let mut pbuffer = MaybeUninit::uninit();
-                sys::cuMemAllocHost_v2(pbuffer.as_mut_ptr(), size)
-                    .result()
-                    .unwrap();
+                sys::cuMemAllocHost_v2(pbuffer.as_mut_ptr(), size).result()?;
                 pbuffer.assume_init() as u64
             },
-            AddressSpace::Cpu => unsafe { libc::malloc(size) as u64 },
+            AddressSpace::Registered => {
+                let addr;
+                (addr, obtained) = host::try_alloc_host(size, options).ok_or(out_of_memory)?;
+                let registered = unsafe {
+                    sys::cuMemHostRegister_v2(
+                        addr as *mut libc::c_void,
+                        size,
+                        sys::CU_MEMHOSTREGISTER_PORTABLE,
+                    )
+                }
+                .result();
+                if let Err(e) = registered {
+                    host::free(addr, size, obtained);
+                    return Err(e);
+                }
+                addr
+            }
+            AddressSpace::Cpu => {
+                let addr;
+                (addr, obtained) = host::try_alloc_host(size, options).ok_or(out_of_memory)?;
+                addr
+            }
         };
-        Buffer {
+        let buf = Buffer {
             ctx: self.ctx.clone(),
             size,
             address_space,
             addr,
-        }
+            pages: obtained,
+            is_view: false,
+        };
+        Ok(buf)
     }
 
     pub fn free_buffer_sync(&self, buf: &Buffer) {
+        accounting::untrack(buf);
+        self.free_untracked(buf);
+    }
+
+    pub(crate) fn free_untracked(&self, buf: &Buffer) {
+        assert!(!buf.is_view, "can't free a buffer view");
         self.ctx.set_current();
         match buf.address_space {
             AddressSpace::Device => unsafe { sys::cuMemFree_v2(buf.addr) }.result().unwrap(),
             AddressSpace::Pinned => unsafe { sys::cuMemFreeHost(buf.addr as *mut libc::c_void) }
                 .result()
                 .unwrap(),
-            AddressSpace::Cpu => unsafe { libc::free(buf.addr as *mut libc::c_void) },
+            AddressSpace::Registered => {
+                unsafe { sys::cuMemHostUnregister(buf.addr as *mut libc::c_void) }
+                    .result()
+                    .unwrap();
+                host::free(buf.addr, buf.size, buf.pages);
+            }
+            AddressSpace::Cpu => host::free(buf.addr, buf.size, buf.pages),
         }
     }
 
     pub fn memcpy_async(&self, dst: &Buffer, src: &Buffer) {
+        self.try_memcpy_async(dst, src).unwrap();
+    }
+
+    /// Like `memcpy_async`, but returns the driver's error instead of
+    /// panicking.
+    pub fn try_memcpy_async(&self, dst: &Buffer, src: &Buffer) -> Result<(), DriverError> {
         self.ctx.set_current();
         if dst.size != src.size {
             panic!("size mismatch");
@@ -106,17 +346,42 @@ impl Stream {
         // }
 
         // log!("Copying from {:?} to {:?} on {:?}", src, dst, self);
-        unsafe { sys::cuMemcpyAsync(dst.addr, src.addr, src.size, self.stream) }
+        #[cfg(feature = "fault-injection")]
+        let delay = fault::inject(fault::Call::Memcpy)?;
+        unsafe { sys::cuMemcpyAsync(dst.addr, src.addr, src.size, self.stream) }.result()?;
+        #[cfg(feature = "fault-injection")]
+        if let Some(delay) = delay {
+            fault::delay_stream(self, delay);
+        }
+        Ok(())
+    }
+
+    /// Sets every byte of `buf` to `value`. `buf` must be accessible to the
+    /// device: device, pinned or registered memory.
+    pub fn memset_async(&self, buf: &Buffer, value: u8) {
+        self.ctx.set_current();
+        unsafe { sys::cuMemsetD8Async(buf.addr, value, buf.size, self.stream) }
             .result()
             .unwrap();
     }
 
     pub fn synchronize(&self) {
+        self.try_synchronize().unwrap();
+    }
+
+    /// Like `synchronize`, but returns the driver's error, e.g. from a failed
+    /// copy, instead of panicking.
+    pub fn try_synchronize(&self) -> Result<(), DriverError> {
         self.ctx.set_current();
         // log!("Synchronizing stream {:?}", self);
-        unsafe { sys::cuStreamSynchronize(self.stream) }
-            .result()
-            .unwrap();
+        #[cfg(feature = "fault-injection")]
+        let delay = fault::inject(fault::Call::Synchronize)?;
+        unsafe { sys::cuStreamSynchronize(self.stream) }.result()?;
+        #[cfg(feature = "fault-injection")]
+        if let Some(delay) = delay {
+            std::thread::sleep(delay);
+        }
+        Ok(())
     }
 
     pub fn record_event(&self, event: &Event) {
@@ -140,35 +405,97 @@ pub struct Context {
     pub device_id: i32,
 }
 
+unsafe impl Send for Context {}
+unsafe impl Sync for Context {}
+
 #[derive(Debug, Clone)]
 pub struct Event {
     pub ctx: Context,
     pub event: *mut sys::CUevent_st,
 }
 
+unsafe impl Send for Event {}
+unsafe impl Sync for Event {}
+
+impl Event {
+    pub fn synchronize(&self) {
+        self.try_synchronize().unwrap();
+    }
+
+    pub fn try_synchronize(&self) -> Result<(), DriverError> {
+        self.ctx.set_current();
+        #[cfg(feature = "fault-injection")]
+        let delay = fault::inject(fault::Call::Synchronize)?;
+        unsafe { sys::cuEventSynchronize(self.event) }.result()?;
+        #[cfg(feature = "fault-injection")]
+        if let Some(delay) = delay {
+            std::thread::sleep(delay);
+        }
+        Ok(())
+    }
+
+    /// GPU time between `start` and this event. Both must be timing events
+    /// (`Context::create_timing_event`) that have completed.
+    pub fn elapsed_since(&self, start: &Event) -> std::time::Duration {
+        self.ctx.set_current();
+        let mut ms = 0.0f32;
+        unsafe { sys::cuEventElapsedTime(&mut ms, start.event, self.event) }
+            .result()
+            .unwrap();
+        std::time::Duration::from_secs_f64(ms as f64 / 1e3)
+    }
+
+    /// Destroys the event. Work already waiting on it is unaffected, but
+    /// every clone of it becomes invalid.
+    pub fn destroy(self) {
+        self.ctx.set_current();
+        unsafe { sys::cuEventDestroy_v2(self.event) }
+            .result()
+            .unwrap();
+    }
+}
+
 impl Context {
     pub fn new(device_id: i32) -> Self {
-        _ = *INIT;
+        Self::with_flags(device_id, 0)
+    }
+
+    /// Creates a context on `device_id` with `CUctx_flags` such as
+    /// `CU_CTX_SCHED_SPIN`.
+    pub fn with_flags(device_id: i32, flags: u32) -> Self {
+        LazyLock::force(&INIT);
 
         let dev = unsafe {
             let mut pdev = MaybeUninit::uninit();
-            sys::cuDeviceGet(pdev.as_mut_ptr(), 0).result().unwrap();
+            sys::cuDeviceGet(pdev.as_mut_ptr(), device_id)
+                .result()
+                .unwrap();
             pdev.assume_init()
         };
 
         let ctx = unsafe {
             let mut pctx = MaybeUninit::uninit();
-            sys::cuCtxCreate_v2(pctx.as_mut_ptr(), 0, dev)
+            sys::cuCtxCreate_v2(pctx.as_mut_ptr(), flags, dev)
                 .result()
                 .unwrap();
             pctx.assume_init()
         };
 
         let ctx = Self { ctx, device_id };
-        log!("Created {:?}", ctx);
+        log_debug!("Created {:?} with flags {:#x}", ctx, flags);
         ctx
     }
 
+    /// Destroys the context after warning about buffers never freed on it,
+    /// which the driver releases (host memory of `Cpu` and `Registered`
+    /// buffers stays mapped). Every clone of the context becomes invalid.
+    pub fn destroy(self) {
+        accounting::warn_leaks(Some(&self));
+        accounting::forget_context(&self);
+        unsafe { sys::cuCtxDestroy_v2(self.ctx) }.result().unwrap();
+        log_debug!("Destroyed {:?}", self);
+    }
+
     pub fn set_current(&self) {
         unsafe { sys::cuCtxSetCurrent(self.ctx) }.result().unwrap();
     }
@@ -185,7 +512,7 @@ impl Context {
             .unwrap();
             pstream.assume_init()
         };
-        log!("Created {:?} on {:?}", stream, self);
+        log_debug!("Created {:?} on {:?}", stream, self);
         Stream {
             ctx: self.clone(),
             stream,
@@ -209,28 +536,28 @@ impl Context {
             event,
         }
     }
+
+    /// Like `create_event`, but usable with `Event::elapsed_since`.
+    pub fn create_timing_event(&self) -> Event {
+        self.set_current();
+        let event = unsafe {
+            let mut pevent = MaybeUninit::uninit();
+            sys::cuEventCreate(
+                pevent.as_mut_ptr(),
+                sys::CUevent_flags_enum::CU_EVENT_DEFAULT as u32,
+            )
+            .result()
+            .unwrap();
+            pevent.assume_init()
+        };
+        Event {
+            ctx: self.clone(),
+            event,
+        }
+    }
 }
 
+/// Formats a byte count in IEC units, e.g. `8.00 GiB`.
 pub fn bytes_to_human_readable(bytes_usize: usize) -> String {
-    let mut running = bytes_usize as f64;
-    if running < 1024.0 {
-        return format!("{:.2} B", running);
-    }
-    running /= 1024.0;
-    if running < 1024.0 {
-        return format!("{:.2} KB", running);
-    }
-    running /= 1024.0;
-    if running < 1024.0 {
-        return format!("{:.2} MB", running);
-    }
-    running /= 1024.0;
-    if running < 1024.0 {
-        return format!("{:.2} GB", running);
-    }
-    running /= 1024.0;
-    if running < 1024.0 {
-        return format!("{:.2} TB", running);
-    }
-    format!("{:.2} PB", running / 1024.0)
+    ByteSize::from(bytes_usize).to_string()
 }
@@ -1,11 +1,492 @@
+//! Leveled logging to stderr.
+//!
+//! Configured from the environment on first use, or with [`set_config`]:
+//!
+//! - `CUDA_GISTS_LOG`: filter such as `info` or `warn,cuda_gists::host=trace,h2d=debug`.
+//!   The longest matching module prefix wins; the bare level is the default.
+//! - `CUDA_GISTS_LOG_FORMAT`: `text` (default), `json`, or `log`/`tracing` to
+//!   forward records to those crates when the matching feature is enabled.
+//! - `CUDA_GISTS_LOG_TIME=1` / `CUDA_GISTS_LOG_THREAD=1`: add timestamps / thread ids.
+
+use std::io::Write;
+use std::sync::{LazyLock, Once, RwLock};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+            Level::Trace => "TRACE",
+        }
+    }
+}
+
+impl std::str::FromStr for Level {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "error" => Ok(Level::Error),
+            "warn" | "warning" => Ok(Level::Warn),
+            "info" => Ok(Level::Info),
+            "debug" => Ok(Level::Debug),
+            "trace" => Ok(Level::Trace),
+            _ => Err(format!("unknown log level {:?}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Text,
+    /// One JSON object per line.
+    Json,
+    /// Forward to the `log` crate.
+    #[cfg(feature = "log")]
+    Log,
+    /// Forward to the `tracing` crate.
+    #[cfg(feature = "tracing")]
+    Tracing,
+}
+
+/// Accepted `CUDA_GISTS_LOG_FORMAT` values in this build.
+const FORMATS: &[&str] = &[
+    "text",
+    "json",
+    #[cfg(feature = "log")]
+    "log",
+    #[cfg(feature = "tracing")]
+    "tracing",
+];
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Level for modules not matched by `modules`.
+    pub level: Level,
+    /// Per-module-prefix levels.
+    pub modules: Vec<(String, Level)>,
+    pub format: Format,
+    pub timestamps: bool,
+    pub thread_ids: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            level: Level::Info,
+            modules: Vec::new(),
+            format: Format::Text,
+            timestamps: false,
+            thread_ids: false,
+        }
+    }
+}
+
+impl Config {
+    /// Reads the `CUDA_GISTS_LOG*` variables. Values that can't be used are
+    /// skipped with a warning on stderr, printed once per process.
+    pub fn from_env() -> Self {
+        let mut config = Self::default();
+        let mut problems = Vec::new();
+        if let Ok(filter) = std::env::var("CUDA_GISTS_LOG") {
+            for directive in config.parse_filter(&filter) {
+                problems.push(format!("ignoring CUDA_GISTS_LOG directive {:?}", directive));
+            }
+        }
+        match std::env::var("CUDA_GISTS_LOG_FORMAT").as_deref() {
+            Err(_) | Ok("text") => {}
+            Ok("json") => config.format = Format::Json,
+            #[cfg(feature = "log")]
+            Ok("log") => config.format = Format::Log,
+            #[cfg(feature = "tracing")]
+            Ok("tracing") => config.format = Format::Tracing,
+            Ok(other) => problems.push(format!(
+                "ignoring CUDA_GISTS_LOG_FORMAT {:?} (expected {})",
+                other,
+                FORMATS.join(", ")
+            )),
+        }
+        let flag = |name| std::env::var(name).is_ok_and(|v| v == "1" || v == "true");
+        config.timestamps = flag("CUDA_GISTS_LOG_TIME");
+        config.thread_ids = flag("CUDA_GISTS_LOG_THREAD");
+        if !problems.is_empty() {
+            static WARNED: Once = Once::new();
+            WARNED.call_once(|| {
+                for problem in &problems {
+                    let _ = writeln!(std::io::stderr().lock(), "cuda-gists: {}", problem);
+                }
+            });
+        }
+        config
+    }
+
+    /// Applies directives like `warn,cuda_gists::host=trace`, returning the
+    /// ones that couldn't be parsed.
+    pub fn parse_filter(&mut self, filter: &str) -> Vec<String> {
+        let mut rejected = Vec::new();
+        for directive in filter.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+            let parsed = match directive.split_once('=') {
+                Some((module, level)) if !module.trim().is_empty() => {
+                    level.trim().parse().map(|level| {
+                        self.modules.push((module.trim().to_string(), level));
+                    })
+                }
+                Some(_) => Err(String::new()),
+                None => directive.parse().map(|level| self.level = level),
+            };
+            if parsed.is_err() {
+                rejected.push(directive.to_string());
+            }
+        }
+        rejected
+    }
+
+    pub fn level_for(&self, module: &str) -> Level {
+        self.modules
+            .iter()
+            .filter(|(prefix, _)| {
+                module == prefix
+                    || module
+                        .strip_prefix(prefix.as_str())
+                        .is_some_and(|rest| rest.starts_with("::"))
+            })
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map_or(self.level, |(_, level)| *level)
+    }
+}
+
+static CONFIG: LazyLock<RwLock<Config>> = LazyLock::new(|| RwLock::new(Config::from_env()));
+
+pub fn set_config(config: Config) {
+    *CONFIG.write().unwrap() = config;
+}
+
+pub fn config() -> Config {
+    CONFIG.read().unwrap().clone()
+}
+
+pub fn enabled(level: Level, module: &str) -> bool {
+    level <= CONFIG.read().unwrap().level_for(module)
+}
+
+pub fn format() -> Format {
+    CONFIG.read().unwrap().format
+}
+
+#[cfg(feature = "tracing")]
+#[doc(hidden)]
+pub use ::tracing as __tracing;
+
+/// Writes a record in the configured format. `log_at!` sends `Tracing`
+/// records itself, since a tracing target must be known at the call site;
+/// here they can only be attributed to this crate.
+pub fn emit(level: Level, module: &str, file: &str, line: u32, args: std::fmt::Arguments) {
+    let config = CONFIG.read().unwrap();
+    let pid = std::process::id();
+    let tid = unsafe { libc::gettid() };
+    let time = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64();
+
+    match config.format {
+        Format::Text => {
+            use colored::Colorize;
+            let mut prefix = String::from("[");
+            if config.timestamps {
+                prefix += &format!("{:.6} ", time);
+            }
+            prefix += &pid.to_string();
+            if config.thread_ids {
+                prefix += &format!("/{}", tid);
+            }
+            prefix += &format!("|{}:{}] ", file, line);
+            let level_str = match level {
+                Level::Error => level.as_str().red(),
+                Level::Warn => level.as_str().yellow(),
+                Level::Info => level.as_str().green(),
+                Level::Debug => level.as_str().blue(),
+                Level::Trace => level.as_str().dimmed(),
+            };
+            let _ = writeln!(
+                std::io::stderr().lock(),
+                "{}{:<5} {}",
+                prefix.cyan(),
+                level_str,
+                args
+            );
+        }
+        Format::Json => {
+            let mut line_str = format!(
+                "{{\"level\":\"{}\",\"module\":{},\"file\":{},\"line\":{},\"pid\":{}",
+                level.as_str(),
+                json_string(module),
+                json_string(file),
+                line,
+                pid
+            );
+            if config.timestamps {
+                line_str += &format!(",\"time\":{:.6}", time);
+            }
+            if config.thread_ids {
+                line_str += &format!(",\"tid\":{}", tid);
+            }
+            line_str += &format!(",\"msg\":{}}}", json_string(&args.to_string()));
+            let _ = writeln!(std::io::stderr().lock(), "{}", line_str);
+        }
+        #[cfg(feature = "log")]
+        Format::Log => {
+            let level = match level {
+                Level::Error => ::log::Level::Error,
+                Level::Warn => ::log::Level::Warn,
+                Level::Info => ::log::Level::Info,
+                Level::Debug => ::log::Level::Debug,
+                Level::Trace => ::log::Level::Trace,
+            };
+            if level > ::log::max_level() {
+                return;
+            }
+            ::log::logger().log(
+                &::log::Record::builder()
+                    .args(args)
+                    .level(level)
+                    .target(module)
+                    .module_path(Some(module))
+                    .file(Some(file))
+                    .line(Some(line))
+                    .build(),
+            );
+        }
+        #[cfg(feature = "tracing")]
+        Format::Tracing => match level {
+            Level::Error => ::tracing::error!(target: "cuda_gists", module, file, line, "{}", args),
+            Level::Warn => ::tracing::warn!(target: "cuda_gists", module, file, line, "{}", args),
+            Level::Info => ::tracing::info!(target: "cuda_gists", module, file, line, "{}", args),
+            Level::Debug => ::tracing::debug!(target: "cuda_gists", module, file, line, "{}", args),
+            Level::Trace => ::tracing::trace!(target: "cuda_gists", module, file, line, "{}", args),
+        },
+    }
+}
+
+/// Quotes and escapes `s` as a JSON string.
+pub fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(not(feature = "tracing"))]
 #[macro_export]
-macro_rules! log {
-    ($($x:tt)*) => {
+macro_rules! log_at {
+    ($level:expr, $($x:tt)*) => {
         {
-            use colored::Colorize;
-            let s = format!("[{}|{}:{}] ", std::process::id(), file!(), line!());
-            eprint!("{}", s.cyan());
-            eprintln!($($x)*)
+            let level = $level;
+            if $crate::log::enabled(level, module_path!()) {
+                $crate::log::emit(level, module_path!(), file!(), line!(), format_args!($($x)*));
+            }
+        }
+    }
+}
+
+/// With `tracing`, records are sent from the call site so their target is
+/// the calling module, as with `log`.
+#[cfg(feature = "tracing")]
+#[macro_export]
+macro_rules! log_at {
+    ($level:expr, $($x:tt)*) => {
+        {
+            let level = $level;
+            if $crate::log::enabled(level, module_path!()) {
+                if $crate::log::format() == $crate::log::Format::Tracing {
+                    $crate::__tracing_event!(level, $($x)*);
+                } else {
+                    $crate::log::emit(level, module_path!(), file!(), line!(), format_args!($($x)*));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(feature = "tracing")]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __tracing_event {
+    ($level:expr, $($x:tt)*) => {
+        match $level {
+            $crate::log::Level::Error => {
+                $crate::log::__tracing::error!(target: module_path!(), "{}", format_args!($($x)*))
+            }
+            $crate::log::Level::Warn => {
+                $crate::log::__tracing::warn!(target: module_path!(), "{}", format_args!($($x)*))
+            }
+            $crate::log::Level::Info => {
+                $crate::log::__tracing::info!(target: module_path!(), "{}", format_args!($($x)*))
+            }
+            $crate::log::Level::Debug => {
+                $crate::log::__tracing::debug!(target: module_path!(), "{}", format_args!($($x)*))
+            }
+            $crate::log::Level::Trace => {
+                $crate::log::__tracing::trace!(target: module_path!(), "{}", format_args!($($x)*))
+            }
+        }
+    }
+}
+
+/// Logs at `Info`.
+#[macro_export]
+macro_rules! log {
+    ($($x:tt)*) => { $crate::log_at!($crate::log::Level::Info, $($x)*) }
+}
+
+#[macro_export]
+macro_rules! log_error {
+    ($($x:tt)*) => { $crate::log_at!($crate::log::Level::Error, $($x)*) }
+}
+
+#[macro_export]
+macro_rules! log_warn {
+    ($($x:tt)*) => { $crate::log_at!($crate::log::Level::Warn, $($x)*) }
+}
+
+#[macro_export]
+macro_rules! log_debug {
+    ($($x:tt)*) => { $crate::log_at!($crate::log::Level::Debug, $($x)*) }
+}
+
+#[macro_export]
+macro_rules! log_trace {
+    ($($x:tt)*) => { $crate::log_at!($crate::log::Level::Trace, $($x)*) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(s: &str) -> (Config, Vec<String>) {
+        let mut config = Config::default();
+        let rejected = config.parse_filter(s);
+        (config, rejected)
+    }
+
+    #[test]
+    fn parse_levels() {
+        assert_eq!("WARNING".parse(), Ok(Level::Warn));
+        assert_eq!("trace".parse(), Ok(Level::Trace));
+        assert!("loud".parse::<Level>().is_err());
+    }
+
+    #[test]
+    fn parse_filter_directives() {
+        let (config, rejected) = filter(" warn , cuda_gists::host=trace,h2d = debug,");
+        assert!(rejected.is_empty());
+        assert_eq!(config.level, Level::Warn);
+        assert_eq!(
+            config.modules,
+            [
+                (String::from("cuda_gists::host"), Level::Trace),
+                (String::from("h2d"), Level::Debug),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_filter_rejects_bad_directives() {
+        let (config, rejected) = filter("loud,debug,host=shouty,=info");
+        assert_eq!(rejected, ["loud", "host=shouty", "=info"]);
+        assert_eq!(config.level, Level::Debug);
+        assert!(config.modules.is_empty());
+    }
+
+    #[test]
+    fn level_for_most_specific_target_wins() {
+        let (config, _) =
+            filter("warn,cuda_gists=info,cuda_gists::host=trace,cuda_gists::host::numa=error");
+        assert_eq!(config.level_for("other"), Level::Warn);
+        assert_eq!(config.level_for("cuda_gists"), Level::Info);
+        assert_eq!(config.level_for("cuda_gists::bench"), Level::Info);
+        assert_eq!(config.level_for("cuda_gists::host"), Level::Trace);
+        assert_eq!(config.level_for("cuda_gists::host::pages"), Level::Trace);
+        assert_eq!(config.level_for("cuda_gists::host::numa"), Level::Error);
+        // Prefixes only match whole path segments.
+        assert_eq!(config.level_for("cuda_gists::hostile"), Level::Info);
+        assert_eq!(config.level_for("cuda_gists_extra"), Level::Warn);
+    }
+
+    #[test]
+    fn level_for_order_independent() {
+        let (config, _) = filter("cuda_gists::host=trace,cuda_gists=error");
+        assert_eq!(config.level_for("cuda_gists::host"), Level::Trace);
+        assert_eq!(config.level_for("cuda_gists::p2p"), Level::Error);
+    }
+
+    #[test]
+    fn json_escaping() {
+        assert_eq!(json_string("a\"b\\c\n\u{1}"), "\"a\\\"b\\\\c\\n\\u0001\"");
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn tracing_target_is_the_calling_module() {
+        use std::sync::{Arc, Mutex};
+
+        use ::tracing::span::{Attributes, Id, Record};
+        use ::tracing::{Event, Metadata, Subscriber};
+
+        struct Targets(Arc<Mutex<Vec<String>>>);
+
+        impl Subscriber for Targets {
+            fn enabled(&self, _: &Metadata<'_>) -> bool {
+                true
+            }
+            fn new_span(&self, _: &Attributes<'_>) -> Id {
+                Id::from_u64(1)
+            }
+            fn record(&self, _: &Id, _: &Record<'_>) {}
+            fn record_follows_from(&self, _: &Id, _: &Id) {}
+            fn event(&self, event: &Event<'_>) {
+                self.0
+                    .lock()
+                    .unwrap()
+                    .push(event.metadata().target().to_string());
+            }
+            fn enter(&self, _: &Id) {}
+            fn exit(&self, _: &Id) {}
         }
+
+        let targets = Arc::new(Mutex::new(Vec::new()));
+        let previous = config();
+        set_config(Config {
+            format: Format::Tracing,
+            ..Config::default()
+        });
+        ::tracing::subscriber::with_default(Targets(targets.clone()), || {
+            crate::log_warn!("to tracing");
+            crate::log_debug!("filtered out");
+        });
+        set_config(previous);
+        assert_eq!(*targets.lock().unwrap(), ["cuda_gists::log::tests"]);
     }
 }
@@ -0,0 +1,348 @@
+//! Runs a benchmark in one child process per device, the way production jobs
+//! run, so host-memory and PCIe contention between processes shows up.
+//!
+//! The parent re-executes a command once per rank with `CUDA_GISTS_RANK`,
+//! `CUDA_GISTS_WORLD` and `CUDA_GISTS_BARRIER` set. Children line up every
+//! iteration on a barrier in POSIX shared memory and print their results as
+//! CSV (`report::Format::Csv`) on stdout, which the parent parses and
+//! aggregates.
+
+use std::io::{self, Read};
+use std::process::{Command, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::bench::{BenchResult, CopyInfo, Resizable, Sample, Scenario};
+use crate::history::HistoryRow;
+use crate::*;
+
+pub const RANK_ENV: &str = "CUDA_GISTS_RANK";
+pub const WORLD_ENV: &str = "CUDA_GISTS_WORLD";
+pub const BARRIER_ENV: &str = "CUDA_GISTS_BARRIER";
+
+/// How long a child waits at the barrier before assuming a sibling died.
+pub const BARRIER_TIMEOUT: Duration = Duration::from_secs(300);
+
+#[repr(C)]
+struct BarrierState {
+    count: AtomicU64,
+    generation: AtomicU64,
+}
+
+/// A reusable spinning barrier for `parties` processes in a `shm_open` segment.
+pub struct SharedBarrier {
+    pub name: String,
+    pub parties: usize,
+    state: *mut BarrierState,
+    /// The creator unlinks the segment on drop.
+    owner: bool,
+}
+
+unsafe impl Send for SharedBarrier {}
+unsafe impl Sync for SharedBarrier {}
+
+impl SharedBarrier {
+    fn map(name: &str, parties: usize, create: bool) -> io::Result<Self> {
+        let cname = std::ffi::CString::new(name).unwrap();
+        let flags = if create {
+            libc::O_CREAT | libc::O_EXCL | libc::O_RDWR
+        } else {
+            libc::O_RDWR
+        };
+        let fd = unsafe { libc::shm_open(cname.as_ptr(), flags, 0o600) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let len = std::mem::size_of::<BarrierState>();
+        if create && unsafe { libc::ftruncate(fd, len as libc::off_t) } != 0 {
+            let err = io::Error::last_os_error();
+            unsafe {
+                libc::close(fd);
+                libc::shm_unlink(cname.as_ptr());
+            }
+            return Err(err);
+        }
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                fd,
+                0,
+            )
+        };
+        let err = io::Error::last_os_error();
+        unsafe { libc::close(fd) };
+        if ptr == libc::MAP_FAILED {
+            if create {
+                unsafe { libc::shm_unlink(cname.as_ptr()) };
+            }
+            return Err(err);
+        }
+        Ok(Self {
+            name: name.to_string(),
+            parties,
+            state: ptr as *mut BarrierState,
+            owner: create,
+        })
+    }
+
+    /// Creates a fresh, zeroed segment with a unique name.
+    pub fn create(parties: usize) -> io::Result<Self> {
+        let nonce = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .subsec_nanos();
+        Self::map(
+            &format!("/cuda-gists-{}-{}", std::process::id(), nonce),
+            parties,
+            true,
+        )
+    }
+
+    pub fn open(name: &str, parties: usize) -> io::Result<Self> {
+        Self::map(name, parties, false)
+    }
+
+    /// Blocks until all `parties` have called `wait` for this round. Panics
+    /// after `BARRIER_TIMEOUT`.
+    pub fn wait(&self) {
+        let state = unsafe { &*self.state };
+        let generation = state.generation.load(Ordering::Acquire);
+        if state.count.fetch_add(1, Ordering::AcqRel) + 1 == self.parties as u64 {
+            state.count.store(0, Ordering::Relaxed);
+            state.generation.fetch_add(1, Ordering::Release);
+            return;
+        }
+        let t0 = Instant::now();
+        while state.generation.load(Ordering::Acquire) == generation {
+            if t0.elapsed() > BARRIER_TIMEOUT {
+                panic!("timed out waiting on barrier {}", self.name);
+            }
+            std::hint::spin_loop();
+            std::thread::yield_now();
+        }
+    }
+}
+
+impl Drop for SharedBarrier {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(
+                self.state as *mut libc::c_void,
+                std::mem::size_of::<BarrierState>(),
+            )
+        };
+        if self.owner {
+            let cname = std::ffi::CString::new(self.name.as_str()).unwrap();
+            unsafe { libc::shm_unlink(cname.as_ptr()) };
+        }
+    }
+}
+
+/// This process's place in a multi-process run.
+pub struct Child {
+    pub rank: usize,
+    pub world: usize,
+    pub barrier: SharedBarrier,
+}
+
+/// `Some` when running as a child spawned by `spawn`.
+pub fn child() -> Option<Child> {
+    let rank = std::env::var(RANK_ENV).ok()?.parse().ok()?;
+    let world = std::env::var(WORLD_ENV).ok()?.parse().ok()?;
+    let name = std::env::var(BARRIER_ENV).ok()?;
+    let barrier = SharedBarrier::open(&name, world)
+        .unwrap_or_else(|e| panic!("can't open barrier {}: {}", name, e));
+    Some(Child {
+        rank,
+        world,
+        barrier,
+    })
+}
+
+/// Waits at `barrier`, if any, after setting up each iteration, so every
+/// process starts its timed work together.
+pub struct Synchronized<'a, S> {
+    pub inner: S,
+    pub barrier: Option<&'a SharedBarrier>,
+}
+
+impl<'a, S> Synchronized<'a, S> {
+    pub fn new(inner: S, barrier: Option<&'a SharedBarrier>) -> Self {
+        Self { inner, barrier }
+    }
+}
+
+impl<S: Scenario> Scenario for Synchronized<'_, S> {
+    fn name(&self) -> String {
+        self.inner.name()
+    }
+
+    fn bytes(&self) -> ByteSize {
+        self.inner.bytes()
+    }
+
+    fn copies(&self) -> Vec<CopyInfo> {
+        self.inner.copies()
+    }
+
+    fn copy_times(&self) -> Vec<Duration> {
+        self.inner.copy_times()
+    }
+
+    fn setup(&mut self) {
+        self.inner.setup();
+        if let Some(barrier) = self.barrier {
+            barrier.wait();
+        }
+    }
+
+    fn run(&mut self) {
+        self.inner.run();
+    }
+
+    fn sync(&mut self) {
+        self.inner.sync();
+    }
+
+    fn teardown(&mut self) {
+        self.inner.teardown();
+    }
+}
+
+impl<S: Resizable> Resizable for Synchronized<'_, S> {
+    fn set_size(&mut self, size: usize) {
+        self.inner.set_size(size);
+    }
+}
+
+#[derive(Debug)]
+pub struct ProcessResult {
+    pub rank: usize,
+    pub status: ExitStatus,
+    pub rows: Vec<HistoryRow>,
+}
+
+/// Runs `command(rank)` for ranks `0..world` concurrently with the child
+/// environment set and stdout captured, and waits for all of them. Each
+/// child's stdout is drained on its own thread so a rank writing more than a
+/// pipe buffer can't stall its siblings at the barrier. If a rank fails to
+/// start, the ones already running are killed.
+pub fn spawn(
+    world: usize,
+    mut command: impl FnMut(usize) -> Command,
+) -> io::Result<Vec<ProcessResult>> {
+    let barrier = SharedBarrier::create(world)?;
+    let mut children = Vec::with_capacity(world);
+    for rank in 0..world {
+        let spawned = command(rank)
+            .env(RANK_ENV, rank.to_string())
+            .env(WORLD_ENV, world.to_string())
+            .env(BARRIER_ENV, &barrier.name)
+            .stdout(Stdio::piped())
+            .spawn();
+        let mut child = match spawned {
+            Ok(child) => child,
+            Err(e) => {
+                for (_, mut started, _) in children {
+                    kill(&mut started);
+                }
+                return Err(e);
+            }
+        };
+        log_debug!("Spawned rank {} as pid {}", rank, child.id());
+        let mut stdout = child.stdout.take().unwrap();
+        let reader = std::thread::spawn(move || {
+            let mut buf = Vec::new();
+            stdout.read_to_end(&mut buf).map(|_| buf)
+        });
+        children.push((rank, child, reader));
+    }
+    children
+        .into_iter()
+        .map(|(rank, mut child, reader)| {
+            let stdout = reader.join().unwrap()?;
+            let status = child.wait()?;
+            if !status.success() {
+                log_error!("rank {} exited with {}", rank, status);
+            }
+            Ok(ProcessResult {
+                rank,
+                status,
+                rows: String::from_utf8_lossy(&stdout)
+                    .lines()
+                    .filter_map(HistoryRow::parse)
+                    .collect(),
+            })
+        })
+        .collect()
+}
+
+fn kill(child: &mut std::process::Child) {
+    if let Err(e) = child.kill() {
+        log_warn!("can't kill pid {}: {}", child.id(), e);
+    }
+    let _ = child.wait();
+}
+
+/// Combines the ranks' results per scenario, size and iteration: bytes are
+/// summed and times are those of the slowest process, so bandwidth is what
+/// the processes achieved together.
+pub fn aggregate(results: &[ProcessResult]) -> Vec<BenchResult> {
+    let Some(first) = results.first() else {
+        return Vec::new();
+    };
+    let mut keys: Vec<(&str, u64)> = Vec::new();
+    for row in &first.rows {
+        let key = (row.scenario.as_str(), row.total_bytes);
+        if !keys.contains(&key) {
+            keys.push(key);
+        }
+    }
+    keys.into_iter()
+        .map(|(scenario, size)| {
+            let rows = |r: &ProcessResult| {
+                r.rows
+                    .iter()
+                    .filter(|row| row.scenario == scenario && row.total_bytes == size)
+                    .cloned()
+                    .collect::<Vec<_>>()
+            };
+            let per_rank = results.iter().map(rows).collect::<Vec<_>>();
+            let iterations = per_rank.iter().map(|r| r.len()).min().unwrap_or(0);
+            let copies = per_rank
+                .iter()
+                .filter_map(|r| r.first())
+                .flat_map(|row| row.copies.split(';').filter_map(|c| c.parse().ok()))
+                .collect();
+            let bytes = ByteSize(size * results.len() as u64);
+            let samples = (0..iterations)
+                .map(|i| {
+                    let slowest = |f: fn(&HistoryRow) -> f64| {
+                        Duration::from_secs_f64(
+                            per_rank.iter().map(|r| f(&r[i])).fold(0.0, f64::max),
+                        )
+                    };
+                    let total_time = slowest(|r| r.total_time_s);
+                    Sample {
+                        iteration: i,
+                        copy_time: slowest(|r| r.copy_time_s),
+                        sync_time: slowest(|r| r.sync_time_s),
+                        total_time,
+                        bandwidth: Bandwidth::from_transfer(bytes, total_time),
+                        copy_times: Vec::new(),
+                    }
+                })
+                .collect();
+            BenchResult {
+                scenario: format!("{} x{} processes", scenario, results.len()),
+                copies,
+                bytes,
+                warmup: 0,
+                samples,
+            }
+        })
+        .collect()
+}
@@ -0,0 +1,212 @@
+//! Peer access control and all-pairs device-to-device bandwidth and latency,
+//! in the spirit of the CUDA samples' `p2pBandwidthLatencyTest`.
+
+use cudarc::driver::sys;
+
+use crate::*;
+
+pub fn can_access_peer(device: i32, peer: i32) -> bool {
+    LazyLock::force(&INIT);
+    #[cfg(feature = "fault-injection")]
+    match crate::fault::inject(crate::fault::Call::PeerAccess) {
+        Err(_) => return false,
+        Ok(Some(delay)) => std::thread::sleep(delay),
+        Ok(None) => {}
+    }
+    let mut can_access = 0;
+    unsafe { sys::cuDeviceCanAccessPeer(&mut can_access, device, peer) }
+        .result()
+        .unwrap();
+    can_access == 1
+}
+
+/// Lets `ctx` access memory in `peer`. Returns false if the devices can't be
+/// peers; enabling twice is not an error.
+pub fn enable_peer_access(ctx: &Context, peer: &Context) -> bool {
+    if ctx.device_id == peer.device_id || !can_access_peer(ctx.device_id, peer.device_id) {
+        return false;
+    }
+    ctx.set_current();
+    match unsafe { sys::cuCtxEnablePeerAccess(peer.ctx, 0) } {
+        sys::CUresult::CUDA_SUCCESS | sys::CUresult::CUDA_ERROR_PEER_ACCESS_ALREADY_ENABLED => true,
+        err => panic!("cuCtxEnablePeerAccess failed: {:?}", err),
+    }
+}
+
+/// Revokes access enabled by `enable_peer_access`; a no-op if it wasn't enabled.
+pub fn disable_peer_access(ctx: &Context, peer: &Context) {
+    ctx.set_current();
+    match unsafe { sys::cuCtxDisablePeerAccess(peer.ctx) } {
+        sys::CUresult::CUDA_SUCCESS | sys::CUresult::CUDA_ERROR_PEER_ACCESS_NOT_ENABLED => {}
+        err => panic!("cuCtxDisablePeerAccess failed: {:?}", err),
+    }
+}
+
+/// Enables (or disables) peer access between every pair of `ctxs`, returning
+/// which ordered pairs `(i, j)` have `i` able to access `j`.
+pub fn set_all_peer_access(ctxs: &[Context], enabled: bool) -> Vec<Vec<bool>> {
+    ctxs.iter()
+        .map(|ctx| {
+            ctxs.iter()
+                .map(|peer| {
+                    if ctx.device_id == peer.device_id {
+                        true
+                    } else if enabled {
+                        enable_peer_access(ctx, peer)
+                    } else {
+                        disable_peer_access(ctx, peer);
+                        false
+                    }
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// An N×N table indexed by `[src][dst]`.
+#[derive(Debug, Clone)]
+pub struct Matrix {
+    pub name: String,
+    pub unit: &'static str,
+    pub devices: Vec<i32>,
+    pub values: Vec<Vec<Option<f64>>>,
+}
+
+impl std::fmt::Display for Matrix {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(f, "{} ({}), rows = src, cols = dst", self.name, self.unit)?;
+        write!(f, "{:>8}", "src\\dst")?;
+        for dst in &self.devices {
+            write!(f, "{:>10}", dst)?;
+        }
+        writeln!(f)?;
+        for (src, row) in self.devices.iter().zip(&self.values) {
+            write!(f, "{:>8}", src)?;
+            for value in row {
+                match value {
+                    Some(v) => write!(f, "{:>10.2}", v)?,
+                    None => write!(f, "{:>10}", "-")?,
+                }
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+/// Times `repeat` back-to-back copies of `src` into `dst` on `stream` with
+/// GPU events, returning the per-copy time.
+fn timed_copies(
+    stream: &Stream,
+    dst: &Buffer,
+    src: &Buffer,
+    repeat: usize,
+) -> (Event, Event, usize) {
+    let start = stream.ctx.create_timing_event();
+    let end = stream.ctx.create_timing_event();
+    stream.record_event(&start);
+    for _ in 0..repeat {
+        stream.memcpy_async(dst, src);
+    }
+    stream.record_event(&end);
+    (start, end, repeat)
+}
+
+/// Reads the time of `timed_copies` and destroys its events.
+fn per_copy_secs((start, end, repeat): (Event, Event, usize)) -> f64 {
+    end.synchronize();
+    let secs = end.elapsed_since(&start).as_secs_f64() / repeat as f64;
+    start.destroy();
+    end.destroy();
+    secs
+}
+
+/// Copy bandwidth between every pair of devices in GB/s, with copies issued on
+/// the source device's stream. When `bidirectional`, `dst -> src` runs
+/// concurrently on the destination's stream and the two rates are summed.
+pub fn bandwidth_matrix(
+    streams: &[Stream],
+    size: usize,
+    repeat: usize,
+    bidirectional: bool,
+) -> Matrix {
+    let bufs = streams
+        .iter()
+        .map(|s| {
+            (
+                s.create_buffer_async(size, AddressSpace::Device),
+                s.create_buffer_async(size, AddressSpace::Device),
+            )
+        })
+        .collect::<Vec<_>>();
+    for s in streams {
+        s.synchronize();
+    }
+
+    let mut values = vec![vec![None; streams.len()]; streams.len()];
+    for (i, row) in values.iter_mut().enumerate() {
+        for (j, value) in row.iter_mut().enumerate() {
+            // warm up the path
+            streams[i].memcpy_async(&bufs[j].1, &bufs[i].0);
+            streams[i].synchronize();
+
+            let forward = timed_copies(&streams[i], &bufs[j].1, &bufs[i].0, repeat);
+            let backward = (bidirectional && i != j)
+                .then(|| timed_copies(&streams[j], &bufs[i].1, &bufs[j].0, repeat));
+            let mut gb_s = size as f64 / per_copy_secs(forward) / 1e9;
+            if let Some(backward) = backward {
+                gb_s += size as f64 / per_copy_secs(backward) / 1e9;
+            }
+            *value = Some(gb_s);
+        }
+    }
+
+    for (s, (a, b)) in streams.iter().zip(&bufs) {
+        s.free_buffer_sync(a);
+        s.free_buffer_sync(b);
+    }
+    Matrix {
+        name: format!(
+            "{} bandwidth, {}",
+            if bidirectional {
+                "Bidirectional"
+            } else {
+                "Unidirectional"
+            },
+            ByteSize::from(size)
+        ),
+        unit: "GB/s",
+        devices: streams.iter().map(|s| s.ctx.device_id).collect(),
+        values,
+    }
+}
+
+/// Per-copy latency of tiny copies between every pair of devices, in µs.
+pub fn latency_matrix(streams: &[Stream], repeat: usize) -> Matrix {
+    const SIZE: usize = 4;
+    let bufs = streams
+        .iter()
+        .map(|s| s.create_buffer_async(SIZE, AddressSpace::Device))
+        .collect::<Vec<_>>();
+    for s in streams {
+        s.synchronize();
+    }
+
+    let mut values = vec![vec![None; streams.len()]; streams.len()];
+    for (i, row) in values.iter_mut().enumerate() {
+        for (j, value) in row.iter_mut().enumerate() {
+            let copies = timed_copies(&streams[i], &bufs[j], &bufs[i], repeat);
+            *value = Some(per_copy_secs(copies) * 1e6);
+        }
+    }
+
+    for (s, buf) in streams.iter().zip(&bufs) {
+        s.free_buffer_sync(buf);
+    }
+    Matrix {
+        name: String::from("Latency"),
+        unit: "us",
+        devices: streams.iter().map(|s| s.ctx.device_id).collect(),
+        values,
+    }
+}
@@ -0,0 +1,408 @@
+//! Chooses and runs a route for a copy between buffers on different devices:
+//! a direct peer copy, a relay through GPUs that are peers of each other, or
+//! staging through pinned host memory.
+
+use std::collections::VecDeque;
+
+use crate::collectives::EventPool;
+use crate::topology::{Relation, Topology};
+use crate::*;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Route {
+    /// One `memcpy_async`: same device, a host endpoint, or peer access.
+    Direct,
+    /// Device to pinned host to device, pipelined in chunks.
+    Staged,
+    /// Through the listed intermediate devices (stream indices), each hop a
+    /// peer copy, pipelined in chunks.
+    MultiHop(Vec<usize>),
+}
+
+/// A chosen route and why.
+#[derive(Debug, Clone)]
+pub struct Plan {
+    pub route: Route,
+    /// Indices into the planner's streams of the source and destination.
+    pub src: usize,
+    pub dst: usize,
+    pub reason: String,
+}
+
+impl std::fmt::Display for Plan {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match &self.route {
+            Route::Direct => write!(f, "direct: {}", self.reason),
+            Route::Staged => write!(f, "staged through pinned host: {}", self.reason),
+            Route::MultiHop(via) => write!(f, "multi-hop via {:?}: {}", via, self.reason),
+        }
+    }
+}
+
+/// Plans copies between buffers owned by `streams` (one stream per device).
+/// `peer_access[i][j]` says whether `streams[i]`'s device can access
+/// `streams[j]`'s, as returned by `p2p::set_all_peer_access`.
+pub struct TransferPlanner {
+    pub streams: Vec<Stream>,
+    pub peer_access: Vec<Vec<bool>>,
+    /// Used to rank candidate routes and explain choices.
+    pub topology: Option<Topology>,
+    /// Size of each pipelined piece of a staged or multi-hop copy.
+    pub chunk_size: usize,
+    staging: Vec<Staging>,
+    events: EventPool,
+}
+
+/// A bounce buffer owned by one of the planner's streams.
+struct Staging {
+    stream: usize,
+    address_space: AddressSpace,
+    buffer: Buffer,
+    /// Recorded once the last `execute` using the buffer has copied
+    /// everything out of it; the next one waits on it before refilling.
+    free: Event,
+    used: bool,
+}
+
+/// Chunks in flight per intermediate buffer.
+const DEPTH: usize = 2;
+
+impl TransferPlanner {
+    pub fn new(streams: &[Stream], peer_access: Vec<Vec<bool>>) -> Self {
+        assert_eq!(peer_access.len(), streams.len());
+        Self {
+            streams: streams.to_vec(),
+            peer_access,
+            topology: None,
+            chunk_size: ByteSize::mib(4).as_usize(),
+            staging: Vec::new(),
+            events: EventPool::default(),
+        }
+    }
+
+    pub fn topology(mut self, topology: Topology) -> Self {
+        self.topology = Some(topology);
+        self
+    }
+
+    pub fn chunk_size(mut self, chunk_size: usize) -> Self {
+        assert!(chunk_size > 0);
+        self.chunk_size = chunk_size;
+        self
+    }
+
+    fn device(&self, idx: usize) -> i32 {
+        self.streams[idx].ctx.device_id
+    }
+
+    fn stream_of(&self, buf: &Buffer) -> usize {
+        self.streams
+            .iter()
+            .position(|s| s.ctx.ctx == buf.ctx.ctx)
+            .or_else(|| {
+                self.streams
+                    .iter()
+                    .position(|s| s.ctx.device_id == buf.ctx.device_id)
+            })
+            .expect("buffer belongs to none of the planner's devices")
+    }
+
+    fn relation(&self, a: usize, b: usize) -> Option<Relation> {
+        self.topology
+            .as_ref()
+            .map(|t| t.relation(self.device(a), self.device(b)))
+    }
+
+    fn describe(&self, a: usize, b: usize) -> String {
+        match self.relation(a, b) {
+            Some(r) => format!("GPU{} and GPU{} ({:?})", self.device(a), self.device(b), r),
+            None => format!("GPU{} and GPU{}", self.device(a), self.device(b)),
+        }
+    }
+
+    /// Shortest chain of peer copies from `src` to `dst`, preferring
+    /// topologically closer neighbours; excludes the endpoints.
+    fn peer_path(&self, src: usize, dst: usize) -> Option<Vec<usize>> {
+        let n = self.streams.len();
+        let mut prev = vec![None; n];
+        let mut seen = vec![false; n];
+        let mut queue = VecDeque::from([src]);
+        seen[src] = true;
+        while let Some(at) = queue.pop_front() {
+            if at == dst {
+                let mut path = Vec::new();
+                let mut node = prev[dst];
+                while let Some(p) = node.filter(|&p| p != src) {
+                    path.push(p);
+                    node = prev[p];
+                }
+                path.reverse();
+                return Some(path);
+            }
+            let mut next = (0..n)
+                .filter(|&k| !seen[k] && self.peer_access[at][k])
+                .collect::<Vec<_>>();
+            next.sort_by_key(|&k| self.relation(at, k));
+            for k in next {
+                seen[k] = true;
+                prev[k] = Some(at);
+                queue.push_back(k);
+            }
+        }
+        None
+    }
+
+    pub fn plan(&self, dst: &Buffer, src: &Buffer) -> Plan {
+        assert_eq!(dst.size, src.size, "size mismatch");
+        let (s, d) = (self.stream_of(src), self.stream_of(dst));
+        let plan = |route, reason: String| Plan {
+            route,
+            src: s,
+            dst: d,
+            reason,
+        };
+        if src.address_space.is_host() || dst.address_space.is_host() {
+            return plan(Route::Direct, String::from("host endpoint"));
+        }
+        if src.ctx.device_id == dst.ctx.device_id {
+            return plan(Route::Direct, format!("both on GPU{}", src.ctx.device_id));
+        }
+        if self.peer_access[d][s] || self.peer_access[s][d] {
+            return plan(
+                Route::Direct,
+                format!("peer access between {}", self.describe(s, d)),
+            );
+        }
+        match self.peer_path(s, d) {
+            Some(via) => {
+                let devices = via.iter().map(|&k| self.device(k)).collect::<Vec<_>>();
+                plan(
+                    Route::MultiHop(via),
+                    format!(
+                        "no peer access between {}; relaying through GPU {:?}",
+                        self.describe(s, d),
+                        devices
+                    ),
+                )
+            }
+            None => plan(
+                Route::Staged,
+                format!("no peer path between {}", self.describe(s, d)),
+            ),
+        }
+    }
+
+    /// Index into `staging` of a bounce buffer on `stream`, allocating one if
+    /// needed.
+    fn staging_buffer(&mut self, stream: usize, address_space: AddressSpace) -> usize {
+        let size = self.chunk_size * DEPTH;
+        if let Some(idx) = self.staging.iter().position(|s| {
+            s.stream == stream && s.address_space == address_space && s.buffer.size == size
+        }) {
+            return idx;
+        }
+        let owner = &self.streams[stream];
+        self.staging.push(Staging {
+            stream,
+            buffer: owner.create_buffer_async(size, address_space.clone()),
+            address_space,
+            free: owner.ctx.create_event(),
+            used: false,
+        });
+        self.staging.len() - 1
+    }
+
+    /// Issues `plan` asynchronously. The destination's stream waits for the
+    /// copy, so work queued on it afterwards sees the data. Bounce buffers are
+    /// shared between executions; each one waits until the previous one has
+    /// drained them.
+    pub fn execute(&mut self, plan: &Plan, dst: &Buffer, src: &Buffer) {
+        self.events.reset();
+        // Each stage: the buffer, the stream that copies out of it, and its
+        // index in `staging` for bounce buffers.
+        let mut stages = vec![(src.clone(), plan.src, None)];
+        match &plan.route {
+            Route::Direct => {
+                // Copy on the device side of the transfer.
+                let stream = if src.address_space.is_host() {
+                    plan.dst
+                } else {
+                    plan.src
+                };
+                let ready = self.events.record(&self.streams[plan.src]);
+                self.streams[stream].wait_for_event(&ready);
+                self.streams[stream].memcpy_async(dst, src);
+                let done = self.events.record(&self.streams[stream]);
+                self.streams[plan.dst].wait_for_event(&done);
+                return;
+            }
+            Route::Staged => {
+                let idx = self.staging_buffer(plan.src, AddressSpace::Pinned);
+                stages.push((self.staging[idx].buffer.clone(), plan.dst, Some(idx)));
+            }
+            Route::MultiHop(via) => {
+                for &k in via {
+                    let idx = self.staging_buffer(k, AddressSpace::Device);
+                    stages.push((self.staging[idx].buffer.clone(), k, Some(idx)));
+                }
+            }
+        }
+        stages.push((dst.clone(), plan.dst, None));
+
+        let hops = stages.len() - 1;
+        let n_chunks = src.size.div_ceil(self.chunk_size);
+        // done[h][c]: hop h of chunk c has completed
+        let mut done: Vec<Vec<Event>> = vec![Vec::with_capacity(n_chunks); hops];
+        for c in 0..n_chunks {
+            let offset = c * self.chunk_size;
+            let len = self.chunk_size.min(src.size - offset);
+            let slot = (c % DEPTH) * self.chunk_size;
+            // Intermediate stages are ring buffers; the endpoints are not.
+            let range = |h: usize| {
+                if h == 0 || h == hops {
+                    (offset, len)
+                } else {
+                    (slot, len)
+                }
+            };
+            for h in 0..hops {
+                let stream = &self.streams[stages[h].1];
+                if h > 0 {
+                    stream.wait_for_event(&done[h - 1][c]);
+                }
+                // The slot we're about to overwrite must have been copied out,
+                // by this execution or for the first chunks the previous one.
+                if h + 1 < hops {
+                    if c >= DEPTH {
+                        stream.wait_for_event(&done[h + 1][c - DEPTH]);
+                    } else if let Some(idx) = stages[h + 1].2
+                        && self.staging[idx].used
+                    {
+                        stream.wait_for_event(&self.staging[idx].free);
+                    }
+                }
+                let (src_off, src_len) = range(h);
+                let (dst_off, dst_len) = range(h + 1);
+                stream.memcpy_async(
+                    &stages[h + 1].0.view(dst_off, dst_len),
+                    &stages[h].0.view(src_off, src_len),
+                );
+                done[h].push(self.events.record(stream));
+            }
+        }
+        if let Some(last) = done[hops - 1].last() {
+            self.streams[plan.dst].wait_for_event(last);
+        }
+        for (_, stream, idx) in &stages {
+            if let Some(idx) = *idx {
+                let staging = &mut self.staging[idx];
+                self.streams[*stream].record_event(&staging.free);
+                staging.used = true;
+            }
+        }
+    }
+
+    /// Plans and executes a copy, returning the plan.
+    pub fn copy(&mut self, dst: &Buffer, src: &Buffer) -> Plan {
+        let plan = self.plan(dst, src);
+        log_debug!("{}", plan);
+        self.execute(&plan, dst, src);
+        plan
+    }
+
+    /// Frees bounce buffers and the events guarding them.
+    pub fn release(&mut self) {
+        for stream in &self.streams {
+            stream.synchronize();
+        }
+        for staging in self.staging.drain(..) {
+            self.streams[staging.stream].free_buffer_sync(&staging.buffer);
+            staging.free.destroy();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A fake context, never passed to the driver. Distinct per device so
+    /// `stream_of` can tell the buffers apart.
+    fn context(device_id: i32) -> Context {
+        Context {
+            ctx: std::ptr::without_provenance_mut(device_id as usize + 1),
+            device_id,
+        }
+    }
+
+    /// A planner over `n` fake devices where `links` are the pairs with
+    /// peer access (both ways).
+    fn planner(n: i32, links: &[(usize, usize)]) -> TransferPlanner {
+        let streams = (0..n)
+            .map(|d| Stream {
+                ctx: context(d),
+                stream: std::ptr::null_mut(),
+            })
+            .collect::<Vec<_>>();
+        let mut peer_access = vec![vec![false; n as usize]; n as usize];
+        for &(a, b) in links {
+            peer_access[a][b] = true;
+            peer_access[b][a] = true;
+        }
+        TransferPlanner::new(&streams, peer_access)
+    }
+
+    fn buffer(device_id: i32, address_space: AddressSpace) -> Buffer {
+        Buffer {
+            ctx: context(device_id),
+            size: 1 << 20,
+            address_space,
+            addr: 0,
+            pages: HugePages::None,
+            is_view: false,
+        }
+    }
+
+    #[test]
+    fn peer_path_excludes_endpoints() {
+        let p = planner(4, &[(0, 1), (1, 2), (2, 3)]);
+        assert_eq!(p.peer_path(0, 3), Some(vec![1, 2]));
+        assert_eq!(p.peer_path(3, 0), Some(vec![2, 1]));
+        assert_eq!(p.peer_path(0, 1), Some(vec![]));
+        assert_eq!(p.peer_path(2, 2), Some(vec![]));
+    }
+
+    #[test]
+    fn peer_path_takes_the_fewest_hops() {
+        let p = planner(5, &[(0, 1), (1, 2), (2, 3), (3, 4), (0, 3)]);
+        assert_eq!(p.peer_path(0, 4), Some(vec![3]));
+    }
+
+    #[test]
+    fn no_peer_path_between_islands() {
+        let p = planner(4, &[(0, 1), (2, 3)]);
+        assert_eq!(p.peer_path(0, 3), None);
+        assert_eq!(p.peer_path(1, 2), None);
+    }
+
+    #[test]
+    fn plans_routes() {
+        let p = planner(4, &[(0, 1), (1, 2)]);
+        let dev = |d| buffer(d, AddressSpace::Device);
+        let route = |dst: &Buffer, src: &Buffer| p.plan(dst, src).route;
+        assert_eq!(
+            route(&buffer(0, AddressSpace::Pinned), &dev(2)),
+            Route::Direct
+        );
+        assert_eq!(route(&dev(1), &dev(1)), Route::Direct);
+        assert_eq!(route(&dev(1), &dev(0)), Route::Direct);
+        assert_eq!(route(&dev(2), &dev(0)), Route::MultiHop(vec![1]));
+        assert_eq!(route(&dev(3), &dev(0)), Route::Staged);
+        let plan = p.plan(&dev(3), &dev(0));
+        assert_eq!((plan.src, plan.dst), (0, 3));
+        assert_eq!(
+            plan.to_string(),
+            "staged through pinned host: no peer path between GPU0 and GPU3"
+        );
+    }
+}
@@ -0,0 +1,561 @@
+//! Structured benchmark output: JSON lines, CSV and a Markdown summary.
+
+use std::io::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::bench::{BenchResult, CopyInfo, Sample};
+use crate::latency::LatencyStats;
+use crate::log::json_string;
+use crate::p2p::Matrix;
+use crate::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Format {
+    /// Summary log lines on stderr only.
+    #[default]
+    Text,
+    /// One JSON object per line: an `environment` record, then one per sample.
+    Json,
+    /// A header row, then one row per sample.
+    Csv,
+    /// A summary table per scenario, written when the run finishes.
+    Markdown,
+}
+
+impl std::str::FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "text" => Ok(Format::Text),
+            "json" | "jsonl" => Ok(Format::Json),
+            "csv" => Ok(Format::Csv),
+            "markdown" | "md" => Ok(Format::Markdown),
+            _ => Err(format!(
+                "unknown format {:?} (expected text, json, csv or markdown)",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DeviceInfo {
+    pub ordinal: i32,
+    pub name: String,
+    pub pci_bus_id: String,
+}
+
+/// Where and when a run happened.
+#[derive(Debug, Clone)]
+pub struct Environment {
+    /// Seconds since the Unix epoch at collection time; identifies the run.
+    pub timestamp: u64,
+    pub hostname: String,
+    pub crate_version: &'static str,
+    pub driver_version: i32,
+    pub devices: Vec<DeviceInfo>,
+    /// CPUs benchmark threads were bound to (`Affinity::describe`); empty if
+    /// unbound.
+    pub cpu_binding: String,
+}
+
+impl Environment {
+    pub fn collect(ordinals: &[i32]) -> Self {
+        Self {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            hostname: hostname(),
+            crate_version: env!("CARGO_PKG_VERSION"),
+            driver_version: driver_version(),
+            devices: ordinals
+                .iter()
+                .map(|&ordinal| DeviceInfo {
+                    ordinal,
+                    name: device_name(ordinal),
+                    pci_bus_id: pci_bus_id(ordinal),
+                })
+                .collect(),
+            cpu_binding: String::new(),
+        }
+    }
+
+    fn device_names(&self) -> String {
+        self.devices
+            .iter()
+            .map(|d| format!("{}:{}", d.ordinal, d.name))
+            .collect::<Vec<_>>()
+            .join(";")
+    }
+}
+
+pub fn hostname() -> String {
+    let mut name = [0 as libc::c_char; 256];
+    if unsafe { libc::gethostname(name.as_mut_ptr(), name.len()) } != 0 {
+        return String::from("unknown");
+    }
+    unsafe { std::ffi::CStr::from_ptr(name.as_ptr()) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+pub const CSV_HEADER: &str = "timestamp,hostname,driver_version,devices,scenario,copies,total_bytes,iteration,copy_time_s,sync_time_s,total_time_s,bandwidth_gb_s,direction_gb_s";
+
+/// Quotes a CSV field if it contains a delimiter, quote or newline.
+pub fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+/// Splits one CSV line into fields, undoing `csv_field` quoting.
+pub fn parse_csv_line(line: &str) -> Vec<String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, quoted) {
+            ('"', true) if chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            ('"', _) => quoted = !quoted,
+            (',', false) => fields.push(std::mem::take(&mut field)),
+            (c, _) => field.push(c),
+        }
+    }
+    fields.push(field);
+    fields
+}
+
+/// Per-direction bandwidths as `H2D=12.3456;D2H=11.0000` (GB/s).
+fn directions_str(result: &BenchResult, sample: &Sample) -> String {
+    result
+        .sample_direction_bandwidths(sample)
+        .iter()
+        .map(|(d, bw)| format!("{:?}={:.4}", d, bw.gb_s()))
+        .collect::<Vec<_>>()
+        .join(";")
+}
+
+fn copies_str(copies: &[CopyInfo]) -> String {
+    copies
+        .iter()
+        .map(|c| c.to_string())
+        .collect::<Vec<_>>()
+        .join(";")
+}
+
+pub fn csv_row(env: &Environment, result: &BenchResult, sample: &Sample) -> String {
+    [
+        env.timestamp.to_string(),
+        csv_field(&env.hostname),
+        env.driver_version.to_string(),
+        csv_field(&env.device_names()),
+        csv_field(&result.scenario),
+        csv_field(&copies_str(&result.copies)),
+        result.bytes.bytes().to_string(),
+        sample.iteration.to_string(),
+        format!("{:.9}", sample.copy_time.as_secs_f64()),
+        format!("{:.9}", sample.sync_time.as_secs_f64()),
+        format!("{:.9}", sample.total_time.as_secs_f64()),
+        format!("{:.4}", sample.bandwidth.gb_s()),
+        directions_str(result, sample),
+    ]
+    .join(",")
+}
+
+pub fn json_environment(env: &Environment) -> String {
+    let devices = env
+        .devices
+        .iter()
+        .map(|d| {
+            format!(
+                "{{\"ordinal\":{},\"name\":{},\"pci_bus_id\":{}}}",
+                d.ordinal,
+                json_string(&d.name),
+                json_string(&d.pci_bus_id)
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(",");
+    format!(
+        "{{\"type\":\"environment\",\"timestamp\":{},\"hostname\":{},\"crate_version\":{},\"driver_version\":{},\"devices\":[{}],\"cpu_binding\":{}}}",
+        env.timestamp,
+        json_string(&env.hostname),
+        json_string(env.crate_version),
+        env.driver_version,
+        devices,
+        json_string(&env.cpu_binding)
+    )
+}
+
+/// `value` with `precision` decimals, or `null` if it has no JSON form
+/// (infinite or NaN, e.g. a bandwidth over zero time).
+fn json_number(value: f64, precision: usize) -> String {
+    if value.is_finite() {
+        format!("{:.*}", precision, value)
+    } else {
+        String::from("null")
+    }
+}
+
+pub fn json_sample(env: &Environment, result: &BenchResult, sample: &Sample) -> String {
+    let copies = result
+        .copies
+        .iter()
+        .map(|c| {
+            format!(
+                "{{\"src\":\"{:?}\",\"src_device\":{},\"dst\":\"{:?}\",\"dst_device\":{}}}",
+                c.src, c.src_device, c.dst, c.dst_device
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(",");
+    let directions = result
+        .sample_direction_bandwidths(sample)
+        .iter()
+        .map(|(d, bw)| format!("\"{:?}\":{}", d, json_number(bw.gb_s(), 4)))
+        .collect::<Vec<_>>()
+        .join(",");
+    format!(
+        "{{\"type\":\"sample\",\"timestamp\":{},\"scenario\":{},\"copies\":[{}],\"total_bytes\":{},\"iteration\":{},\"copy_time_s\":{:.9},\"sync_time_s\":{:.9},\"total_time_s\":{:.9},\"bandwidth_gb_s\":{},\"direction_gb_s\":{{{}}}}}",
+        env.timestamp,
+        json_string(&result.scenario),
+        copies,
+        result.bytes.bytes(),
+        sample.iteration,
+        sample.copy_time.as_secs_f64(),
+        sample.sync_time.as_secs_f64(),
+        sample.total_time.as_secs_f64(),
+        json_number(sample.bandwidth.gb_s(), 4),
+        directions
+    )
+}
+
+pub fn markdown_table(env: &Environment, results: &[BenchResult]) -> String {
+    let mut out = format!(
+        "- host: {}\n- driver: {}\n- devices: {}\n- CPU binding: {}\n\n",
+        env.hostname,
+        env.driver_version,
+        env.device_names(),
+        if env.cpu_binding.is_empty() {
+            "none"
+        } else {
+            &env.cpu_binding
+        }
+    );
+    out += "| Scenario | Total | Iters | Mean BW | Best BW | Median time | Per direction |\n";
+    out += "|---|---:|---:|---:|---:|---:|---|\n";
+    for result in results {
+        let directions = result
+            .direction_bandwidths()
+            .iter()
+            .map(|(d, bw)| format!("{:?} {}", d, bw))
+            .collect::<Vec<_>>()
+            .join(", ");
+        out += &format!(
+            "| {} | {} | {} | {} | {} | {:?} | {} |\n",
+            result.scenario.replace('|', "\\|"),
+            result.bytes,
+            result.samples.len(),
+            result.mean_bandwidth(),
+            result.best_bandwidth(),
+            result.median_total_time(),
+            directions
+        );
+    }
+    out
+}
+
+/// Writes results in one format as they come in.
+pub struct Reporter {
+    pub format: Format,
+    pub env: Environment,
+    out: Box<dyn Write>,
+    results: Vec<BenchResult>,
+}
+
+impl Reporter {
+    pub fn new(format: Format, env: Environment, mut out: Box<dyn Write>) -> Self {
+        match format {
+            Format::Json => writeln!(out, "{}", json_environment(&env)).unwrap(),
+            Format::Csv => writeln!(out, "{}", CSV_HEADER).unwrap(),
+            Format::Text | Format::Markdown => {}
+        }
+        Self {
+            format,
+            env,
+            out,
+            results: Vec::new(),
+        }
+    }
+
+    pub fn report(&mut self, result: &BenchResult) {
+        match self.format {
+            Format::Text => log!(
+                "=== {}: mean {}, best {}",
+                result.scenario,
+                result.mean_bandwidth(),
+                result.best_bandwidth()
+            ),
+            Format::Json => {
+                for sample in &result.samples {
+                    writeln!(self.out, "{}", json_sample(&self.env, result, sample)).unwrap();
+                }
+            }
+            Format::Csv => {
+                for sample in &result.samples {
+                    writeln!(self.out, "{}", csv_row(&self.env, result, sample)).unwrap();
+                }
+            }
+            Format::Markdown => {}
+        }
+        self.out.flush().unwrap();
+        self.results.push(result.clone());
+    }
+
+    pub fn finish(mut self) -> Vec<BenchResult> {
+        if self.format == Format::Markdown {
+            write!(self.out, "{}", markdown_table(&self.env, &self.results)).unwrap();
+        }
+        self.out.flush().unwrap();
+        self.results
+    }
+}
+
+pub const LATENCY_CSV_HEADER: &str =
+    "timestamp,hostname,name,samples,min_s,p50_s,p90_s,p99_s,max_s";
+
+/// Writes latency percentiles in `format`; `Text` and `Markdown` both produce a table.
+pub fn write_latency(
+    out: &mut dyn Write,
+    format: Format,
+    env: &Environment,
+    stats: &[LatencyStats],
+) -> std::io::Result<()> {
+    match format {
+        Format::Json => {
+            writeln!(out, "{}", json_environment(env))?;
+            for s in stats {
+                writeln!(
+                    out,
+                    "{{\"type\":\"latency\",\"timestamp\":{},\"name\":{},\"samples\":{},\"min_s\":{:.9},\"p50_s\":{:.9},\"p90_s\":{:.9},\"p99_s\":{:.9},\"max_s\":{:.9}}}",
+                    env.timestamp,
+                    json_string(&s.name),
+                    s.samples,
+                    s.min.as_secs_f64(),
+                    s.p50.as_secs_f64(),
+                    s.p90.as_secs_f64(),
+                    s.p99.as_secs_f64(),
+                    s.max.as_secs_f64()
+                )?;
+            }
+        }
+        Format::Csv => {
+            writeln!(out, "{}", LATENCY_CSV_HEADER)?;
+            for s in stats {
+                writeln!(
+                    out,
+                    "{},{},{},{},{:.9},{:.9},{:.9},{:.9},{:.9}",
+                    env.timestamp,
+                    csv_field(&env.hostname),
+                    csv_field(&s.name),
+                    s.samples,
+                    s.min.as_secs_f64(),
+                    s.p50.as_secs_f64(),
+                    s.p90.as_secs_f64(),
+                    s.p99.as_secs_f64(),
+                    s.max.as_secs_f64()
+                )?;
+            }
+        }
+        Format::Text | Format::Markdown => {
+            writeln!(out, "| Measurement | n | min | p50 | p90 | p99 | max |")?;
+            writeln!(out, "|---|---:|---:|---:|---:|---:|---:|")?;
+            for s in stats {
+                writeln!(
+                    out,
+                    "| {} | {} | {:?} | {:?} | {:?} | {:?} | {:?} |",
+                    s.name, s.samples, s.min, s.p50, s.p90, s.p99, s.max
+                )?;
+            }
+        }
+    }
+    Ok(())
+}
+
+pub const MATRIX_CSV_HEADER: &str = "timestamp,hostname,matrix,src,dst,value,unit";
+
+/// Writes device-pair matrices in `format`; `Text` and `Markdown` both print
+/// the N×N tables, the others one record per cell.
+pub fn write_matrices(
+    out: &mut dyn Write,
+    format: Format,
+    env: &Environment,
+    matrices: &[Matrix],
+) -> std::io::Result<()> {
+    let cells = |m: &'_ Matrix| {
+        m.devices
+            .iter()
+            .zip(&m.values)
+            .flat_map(|(&src, row)| {
+                m.devices
+                    .iter()
+                    .zip(row)
+                    .filter_map(move |(&dst, v)| v.map(|v| (src, dst, v)))
+            })
+            .collect::<Vec<_>>()
+    };
+    match format {
+        Format::Json => {
+            writeln!(out, "{}", json_environment(env))?;
+            for m in matrices {
+                for (src, dst, value) in cells(m) {
+                    writeln!(
+                        out,
+                        "{{\"type\":\"matrix\",\"timestamp\":{},\"matrix\":{},\"src\":{},\"dst\":{},\"value\":{},\"unit\":{}}}",
+                        env.timestamp,
+                        json_string(&m.name),
+                        src,
+                        dst,
+                        json_number(value, 4),
+                        json_string(m.unit)
+                    )?;
+                }
+            }
+        }
+        Format::Csv => {
+            writeln!(out, "{}", MATRIX_CSV_HEADER)?;
+            for m in matrices {
+                for (src, dst, value) in cells(m) {
+                    writeln!(
+                        out,
+                        "{},{},{},{},{},{:.4},{}",
+                        env.timestamp,
+                        csv_field(&env.hostname),
+                        csv_field(&m.name),
+                        src,
+                        dst,
+                        value,
+                        m.unit
+                    )?;
+                }
+            }
+        }
+        Format::Text | Format::Markdown => {
+            for m in matrices {
+                writeln!(out, "{}", m)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn csv_field_quoting() {
+        assert_eq!(csv_field("plain"), "plain");
+        assert_eq!(csv_field(""), "");
+        assert_eq!(csv_field("a,b"), "\"a,b\"");
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+        assert_eq!(csv_field("two\nlines"), "\"two\nlines\"");
+        assert_eq!(csv_field("cr\r"), "\"cr\r\"");
+    }
+
+    #[test]
+    fn parse_csv_line_fields() {
+        assert_eq!(parse_csv_line("a,b,c"), ["a", "b", "c"]);
+        assert_eq!(parse_csv_line(""), [""]);
+        assert_eq!(parse_csv_line("a,,c,"), ["a", "", "c", ""]);
+        assert_eq!(
+            parse_csv_line("1,\"x,y\",\"say \"\"hi\"\"\",z"),
+            ["1", "x,y", "say \"hi\"", "z"]
+        );
+        assert_eq!(parse_csv_line("\"\"\"\""), ["\""]);
+    }
+
+    #[test]
+    fn csv_round_trip() {
+        let fields = ["host,1", "\"quoted\"", "GPU0 -> GPU1; \"a\",b", "", "plain"];
+        let line = fields.map(csv_field).join(",");
+        assert_eq!(parse_csv_line(&line), fields);
+    }
+
+    #[test]
+    fn header_matches_row_width() {
+        assert_eq!(parse_csv_line(CSV_HEADER).len(), 13);
+        assert_eq!(parse_csv_line(CSV_HEADER)[6], "total_bytes");
+    }
+
+    fn environment() -> Environment {
+        Environment {
+            timestamp: 1,
+            hostname: String::from("host"),
+            crate_version: "0.0.0",
+            driver_version: 0,
+            devices: Vec::new(),
+            cpu_binding: String::new(),
+        }
+    }
+
+    #[test]
+    fn json_numbers_are_finite_or_null() {
+        assert_eq!(json_number(1.23456, 4), "1.2346");
+        assert_eq!(json_number(f64::INFINITY, 4), "null");
+        assert_eq!(json_number(f64::NAN, 4), "null");
+    }
+
+    #[test]
+    fn json_sample_without_elapsed_time() {
+        let copy = CopyInfo {
+            src: AddressSpace::Pinned,
+            src_device: 0,
+            dst: AddressSpace::Device,
+            dst_device: 0,
+        };
+        let sample = Sample {
+            iteration: 0,
+            copy_time: Duration::ZERO,
+            sync_time: Duration::ZERO,
+            total_time: Duration::ZERO,
+            bandwidth: Bandwidth::from_transfer(ByteSize::mib(1), Duration::ZERO),
+            copy_times: vec![Duration::ZERO],
+        };
+        let result = BenchResult {
+            scenario: String::from("h2d"),
+            copies: vec![copy],
+            bytes: ByteSize::mib(1),
+            warmup: 0,
+            samples: vec![sample.clone()],
+        };
+        let json = json_sample(&environment(), &result, &sample);
+        assert!(json.contains("\"bandwidth_gb_s\":null"), "{json}");
+        assert!(json.contains(":null}}"), "{json}");
+        assert!(!json.contains("inf") && !json.contains("NaN"), "{json}");
+    }
+
+    #[test]
+    fn json_matrix_cells_without_a_value() {
+        let matrix = Matrix {
+            name: String::from("Latency"),
+            unit: "us",
+            devices: vec![0],
+            values: vec![vec![Some(f64::NAN)]],
+        };
+        let mut out = Vec::new();
+        write_matrices(&mut out, Format::Json, &environment(), &[matrix]).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("\"value\":null,"), "{out}");
+    }
+}
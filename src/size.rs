@@ -0,0 +1,427 @@
+//! Byte sizes and bandwidths with IEC (1024-based) and SI (1000-based) units.
+
+use std::fmt;
+use std::ops::{Add, AddAssign, Div, Mul, Sub};
+use std::str::FromStr;
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Units {
+    /// KiB, MiB, GiB, ... (powers of 1024).
+    #[default]
+    Iec,
+    /// KB, MB, GB, ... (powers of 1000).
+    Si,
+}
+
+impl Units {
+    fn base(self) -> f64 {
+        match self {
+            Units::Iec => 1024.0,
+            Units::Si => 1000.0,
+        }
+    }
+
+    fn prefixes(self) -> [&'static str; 6] {
+        match self {
+            Units::Iec => ["", "Ki", "Mi", "Gi", "Ti", "Pi"],
+            Units::Si => ["", "K", "M", "G", "T", "P"],
+        }
+    }
+}
+
+/// Scales `value` to the largest prefix that keeps it >= 1.
+fn scale(value: f64, units: Units) -> (f64, &'static str) {
+    let prefixes = units.prefixes();
+    let mut value = value;
+    let mut idx = 0;
+    while value.abs() >= units.base() && idx + 1 < prefixes.len() {
+        value /= units.base();
+        idx += 1;
+    }
+    (value, prefixes[idx])
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseSizeError(String);
+
+impl fmt::Display for ParseSizeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for ParseSizeError {}
+
+/// A number of bytes. Parses from strings like `8GiB`, `512MB`, `1.5 TiB`,
+/// `4k` or `0x200000`; bare `K`/`M`/`G`/... suffixes are 1024-based.
+/// Displays in IEC units; use `display(Units::Si)` for SI.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct ByteSize(pub u64);
+
+impl ByteSize {
+    pub const fn b(n: u64) -> Self {
+        Self(n)
+    }
+    pub const fn kib(n: u64) -> Self {
+        Self(n << 10)
+    }
+    pub const fn mib(n: u64) -> Self {
+        Self(n << 20)
+    }
+    pub const fn gib(n: u64) -> Self {
+        Self(n << 30)
+    }
+    pub const fn tib(n: u64) -> Self {
+        Self(n << 40)
+    }
+    pub const fn kb(n: u64) -> Self {
+        Self(n * 1_000)
+    }
+    pub const fn mb(n: u64) -> Self {
+        Self(n * 1_000_000)
+    }
+    pub const fn gb(n: u64) -> Self {
+        Self(n * 1_000_000_000)
+    }
+    pub const fn tb(n: u64) -> Self {
+        Self(n * 1_000_000_000_000)
+    }
+
+    pub const fn bytes(self) -> u64 {
+        self.0
+    }
+
+    pub const fn as_usize(self) -> usize {
+        self.0 as usize
+    }
+
+    /// Formats with two decimals in the given units, e.g. `8.00 GiB` or `8.59 GB`.
+    pub fn display(self, units: Units) -> String {
+        let (value, prefix) = scale(self.0 as f64, units);
+        if prefix.is_empty() {
+            format!("{} B", self.0)
+        } else {
+            format!("{:.2} {}B", value, prefix)
+        }
+    }
+}
+
+impl fmt::Display for ByteSize {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(&self.display(Units::Iec))
+    }
+}
+
+impl FromStr for ByteSize {
+    type Err = ParseSizeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || ParseSizeError(format!("invalid size {:?}", s));
+        let t = s.trim();
+        if let Some(hex) = t.strip_prefix("0x").or_else(|| t.strip_prefix("0X")) {
+            let hex = hex.replace('_', "");
+            return u64::from_str_radix(&hex, 16).map(Self).map_err(|_| err());
+        }
+
+        let split = t
+            .find(|c: char| !(c.is_ascii_digit() || c == '.' || c == '_'))
+            .unwrap_or(t.len());
+        let (number, unit) = t.split_at(split);
+        let number = number.replace('_', "");
+        if number.is_empty() {
+            return Err(err());
+        }
+        let multiplier: u64 = match unit.trim().to_ascii_lowercase().as_str() {
+            "" | "b" => 1,
+            "k" | "kib" => 1 << 10,
+            "m" | "mib" => 1 << 20,
+            "g" | "gib" => 1 << 30,
+            "t" | "tib" => 1 << 40,
+            "p" | "pib" => 1 << 50,
+            "kb" => 1_000,
+            "mb" => 1_000_000,
+            "gb" => 1_000_000_000,
+            "tb" => 1_000_000_000_000,
+            "pb" => 1_000_000_000_000_000,
+            _ => return Err(err()),
+        };
+        if let Ok(n) = number.parse::<u64>() {
+            return n.checked_mul(multiplier).map(Self).ok_or_else(err);
+        }
+        let n: f64 = number.parse().map_err(|_| err())?;
+        let bytes = (n * multiplier as f64).round();
+        // `u64::MAX as f64` is 2^64, which doesn't fit.
+        if !bytes.is_finite() || bytes >= u64::MAX as f64 {
+            return Err(err());
+        }
+        Ok(Self(bytes as u64))
+    }
+}
+
+impl From<usize> for ByteSize {
+    fn from(n: usize) -> Self {
+        Self(n as u64)
+    }
+}
+
+impl From<ByteSize> for usize {
+    fn from(size: ByteSize) -> Self {
+        size.0 as usize
+    }
+}
+
+impl Add for ByteSize {
+    type Output = Self;
+    fn add(self, rhs: Self) -> Self {
+        Self(self.0 + rhs.0)
+    }
+}
+
+impl AddAssign for ByteSize {
+    fn add_assign(&mut self, rhs: Self) {
+        self.0 += rhs.0;
+    }
+}
+
+impl Sub for ByteSize {
+    type Output = Self;
+    fn sub(self, rhs: Self) -> Self {
+        Self(self.0 - rhs.0)
+    }
+}
+
+impl Mul<u64> for ByteSize {
+    type Output = Self;
+    fn mul(self, rhs: u64) -> Self {
+        Self(self.0 * rhs)
+    }
+}
+
+impl Mul<ByteSize> for u64 {
+    type Output = ByteSize;
+    fn mul(self, rhs: ByteSize) -> ByteSize {
+        ByteSize(self * rhs.0)
+    }
+}
+
+impl Div<u64> for ByteSize {
+    type Output = Self;
+    fn div(self, rhs: u64) -> Self {
+        Self(self.0 / rhs)
+    }
+}
+
+/// Dividing a size by a duration gives a bandwidth.
+impl Div<Duration> for ByteSize {
+    type Output = Bandwidth;
+    fn div(self, rhs: Duration) -> Bandwidth {
+        Bandwidth::from_transfer(self, rhs)
+    }
+}
+
+impl std::iter::Sum for ByteSize {
+    fn sum<I: Iterator<Item = Self>>(iter: I) -> Self {
+        Self(iter.map(|s| s.0).sum())
+    }
+}
+
+/// Bytes per second. Displays in GB/s; use `display(Units::Iec)` for GiB/s.
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Default)]
+pub struct Bandwidth(pub f64);
+
+impl Bandwidth {
+    pub fn from_transfer(size: ByteSize, time: Duration) -> Self {
+        Self(size.0 as f64 / time.as_secs_f64())
+    }
+
+    pub fn bytes_per_sec(self) -> f64 {
+        self.0
+    }
+
+    pub fn gb_s(self) -> f64 {
+        self.0 / 1e9
+    }
+
+    pub fn gib_s(self) -> f64 {
+        self.0 / (1u64 << 30) as f64
+    }
+
+    /// Formats with two decimals in the given units, e.g. `24.31 GB/s`.
+    pub fn display(self, units: Units) -> String {
+        let (value, prefix) = scale(self.0, units);
+        format!("{:.2} {}B/s", value, prefix)
+    }
+}
+
+impl fmt::Display for Bandwidth {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(&self.display(Units::Si))
+    }
+}
+
+impl Add for Bandwidth {
+    type Output = Self;
+    fn add(self, rhs: Self) -> Self {
+        Self(self.0 + rhs.0)
+    }
+}
+
+impl std::iter::Sum for Bandwidth {
+    fn sum<I: Iterator<Item = Self>>(iter: I) -> Self {
+        Self(iter.map(|b| b.0).sum())
+    }
+}
+
+#[cfg(feature = "serde")]
+mod serde_impls {
+    use super::{Bandwidth, ByteSize};
+    use serde::de::{self, Visitor};
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use std::fmt;
+
+    /// Serialized as a plain byte count; deserialized from a count or a string.
+    impl Serialize for ByteSize {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            serializer.serialize_u64(self.0)
+        }
+    }
+
+    impl<'de> Deserialize<'de> for ByteSize {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            struct ByteSizeVisitor;
+
+            impl Visitor<'_> for ByteSizeVisitor {
+                type Value = ByteSize;
+
+                fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                    f.write_str("a byte count or a size string like \"8GiB\"")
+                }
+
+                fn visit_u64<E: de::Error>(self, v: u64) -> Result<ByteSize, E> {
+                    Ok(ByteSize(v))
+                }
+
+                fn visit_i64<E: de::Error>(self, v: i64) -> Result<ByteSize, E> {
+                    u64::try_from(v)
+                        .map(ByteSize)
+                        .map_err(|_| E::custom("negative size"))
+                }
+
+                fn visit_str<E: de::Error>(self, v: &str) -> Result<ByteSize, E> {
+                    v.parse().map_err(E::custom)
+                }
+            }
+
+            deserializer.deserialize_any(ByteSizeVisitor)
+        }
+    }
+
+    /// Serialized as bytes per second.
+    impl Serialize for Bandwidth {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            serializer.serialize_f64(self.0)
+        }
+    }
+
+    impl<'de> Deserialize<'de> for Bandwidth {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            f64::deserialize(deserializer).map(Bandwidth)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(s: &str) -> Result<ByteSize, ParseSizeError> {
+        s.parse()
+    }
+
+    #[test]
+    fn parse_suffixes() {
+        assert_eq!(parse("123"), Ok(ByteSize(123)));
+        assert_eq!(parse("123B"), Ok(ByteSize(123)));
+        assert_eq!(parse("4k"), Ok(ByteSize::kib(4)));
+        assert_eq!(parse("4KiB"), Ok(ByteSize::kib(4)));
+        assert_eq!(parse("512MiB"), Ok(ByteSize::mib(512)));
+        assert_eq!(parse("8G"), Ok(ByteSize::gib(8)));
+        assert_eq!(parse("2TiB"), Ok(ByteSize::tib(2)));
+        assert_eq!(parse("1PiB"), Ok(ByteSize(1 << 50)));
+        assert_eq!(parse("4KB"), Ok(ByteSize::kb(4)));
+        assert_eq!(parse("512MB"), Ok(ByteSize::mb(512)));
+        assert_eq!(parse("8GB"), Ok(ByteSize::gb(8)));
+        assert_eq!(parse("2TB"), Ok(ByteSize::tb(2)));
+        assert_eq!(parse("0x200000"), Ok(ByteSize::mib(2)));
+        assert_eq!(parse("0X20_0000"), Ok(ByteSize::mib(2)));
+        assert_eq!(parse("1_000_000"), Ok(ByteSize(1_000_000)));
+    }
+
+    #[test]
+    fn parse_case_and_spaces() {
+        assert_eq!(parse("8gib"), Ok(ByteSize::gib(8)));
+        assert_eq!(parse("8GIB"), Ok(ByteSize::gib(8)));
+        assert_eq!(parse("8 GiB"), Ok(ByteSize::gib(8)));
+        assert_eq!(parse("  8gb "), Ok(ByteSize::gb(8)));
+    }
+
+    #[test]
+    fn parse_fractions() {
+        assert_eq!(parse("1.5 TiB"), Ok(ByteSize::gib(1536)));
+        assert_eq!(parse("0.5k"), Ok(ByteSize(512)));
+        assert_eq!(parse("2.5MB"), Ok(ByteSize::kb(2500)));
+        assert_eq!(parse(".5KiB"), Ok(ByteSize(512)));
+        assert_eq!(parse("1.0001"), Ok(ByteSize(1)));
+    }
+
+    #[test]
+    fn parse_overflow() {
+        assert!(parse("18446744073709551616").is_err());
+        assert!(parse("16384PiB").is_err());
+        assert!(parse("20000000TiB").is_err());
+        assert!(parse("1e30").is_err());
+        assert!(parse("0x1_0000_0000_0000_0000").is_err());
+        assert_eq!(parse("18446744073709551615"), Ok(ByteSize(u64::MAX)));
+    }
+
+    #[test]
+    fn parse_garbage() {
+        for bad in [
+            "", " ", "GiB", "abc", "-1", "1.2.3", "8 GiBs", "8 XB", "0x", "0xZZ",
+        ] {
+            assert!(parse(bad).is_err(), "{:?}", bad);
+        }
+        assert_eq!(
+            parse("abc").unwrap_err().to_string(),
+            "invalid size \"abc\""
+        );
+    }
+
+    #[test]
+    fn display() {
+        assert_eq!(ByteSize(17).to_string(), "17 B");
+        assert_eq!(ByteSize::gib(8).to_string(), "8.00 GiB");
+        assert_eq!(ByteSize::gib(8).display(Units::Si), "8.59 GB");
+        assert_eq!(format!("{:>10}", ByteSize::kib(1)), "  1.00 KiB");
+        let bw = Bandwidth::from_transfer(ByteSize::gb(25), Duration::from_secs(1));
+        assert_eq!(bw.to_string(), "25.00 GB/s");
+        assert_eq!(bw.display(Units::Iec), "23.28 GiB/s");
+    }
+
+    #[test]
+    fn display_round_trip() {
+        for size in [
+            ByteSize(0),
+            ByteSize(1023),
+            ByteSize::kib(1),
+            ByteSize(1536),
+            ByteSize::mib(512),
+            ByteSize::gib(8),
+            ByteSize::gib(1536),
+            ByteSize(3 << 50),
+        ] {
+            assert_eq!(parse(&size.to_string()), Ok(size), "{}", size);
+        }
+    }
+}
@@ -0,0 +1,416 @@
+//! Benchmark suites described in TOML rather than code.
+//!
+//! ```toml
+//! name = "h2d"
+//! iterations = 5
+//! devices = "0,1"
+//!
+//! [[scenario]]
+//! name = "pinned-h2d"
+//! src = "pinned"
+//! dst = "device"
+//! sizes = ["64MiB", "1GiB"]
+//! per_device = true
+//! streams = 2
+//! concurrency = "threads"
+//! ```
+//!
+//! Device indices in scenarios refer to the suite's `devices` selection, so
+//! `src_device = 1` is the second selected device.
+
+use std::path::Path;
+
+use serde::Deserialize;
+
+use crate::bench::{Endpoint, Transfer};
+use crate::devices::DeviceSelector;
+use crate::*;
+
+/// Built-in suites, selectable by name instead of a file.
+pub const PRESETS: &[(&str, &str)] = &[
+    ("quick", include_str!("suites/quick.toml")),
+    ("full", include_str!("suites/full.toml")),
+];
+
+#[derive(Debug)]
+pub enum SuiteError {
+    Io(std::io::Error),
+    Parse(toml::de::Error),
+    /// A suite that parsed but can't run here; names the offending scenario.
+    Invalid(String),
+}
+
+impl std::fmt::Display for SuiteError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            SuiteError::Io(e) => write!(f, "{}", e),
+            SuiteError::Parse(e) => write!(f, "{}", e),
+            SuiteError::Invalid(msg) => f.write_str(msg),
+        }
+    }
+}
+
+impl std::error::Error for SuiteError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Space {
+    Device,
+    Pinned,
+    Registered,
+    /// Pageable memory.
+    Cpu,
+}
+
+impl From<Space> for AddressSpace {
+    fn from(space: Space) -> Self {
+        match space {
+            Space::Device => AddressSpace::Device,
+            Space::Pinned => AddressSpace::Pinned,
+            Space::Registered => AddressSpace::Registered,
+            Space::Cpu => AddressSpace::Cpu,
+        }
+    }
+}
+
+/// How the copies of a scenario are issued.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Concurrency {
+    /// From one thread, one stream per copy.
+    #[default]
+    Streams,
+    /// One thread per copy.
+    Threads,
+    /// One process per device; needs `per_device`.
+    Processes,
+}
+
+/// How host buffers are faulted in before copying.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Touch {
+    #[default]
+    None,
+    Populate,
+    WillNeed,
+    Touch,
+}
+
+impl From<Touch> for Prefault {
+    fn from(touch: Touch) -> Self {
+        match touch {
+            Touch::None => Prefault::None,
+            Touch::Populate => Prefault::Populate,
+            Touch::WillNeed => Prefault::WillNeed,
+            Touch::Touch => Prefault::Touch,
+        }
+    }
+}
+
+fn one() -> usize {
+    1
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ScenarioSpec {
+    pub name: String,
+    pub src: Space,
+    pub dst: Space,
+    /// Index into the suite's devices of the source side.
+    #[serde(default)]
+    pub src_device: usize,
+    #[serde(default)]
+    pub dst_device: usize,
+    /// Run the copy on every device (source and destination both that
+    /// device) instead of `src_device`/`dst_device`.
+    #[serde(default)]
+    pub per_device: bool,
+    /// Also copy back in the opposite direction at the same time.
+    #[serde(default)]
+    pub bidirectional: bool,
+    pub sizes: Vec<ByteSize>,
+    /// Concurrent copies per device, each on its own stream.
+    #[serde(default = "one")]
+    pub streams: usize,
+    #[serde(default)]
+    pub concurrency: Concurrency,
+    #[serde(default)]
+    pub touch: Touch,
+    /// Overrides the suite's `iterations`/`warmup`.
+    pub iterations: Option<usize>,
+    pub warmup: Option<usize>,
+}
+
+fn default_iterations() -> usize {
+    3
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Suite {
+    pub name: String,
+    /// A `DeviceSelector`, e.g. `"0,1"` or `"all"`.
+    #[serde(default)]
+    pub devices: Option<String>,
+    #[serde(default = "default_iterations")]
+    pub iterations: usize,
+    #[serde(default)]
+    pub warmup: usize,
+    #[serde(rename = "scenario", default)]
+    pub scenarios: Vec<ScenarioSpec>,
+}
+
+impl std::str::FromStr for Suite {
+    type Err = SuiteError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        toml::from_str(s).map_err(SuiteError::Parse)
+    }
+}
+
+impl Suite {
+    pub fn load(path: &Path) -> Result<Self, SuiteError> {
+        std::fs::read_to_string(path)
+            .map_err(SuiteError::Io)?
+            .parse()
+    }
+
+    pub fn preset(name: &str) -> Option<Self> {
+        PRESETS
+            .iter()
+            .find(|(n, _)| *n == name)
+            .map(|(_, toml)| toml.parse().expect("built-in suite"))
+    }
+
+    /// A preset name or a path to a TOML file.
+    pub fn load_or_preset(name_or_path: &str) -> Result<Self, SuiteError> {
+        match Self::preset(name_or_path) {
+            Some(suite) => Ok(suite),
+            None => Self::load(Path::new(name_or_path)),
+        }
+    }
+
+    pub fn device_selector(&self) -> Result<DeviceSelector, SuiteError> {
+        self.devices
+            .as_deref()
+            .unwrap_or("all")
+            .parse()
+            .map_err(|e| SuiteError::Invalid(format!("suite {}: {}", self.name, e)))
+    }
+
+    /// Checks the suite can run on `n_devices` selected devices.
+    pub fn validate(&self, n_devices: usize) -> Result<(), SuiteError> {
+        let invalid = |spec: &ScenarioSpec, msg: String| {
+            Err(SuiteError::Invalid(format!(
+                "scenario {}: {}",
+                spec.name, msg
+            )))
+        };
+        if self.scenarios.is_empty() {
+            return Err(SuiteError::Invalid(format!(
+                "suite {} has no scenarios",
+                self.name
+            )));
+        }
+        if n_devices == 0 {
+            return Err(SuiteError::Invalid(String::from("no devices selected")));
+        }
+        for (idx, spec) in self.scenarios.iter().enumerate() {
+            if self.scenarios[..idx].iter().any(|s| s.name == spec.name) {
+                return invalid(spec, String::from("duplicate name"));
+            }
+            if spec.sizes.is_empty() || spec.sizes.iter().any(|s| s.bytes() == 0) {
+                return invalid(spec, String::from("needs at least one non-zero size"));
+            }
+            if spec.streams == 0 {
+                return invalid(spec, String::from("streams must be at least 1"));
+            }
+            if spec.iterations.unwrap_or(self.iterations) == 0 {
+                return invalid(spec, String::from("iterations must be at least 1"));
+            }
+            if !spec.per_device {
+                let needed = spec.src_device.max(spec.dst_device) + 1;
+                if needed > n_devices {
+                    return invalid(
+                        spec,
+                        format!("needs {} devices but {} are selected", needed, n_devices),
+                    );
+                }
+            }
+            if spec.concurrency == Concurrency::Processes && !spec.per_device {
+                return invalid(
+                    spec,
+                    String::from("concurrency = \"processes\" needs per_device = true"),
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Streams to create per device so every scenario has enough.
+    pub fn streams_per_device(&self) -> usize {
+        self.scenarios
+            .iter()
+            .map(|s| s.streams * if s.bidirectional { 2 } else { 1 })
+            .max()
+            .unwrap_or(1)
+    }
+}
+
+impl ScenarioSpec {
+    pub fn host_options(&self) -> HostOptions {
+        HostOptions {
+            prefault: self.touch.into(),
+            ..Default::default()
+        }
+    }
+
+    /// The `(src, dst)` device indices copies run between.
+    pub fn device_pairs(&self, n_devices: usize) -> Vec<(usize, usize)> {
+        if self.per_device {
+            (0..n_devices).map(|d| (d, d)).collect()
+        } else {
+            vec![(self.src_device, self.dst_device)]
+        }
+    }
+
+    /// Builds the transfer over `streams`, which holds `per_device`
+    /// consecutive streams for each of `n_devices` devices.
+    pub fn build(
+        &self,
+        streams: &[Stream],
+        per_device: usize,
+        n_devices: usize,
+        size: usize,
+    ) -> Transfer {
+        let mut t =
+            Transfer::new(self.name.clone(), streams, size).host_options(self.host_options());
+        let endpoint = |space: Space, device: usize, slot: usize| Endpoint {
+            address_space: space.into(),
+            device: device * per_device,
+            slot,
+        };
+        // Host-to-device copies are issued on the device's side.
+        let issuer = |src: usize, dst: usize| if self.src == Space::Device { src } else { dst };
+        for (src, dst) in self.device_pairs(n_devices) {
+            for k in 0..self.streams {
+                t = t.copy(
+                    endpoint(self.dst, dst, k),
+                    endpoint(self.src, src, k),
+                    issuer(src, dst) * per_device + k,
+                );
+                if self.bidirectional {
+                    let slot = self.streams + k;
+                    t = t.copy(
+                        endpoint(self.src, src, slot),
+                        endpoint(self.dst, dst, slot),
+                        issuer(src, dst) * per_device + slot,
+                    );
+                }
+            }
+        }
+        if self.bidirectional {
+            t = t.timed();
+        }
+        if self.concurrency == Concurrency::Threads {
+            t = t.threaded();
+        }
+        t
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A suite with one scenario, with `extra` appended to the scenario.
+    fn suite(extra: &str) -> Suite {
+        format!(
+            "name = \"t\"\n[[scenario]]\nname = \"a\"\nsrc = \"pinned\"\ndst = \"device\"\n{}",
+            extra
+        )
+        .parse()
+        .unwrap()
+    }
+
+    fn invalid(suite: &Suite, n_devices: usize) -> String {
+        match suite.validate(n_devices) {
+            Err(SuiteError::Invalid(msg)) => msg,
+            other => panic!("expected an invalid suite, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn presets_parse_and_validate() {
+        for (name, _) in PRESETS {
+            let suite = Suite::preset(name).unwrap();
+            assert_eq!(suite.name, *name);
+            suite.validate(1).unwrap();
+            suite.device_selector().unwrap();
+        }
+        assert!(Suite::preset("missing").is_none());
+    }
+
+    #[test]
+    fn rejects_duplicate_names() {
+        let mut s = suite("sizes = [\"1MiB\"]");
+        s.scenarios.push(s.scenarios[0].clone());
+        assert_eq!(invalid(&s, 1), "scenario a: duplicate name");
+    }
+
+    #[test]
+    fn rejects_missing_or_zero_sizes() {
+        for sizes in ["sizes = []", "sizes = [\"1MiB\", \"0\"]"] {
+            assert_eq!(
+                invalid(&suite(sizes), 1),
+                "scenario a: needs at least one non-zero size"
+            );
+        }
+    }
+
+    #[test]
+    fn processes_need_per_device() {
+        let s = suite("sizes = [\"1MiB\"]\nconcurrency = \"processes\"");
+        assert!(invalid(&s, 1).contains("needs per_device = true"));
+        suite("sizes = [\"1MiB\"]\nconcurrency = \"processes\"\nper_device = true")
+            .validate(1)
+            .unwrap();
+    }
+
+    #[test]
+    fn rejects_devices_past_the_selection() {
+        let s = suite("sizes = [\"1MiB\"]\nsrc_device = 0\ndst_device = 2");
+        assert_eq!(
+            invalid(&s, 2),
+            "scenario a: needs 3 devices but 2 are selected"
+        );
+        s.validate(3).unwrap();
+        // Per-device scenarios ignore the explicit indices.
+        suite("sizes = [\"1MiB\"]\ndst_device = 2\nper_device = true")
+            .validate(1)
+            .unwrap();
+        assert_eq!(
+            invalid(&suite("sizes = [\"1MiB\"]"), 0),
+            "no devices selected"
+        );
+    }
+
+    #[test]
+    fn rejects_bad_device_selections() {
+        let mut s = suite("sizes = [\"1MiB\"]");
+        s.devices = Some(String::from("0,0"));
+        assert!(matches!(s.device_selector(), Err(SuiteError::Invalid(_))));
+        s.devices = None;
+        assert_eq!(s.device_selector().unwrap(), DeviceSelector::default());
+    }
+
+    #[test]
+    fn rejects_unknown_fields() {
+        assert!(matches!(
+            "name = \"t\"\nthreads = 2".parse::<Suite>(),
+            Err(SuiteError::Parse(_))
+        ));
+    }
+}
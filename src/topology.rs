@@ -0,0 +1,479 @@
+//! Host topology from sysfs: where each GPU sits in the PCI hierarchy, its
+//! link speed and width, and its NUMA node and local CPUs.
+//!
+//! Everything is read relative to a configurable root (normally `/sys`) so a
+//! copied or hand-built tree can stand in for the real one.
+
+use std::path::{Path, PathBuf};
+
+use crate::host::parse_cpu_list;
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct PciLink {
+    /// e.g. `16.0 GT/s PCIe`, as reported by sysfs.
+    pub current_speed: Option<String>,
+    pub max_speed: Option<String>,
+    pub current_width: Option<u32>,
+    pub max_width: Option<u32>,
+}
+
+impl PciLink {
+    /// Whether the link trained below its maximum speed or width.
+    pub fn is_degraded(&self) -> bool {
+        let slower = match (&self.current_speed, &self.max_speed) {
+            (Some(cur), Some(max)) => match (link_speed_gts(cur), link_speed_gts(max)) {
+                (Some(cur), Some(max)) => cur < max,
+                _ => false,
+            },
+            _ => false,
+        };
+        let narrower = match (self.current_width, self.max_width) {
+            (Some(cur), Some(max)) => cur < max,
+            _ => false,
+        };
+        slower || narrower
+    }
+}
+
+/// Transfer rate in GT/s from a sysfs speed string like `16.0 GT/s PCIe`.
+pub fn link_speed_gts(s: &str) -> Option<f64> {
+    s.split_whitespace().next()?.parse().ok()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PciDevice {
+    /// Normalized bus id, e.g. `0000:17:00.0`.
+    pub bus_id: String,
+    /// Root complex, e.g. `pci0000:00`, if the sysfs path shows one.
+    pub root_complex: Option<String>,
+    /// Bridges and switch ports between the root complex and the device,
+    /// nearest the root first.
+    pub bridges: Vec<String>,
+    pub numa_node: Option<usize>,
+    pub link: PciLink,
+    pub local_cpus: Vec<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct NumaNode {
+    pub id: usize,
+    pub cpus: Vec<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Gpu {
+    pub ordinal: i32,
+    /// `None` if the bus id has no sysfs entry (e.g. inside some containers).
+    pub pci: Option<PciDevice>,
+}
+
+/// How close two PCI devices are, nearest first; comparable to the
+/// `PIX`/`PXB`/`PHB`/`NODE`/`SYS` levels of `nvidia-smi topo -m`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Relation {
+    /// Behind the same PCIe switch port.
+    SameSwitch,
+    /// Behind a common bridge further up, but not the host bridge.
+    SharedBridge,
+    /// Under the same root complex.
+    HostBridge,
+    /// Different root complexes on one NUMA node.
+    SameNode,
+    /// Across NUMA nodes, or unknown.
+    CrossNode,
+}
+
+impl PciDevice {
+    pub fn relation(&self, other: &PciDevice) -> Relation {
+        let shared = self
+            .bridges
+            .iter()
+            .zip(&other.bridges)
+            .take_while(|(a, b)| a == b)
+            .count();
+        if self.root_complex.is_some() && self.root_complex == other.root_complex {
+            if shared > 0 && shared + 1 >= self.bridges.len().max(other.bridges.len()) {
+                Relation::SameSwitch
+            } else if shared > 0 {
+                Relation::SharedBridge
+            } else {
+                Relation::HostBridge
+            }
+        } else if self.numa_node.is_some() && self.numa_node == other.numa_node {
+            Relation::SameNode
+        } else {
+            Relation::CrossNode
+        }
+    }
+}
+
+/// Lowercases a bus id and trims the CUDA-style 8-digit domain to sysfs's 4.
+pub fn normalize_bus_id(id: &str) -> String {
+    let id = id.trim().to_ascii_lowercase();
+    match id.split_once(':') {
+        Some((domain, rest)) if domain.len() > 4 => {
+            format!("{}:{}", &domain[domain.len() - 4..], rest)
+        }
+        _ => id,
+    }
+}
+
+/// `dddd:bb:dd.f`
+pub(crate) fn is_bus_id(s: &str) -> bool {
+    let b = s.as_bytes();
+    b.len() == 12
+        && b[4] == b':'
+        && b[7] == b':'
+        && b[10] == b'.'
+        && s.chars()
+            .enumerate()
+            .all(|(i, c)| matches!(i, 4 | 7 | 10) || c.is_ascii_hexdigit())
+}
+
+/// A sysfs tree to read from.
+#[derive(Debug, Clone)]
+pub struct Sysfs {
+    pub root: PathBuf,
+}
+
+impl Default for Sysfs {
+    fn default() -> Self {
+        Self::new("/sys")
+    }
+}
+
+impl Sysfs {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    fn read(&self, path: &Path) -> Option<String> {
+        std::fs::read_to_string(path)
+            .ok()
+            .map(|s| s.trim().to_string())
+    }
+
+    pub fn pci_device(&self, bus_id: &str) -> Option<PciDevice> {
+        let bus_id = normalize_bus_id(bus_id);
+        let dir = self.root.join("bus/pci/devices").join(&bus_id);
+        if !dir.exists() {
+            return None;
+        }
+        // The entry links to e.g. devices/pci0000:16/0000:16:02.0/0000:17:00.0.
+        // Only components below `devices` count, not those of the root.
+        let resolved = std::fs::canonicalize(&dir).unwrap_or_else(|_| dir.clone());
+        let devices = std::fs::canonicalize(self.root.join("devices"))
+            .unwrap_or_else(|_| self.root.join("devices"));
+        let components = resolved
+            .strip_prefix(&devices)
+            .map(|below| {
+                below
+                    .components()
+                    .filter_map(|c| c.as_os_str().to_str())
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+        let root_complex = components
+            .iter()
+            .find(|c| c.starts_with("pci") && c.contains(':'))
+            .map(|c| c.to_string());
+        let bridges = components
+            .iter()
+            .filter(|c| is_bus_id(c) && **c != bus_id)
+            .map(|c| c.to_string())
+            .collect();
+        let read = |name: &str| self.read(&dir.join(name));
+        Some(PciDevice {
+            numa_node: read("numa_node").and_then(|n| n.parse().ok()),
+            link: PciLink {
+                current_speed: read("current_link_speed"),
+                max_speed: read("max_link_speed"),
+                current_width: read("current_link_width").and_then(|w| w.parse().ok()),
+                max_width: read("max_link_width").and_then(|w| w.parse().ok()),
+            },
+            local_cpus: read("local_cpulist")
+                .and_then(|s| parse_cpu_list(&s).ok())
+                .unwrap_or_default(),
+            bus_id,
+            root_complex,
+            bridges,
+        })
+    }
+
+    pub fn numa_nodes(&self) -> Vec<NumaNode> {
+        let Ok(entries) = std::fs::read_dir(self.root.join("devices/system/node")) else {
+            return Vec::new();
+        };
+        let mut nodes = entries
+            .filter_map(|e| e.ok())
+            .filter_map(|e| {
+                let name = e.file_name().into_string().ok()?;
+                let id = name.strip_prefix("node")?.parse().ok()?;
+                Some(NumaNode {
+                    id,
+                    cpus: self
+                        .read(&e.path().join("cpulist"))
+                        .and_then(|s| parse_cpu_list(&s).ok())
+                        .unwrap_or_default(),
+                })
+            })
+            .collect::<Vec<_>>();
+        nodes.sort_by_key(|n| n.id);
+        nodes
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Topology {
+    pub gpus: Vec<Gpu>,
+    pub nodes: Vec<NumaNode>,
+}
+
+impl Topology {
+    /// Looks up GPUs given as `(ordinal, PCI bus id)` in `sysfs`.
+    pub fn from_bus_ids(sysfs: &Sysfs, gpus: &[(i32, String)]) -> Self {
+        Self {
+            gpus: gpus
+                .iter()
+                .map(|(ordinal, bus_id)| Gpu {
+                    ordinal: *ordinal,
+                    pci: sysfs.pci_device(bus_id),
+                })
+                .collect(),
+            nodes: sysfs.numa_nodes(),
+        }
+    }
+
+    /// Topology of the given device ordinals, with bus ids from the driver.
+    pub fn discover(sysfs: &Sysfs, ordinals: &[i32]) -> Self {
+        let gpus = ordinals
+            .iter()
+            .map(|&o| (o, crate::pci_bus_id(o)))
+            .collect::<Vec<_>>();
+        Self::from_bus_ids(sysfs, &gpus)
+    }
+
+    pub fn gpu(&self, ordinal: i32) -> Option<&Gpu> {
+        self.gpus.iter().find(|g| g.ordinal == ordinal)
+    }
+
+    /// CPUs local to a GPU: its `local_cpulist`, else its NUMA node's CPUs.
+    pub fn local_cpus(&self, ordinal: i32) -> Vec<usize> {
+        let Some(pci) = self.gpu(ordinal).and_then(|g| g.pci.as_ref()) else {
+            return Vec::new();
+        };
+        if !pci.local_cpus.is_empty() {
+            return pci.local_cpus.clone();
+        }
+        pci.numa_node
+            .and_then(|id| self.nodes.iter().find(|n| n.id == id))
+            .map(|n| n.cpus.clone())
+            .unwrap_or_default()
+    }
+
+    pub fn relation(&self, a: i32, b: i32) -> Relation {
+        match (
+            self.gpu(a).and_then(|g| g.pci.as_ref()),
+            self.gpu(b).and_then(|g| g.pci.as_ref()),
+        ) {
+            (Some(a), Some(b)) => a.relation(b),
+            _ => Relation::CrossNode,
+        }
+    }
+}
+
+impl std::fmt::Display for Topology {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        for gpu in &self.gpus {
+            let Some(pci) = &gpu.pci else {
+                writeln!(f, "GPU{}: not found in sysfs", gpu.ordinal)?;
+                continue;
+            };
+            writeln!(
+                f,
+                "GPU{}: {} node {} link {}/{} x{}/{}{} via {}{}",
+                gpu.ordinal,
+                pci.bus_id,
+                pci.numa_node.map_or("?".to_string(), |n| n.to_string()),
+                pci.link.current_speed.as_deref().unwrap_or("?"),
+                pci.link.max_speed.as_deref().unwrap_or("?"),
+                pci.link
+                    .current_width
+                    .map_or("?".to_string(), |w| w.to_string()),
+                pci.link
+                    .max_width
+                    .map_or("?".to_string(), |w| w.to_string()),
+                if pci.link.is_degraded() {
+                    " (degraded)"
+                } else {
+                    ""
+                },
+                pci.root_complex.as_deref().unwrap_or("?"),
+                pci.bridges
+                    .iter()
+                    .map(|b| format!(" > {}", b))
+                    .collect::<String>()
+            )?;
+        }
+        for node in &self.nodes {
+            writeln!(f, "node{}: {} CPUs", node.id, node.cpus.len())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A throwaway sysfs tree, removed on drop. Its path contains `pci` and
+    /// `:` so it would be mistaken for a root complex if matched.
+    struct FakeSysfs {
+        root: PathBuf,
+    }
+
+    impl FakeSysfs {
+        fn new(name: &str) -> Self {
+            let root =
+                std::env::temp_dir().join(format!("pci-fake:{}-{}", name, std::process::id()));
+            let _ = std::fs::remove_dir_all(&root);
+            std::fs::create_dir_all(root.join("bus/pci/devices")).unwrap();
+            Self { root }
+        }
+
+        fn sysfs(&self) -> Sysfs {
+            Sysfs::new(&self.root)
+        }
+
+        fn write(&self, path: &str, contents: &str) {
+            let path = self.root.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, contents).unwrap();
+        }
+
+        /// Adds a device at `devices/<path>`, linked from `bus/pci/devices`.
+        fn device(&self, path: &str, numa_node: i32, files: &[(&str, &str)]) {
+            let dir = self.root.join("devices").join(path);
+            std::fs::create_dir_all(&dir).unwrap();
+            let bus_id = dir.file_name().unwrap();
+            std::os::unix::fs::symlink(
+                Path::new("../../../devices").join(path),
+                self.root.join("bus/pci/devices").join(bus_id),
+            )
+            .unwrap();
+            std::fs::write(dir.join("numa_node"), format!("{}\n", numa_node)).unwrap();
+            for (name, contents) in files {
+                std::fs::write(dir.join(name), contents).unwrap();
+            }
+        }
+    }
+
+    impl Drop for FakeSysfs {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.root);
+        }
+    }
+
+    /// Two GPUs behind one switch and one beside it on root complex 00,
+    /// one on another root complex of node 0, and one on node 1.
+    fn tree(name: &str) -> FakeSysfs {
+        let fake = FakeSysfs::new(name);
+        let link = [
+            ("current_link_speed", "8.0 GT/s PCIe\n"),
+            ("max_link_speed", "16.0 GT/s PCIe\n"),
+            ("current_link_width", "16\n"),
+            ("max_link_width", "16\n"),
+            ("local_cpulist", "0-1\n"),
+        ];
+        fake.device(
+            "pci0000:00/0000:00:01.0/0000:01:00.0/0000:02:00.0/0000:03:00.0",
+            0,
+            &link,
+        );
+        fake.device(
+            "pci0000:00/0000:00:01.0/0000:01:00.0/0000:02:01.0/0000:04:00.0",
+            0,
+            &[],
+        );
+        fake.device("pci0000:00/0000:00:02.0/0000:05:00.0", 0, &[]);
+        fake.device("pci0000:40/0000:40:01.0/0000:41:00.0", 0, &[]);
+        fake.device("pci0000:80/0000:80:01.0/0000:81:00.0", 1, &[]);
+        fake.write("devices/system/node/node0/cpulist", "0-3\n");
+        fake.write("devices/system/node/node1/cpulist", "4-7\n");
+        fake.write("devices/system/node/possible", "0-1\n");
+        fake
+    }
+
+    #[test]
+    fn pci_device() {
+        let fake = tree("device");
+        let dev = fake.sysfs().pci_device("00000000:03:00.0").unwrap();
+        assert_eq!(dev.bus_id, "0000:03:00.0");
+        assert_eq!(dev.root_complex.as_deref(), Some("pci0000:00"));
+        assert_eq!(
+            dev.bridges,
+            ["0000:00:01.0", "0000:01:00.0", "0000:02:00.0"]
+        );
+        assert_eq!(dev.numa_node, Some(0));
+        assert_eq!(dev.link.current_speed.as_deref(), Some("8.0 GT/s PCIe"));
+        assert_eq!(dev.link.max_width, Some(16));
+        assert!(dev.link.is_degraded());
+        assert_eq!(dev.local_cpus, [0, 1]);
+
+        assert_eq!(fake.sysfs().pci_device("0000:99:00.0"), None);
+    }
+
+    #[test]
+    fn relation() {
+        let fake = tree("relation");
+        let sysfs = fake.sysfs();
+        let dev = |id: &str| sysfs.pci_device(id).unwrap();
+        let gpu = dev("0000:03:00.0");
+        assert_eq!(gpu.relation(&dev("0000:04:00.0")), Relation::SameSwitch);
+        assert_eq!(gpu.relation(&dev("0000:05:00.0")), Relation::HostBridge);
+        assert_eq!(gpu.relation(&dev("0000:41:00.0")), Relation::SameNode);
+        assert_eq!(gpu.relation(&dev("0000:81:00.0")), Relation::CrossNode);
+    }
+
+    #[test]
+    fn numa_nodes() {
+        let fake = tree("numa");
+        assert_eq!(
+            fake.sysfs().numa_nodes(),
+            [
+                NumaNode {
+                    id: 0,
+                    cpus: vec![0, 1, 2, 3],
+                },
+                NumaNode {
+                    id: 1,
+                    cpus: vec![4, 5, 6, 7],
+                },
+            ]
+        );
+        assert!(
+            Sysfs::new(fake.root.join("missing"))
+                .numa_nodes()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn local_cpus() {
+        let fake = tree("cpus");
+        let topology = Topology::from_bus_ids(
+            &fake.sysfs(),
+            &[
+                (0, "0000:03:00.0".to_string()),
+                (1, "0000:81:00.0".to_string()),
+                (2, "0000:99:00.0".to_string()),
+            ],
+        );
+        // Its own local_cpulist, then its node's CPUs, then nothing.
+        assert_eq!(topology.local_cpus(0), [0, 1]);
+        assert_eq!(topology.local_cpus(1), [4, 5, 6, 7]);
+        assert!(topology.local_cpus(2).is_empty());
+        assert_eq!(topology.relation(0, 1), Relation::CrossNode);
+        assert_eq!(topology.relation(0, 2), Relation::CrossNode);
+    }
+}
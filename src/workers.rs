@@ -0,0 +1,260 @@
+//! Long-lived worker threads bound to CUDA contexts, for issuing work to
+//! several devices (or streams) from separate threads.
+
+use std::any::Any;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, mpsc};
+use std::thread::JoinHandle;
+
+use crate::affinity::Affinity;
+use crate::*;
+
+/// A reusable barrier that spins instead of sleeping, so waiters leave it
+/// within a few hundred nanoseconds of each other.
+#[derive(Debug)]
+pub struct SpinBarrier {
+    parties: usize,
+    count: AtomicUsize,
+    generation: AtomicUsize,
+}
+
+impl SpinBarrier {
+    pub fn new(parties: usize) -> Self {
+        Self {
+            parties,
+            count: AtomicUsize::new(0),
+            generation: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn wait(&self) {
+        let generation = self.generation.load(Ordering::Acquire);
+        if self.count.fetch_add(1, Ordering::AcqRel) + 1 == self.parties {
+            self.count.store(0, Ordering::Relaxed);
+            self.generation.fetch_add(1, Ordering::Release);
+            return;
+        }
+        while self.generation.load(Ordering::Acquire) == generation {
+            std::hint::spin_loop();
+        }
+    }
+}
+
+type Job = Box<dyn FnOnce(&Context) + Send>;
+
+/// What a job returned, or the payload it panicked with.
+pub type JobResult<R> = Result<R, Box<dyn Any + Send>>;
+
+struct Worker {
+    ctx: Context,
+    jobs: Option<mpsc::Sender<Job>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+/// One thread per given context, each of which makes its context current
+/// once and then runs submitted closures in order. A panicking job is caught
+/// and reported; the worker keeps going.
+pub struct DeviceWorkerPool {
+    workers: Vec<Worker>,
+}
+
+/// A pending job result.
+pub struct Pending<R> {
+    result: mpsc::Receiver<JobResult<R>>,
+}
+
+impl<R> Pending<R> {
+    pub fn join(self) -> JobResult<R> {
+        self.result
+            .recv()
+            .unwrap_or_else(|_| Err(Box::new("worker exited")))
+    }
+}
+
+impl DeviceWorkerPool {
+    /// Starts one worker per entry of `ctxs`; a context may appear more than
+    /// once to get several threads on it.
+    pub fn new(ctxs: &[Context]) -> Self {
+        Self::with_affinity(ctxs, &Affinity::default())
+    }
+
+    /// Like `new`, with each worker bound to its device's CPUs in `affinity`.
+    pub fn with_affinity(ctxs: &[Context], affinity: &Affinity) -> Self {
+        let workers = ctxs
+            .iter()
+            .enumerate()
+            .map(|(idx, ctx)| {
+                let (jobs, queue) = mpsc::channel::<Job>();
+                let thread_ctx = ctx.clone();
+                let affinity = affinity.clone();
+                let thread = std::thread::Builder::new()
+                    .name(format!("gpu{}-worker{}", ctx.device_id, idx))
+                    .spawn(move || {
+                        affinity.bind_current_thread(thread_ctx.device_id);
+                        thread_ctx.set_current();
+                        for job in queue {
+                            job(&thread_ctx);
+                        }
+                    })
+                    .unwrap();
+                Worker {
+                    ctx: ctx.clone(),
+                    jobs: Some(jobs),
+                    thread: Some(thread),
+                }
+            })
+            .collect();
+        Self { workers }
+    }
+
+    pub fn len(&self) -> usize {
+        self.workers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.workers.is_empty()
+    }
+
+    pub fn context(&self, worker: usize) -> &Context {
+        &self.workers[worker].ctx
+    }
+
+    /// Queues `f` on `worker`. With `fault-injection`, it runs under the
+    /// calling thread's `FaultPlan`.
+    pub fn submit<R: Send + 'static>(
+        &self,
+        worker: usize,
+        f: impl FnOnce(&Context) -> R + Send + 'static,
+    ) -> Pending<R> {
+        let (tx, result) = mpsc::channel();
+        #[cfg(feature = "fault-injection")]
+        let faults = crate::fault::Inherited::current();
+        let job: Job = Box::new(move |ctx| {
+            #[cfg(feature = "fault-injection")]
+            let _faults = faults.enter();
+            let _ = tx.send(std::panic::catch_unwind(std::panic::AssertUnwindSafe(
+                || f(ctx),
+            )));
+        });
+        self.workers[worker]
+            .jobs
+            .as_ref()
+            .unwrap()
+            .send(job)
+            .unwrap();
+        Pending { result }
+    }
+
+    /// Runs `f(worker index, context)` on every worker, released together
+    /// from a `SpinBarrier`, and returns each worker's result in order.
+    pub fn run_all<R: Send + 'static>(
+        &self,
+        f: impl Fn(usize, &Context) -> R + Send + Sync + 'static,
+    ) -> Vec<JobResult<R>> {
+        let f = Arc::new(f);
+        let barrier = Arc::new(SpinBarrier::new(self.workers.len()));
+        let pending = (0..self.workers.len())
+            .map(|idx| {
+                let f = f.clone();
+                let barrier = barrier.clone();
+                self.submit(idx, move |ctx| {
+                    barrier.wait();
+                    f(idx, ctx)
+                })
+            })
+            .collect::<Vec<_>>();
+        pending.into_iter().map(Pending::join).collect()
+    }
+}
+
+impl Drop for DeviceWorkerPool {
+    fn drop(&mut self) {
+        for worker in &mut self.workers {
+            worker.jobs.take();
+        }
+        for worker in &mut self.workers {
+            if let Some(thread) = worker.thread.take() {
+                let _ = thread.join();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicBool;
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn barrier_is_reusable_across_generations() {
+        const THREADS: usize = 4;
+        const ROUNDS: usize = 20;
+        let barrier = Arc::new(SpinBarrier::new(THREADS));
+        let arrived = Arc::new(AtomicUsize::new(0));
+        let threads = (0..THREADS)
+            .map(|_| {
+                let barrier = barrier.clone();
+                let arrived = arrived.clone();
+                std::thread::spawn(move || {
+                    for round in 0..ROUNDS {
+                        arrived.fetch_add(1, Ordering::SeqCst);
+                        barrier.wait();
+                        // Nobody leaves a round before everyone has arrived,
+                        // and nobody arrives at the next one until all left.
+                        assert!(arrived.load(Ordering::SeqCst) >= (round + 1) * THREADS);
+                        barrier.wait();
+                        assert!(arrived.load(Ordering::SeqCst) <= (round + 2) * THREADS);
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(arrived.load(Ordering::SeqCst), THREADS * ROUNDS);
+    }
+
+    #[test]
+    fn barrier_of_one_never_waits() {
+        let barrier = SpinBarrier::new(1);
+        for _ in 0..3 {
+            barrier.wait();
+        }
+    }
+
+    #[test]
+    #[ignore = "needs a GPU"]
+    fn pool_reports_panics_and_keeps_going() {
+        let ctx = Context::new(0);
+        let pool = DeviceWorkerPool::new(&[ctx.clone(), ctx.clone()]);
+        let panicked = pool.submit(0, |_| -> usize { panic!("boom") }).join();
+        let payload = panicked.unwrap_err();
+        assert_eq!(payload.downcast_ref::<&str>(), Some(&"boom"));
+        assert_eq!(pool.submit(0, |ctx| ctx.device_id).join().unwrap(), 0);
+        let results = pool.run_all(|idx, _| idx * 10);
+        assert_eq!(
+            results.into_iter().map(Result::unwrap).collect::<Vec<_>>(),
+            [0, 10]
+        );
+        drop(pool);
+        ctx.destroy();
+    }
+
+    #[test]
+    #[ignore = "needs a GPU"]
+    fn drop_runs_queued_jobs_and_joins() {
+        let ctx = Context::new(0);
+        let pool = DeviceWorkerPool::new(std::slice::from_ref(&ctx));
+        let done = Arc::new(AtomicBool::new(false));
+        let flag = done.clone();
+        let _pending = pool.submit(0, move |_| {
+            std::thread::sleep(Duration::from_millis(50));
+            flag.store(true, Ordering::SeqCst);
+        });
+        drop(pool);
+        assert!(done.load(Ordering::SeqCst));
+        ctx.destroy();
+    }
+}